@@ -1,8 +1,13 @@
-use anyhow::anyhow;
-use flate2::read::GzDecoder;
+use anyhow::{anyhow, bail};
+use bzip2::read::BzDecoder;
+use flate2::read::MultiGzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression as GzipCompression;
-use std::io::{BufReader, BufWriter, Read, Write};
+use lz4_flex::frame::FrameDecoder;
+use std::fmt::Display;
+use std::io::{BufReader, BufWriter, Chain, Cursor, Read, Write};
+use tracing::{debug, warn};
+use xz2::read::XzDecoder;
 use zstd::{Decoder, Encoder};
 
 #[derive(Debug, Clone, Copy, strum::Display, Eq, PartialEq)]
@@ -10,9 +15,113 @@ pub enum Compression {
     Raw,
     Gzip,
     Zstd,
+    /// Only supported for reading, as accepted by `docker load` and `docker import`
+    Xz,
+    /// Only supported for reading
+    Bzip2,
+    /// Only supported for reading, in the LZ4 frame format
+    Lz4,
 }
 
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Zstd skippable frames start with `0x184D2A5?` in little-endian, where `?` is any nibble
+const ZSTD_SKIPPABLE_MAGIC: &[u8] = &[0x2a, 0x4d, 0x18];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Followed by the block size, from `1` to `9`
+const BZIP2_MAGIC: &[u8] = b"BZh";
+/// Follows the bzip2 header, as the magic of the first block or of the end of an empty stream
+const BZIP2_BLOCK_MAGIC: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: &[u8] = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+/// How many bytes [`Compression::from_magic`] needs, for the bzip2 header and the magic after it
+const MAGIC_LENGTH: usize = BZIP2_MAGIC.len() + 1 + BZIP2_BLOCK_MAGIC.len();
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+/// POSIX and GNU tar headers hold `ustar` at this offset
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+/// How many bytes [`Compression::sniff`] needs
+pub const SNIFF_LENGTH: usize = TAR_MAGIC_OFFSET + TAR_MAGIC.len();
+
 impl Compression {
+    /// Detects a compressed stream from its first [`MAGIC_LENGTH`] bytes. Returns `None` for anything that isn't
+    /// compressed with a known format. bzip2 is only recognised with the magic after its header, as the header alone
+    /// is three printable characters that a file name could start with.
+    pub fn from_magic(header: &[u8]) -> Option<Compression> {
+        if header.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(ZSTD_MAGIC)
+            || (header.len() >= 4 && header[0] & 0xf0 == 0x50 && header[1..4] == *ZSTD_SKIPPABLE_MAGIC)
+        {
+            Some(Compression::Zstd)
+        } else if header.starts_with(XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else if header.starts_with(BZIP2_MAGIC)
+            && header.get(3).is_some_and(|size| (b'1'..=b'9').contains(size))
+            && header
+                .get(4..MAGIC_LENGTH)
+                .is_some_and(|magic| magic == BZIP2_BLOCK_MAGIC || magic == BZIP2_END_MAGIC)
+        {
+            Some(Compression::Bzip2)
+        } else if header.starts_with(LZ4_MAGIC) {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+
+    /// Like [`Compression::from_magic`], but also recognises uncompressed tar archives, which needs the first
+    /// [`SNIFF_LENGTH`] bytes. Returns `None` if the content is neither compressed nor a tar archive with a `ustar`
+    /// header. The tar header is checked first, as the file name it starts with could look like a compression magic.
+    pub fn sniff(header: &[u8]) -> Option<Compression> {
+        header
+            .get(TAR_MAGIC_OFFSET..SNIFF_LENGTH)
+            .filter(|magic| *magic == TAR_MAGIC)
+            .map(|_| Compression::Raw)
+            .or_else(|| Compression::from_magic(header))
+    }
+
+    /// Reads a layer whose media type declares `declared` compression, or `None` if the media type isn't known.
+    /// The compression is detected from the content, which wins if the two disagree: registries and tools label zstd
+    /// or uncompressed layers as gzip often enough that trusting the media type would fail partway through a layer.
+    pub fn new_checked_reader<T: Read>(
+        declared: Option<Compression>,
+        mut reader: T,
+        name: impl Display,
+    ) -> anyhow::Result<CompressedReader<'static, Chain<Cursor<Vec<u8>>, T>>> {
+        let mut header = vec![];
+        (&mut reader).take(SNIFF_LENGTH as u64).read_to_end(&mut header)?;
+        let compression = match (declared, Compression::sniff(&header)) {
+            (Some(declared), Some(detected)) if declared != detected => {
+                warn!("{name} has a {declared} media type, but its content is {detected}. Reading it as {detected}");
+                detected
+            }
+            (_, Some(detected)) => detected,
+            // Old tar archives have no magic, and empty layers may be nothing but zeroes
+            (None | Some(Compression::Raw), None) => {
+                debug!("Could not detect the compression of {name}, reading it as an uncompressed tar");
+                Compression::Raw
+            }
+            (Some(declared), None) => {
+                warn!(
+                    "{name} has a {declared} media type, but its content is not {declared}. Reading it as uncompressed"
+                );
+                Compression::Raw
+            }
+        };
+        compression.new_reader(Cursor::new(header).chain(reader))
+    }
+
+    /// Detects the compression of `reader` from its first bytes, returning a reader of the decompressed content.
+    /// Content that isn't compressed with a known format is read as-is.
+    pub fn new_detected_reader<T: Read>(
+        mut reader: T,
+    ) -> anyhow::Result<CompressedReader<'static, Chain<Cursor<Vec<u8>>, T>>> {
+        let mut header = vec![];
+        (&mut reader).take(SNIFF_LENGTH as u64).read_to_end(&mut header)?;
+        let compression = Compression::sniff(&header).unwrap_or(Compression::Raw);
+        compression.new_reader(Cursor::new(header).chain(reader))
+    }
+
     pub fn new_reader<T: Read>(self, file: T) -> anyhow::Result<CompressedReader<'static, T>> {
         CompressedReader::new(self, file)
     }
@@ -49,6 +158,9 @@ impl<'a, T: Write + Sync + Send> CompressedWriter<'a, T> {
                     encoder,
                 )))
             }
+            Compression::Xz | Compression::Bzip2 | Compression::Lz4 => {
+                bail!("Writing {type_} compressed content is not supported")
+            }
         }
     }
 
@@ -107,8 +219,12 @@ impl<T: Write + Sync + Send> Write for CompressedWriter<'_, T> {
 
 pub enum CompressedReader<'a, T: Read> {
     Raw(T),
-    Gzip(GzDecoder<T>),
+    // eStargz layers are made of one gzip member per file, so every member is read
+    Gzip(MultiGzDecoder<T>),
     Zstd(Decoder<'a, BufReader<T>>),
+    Xz(XzDecoder<T>),
+    Bzip2(BzDecoder<T>),
+    Lz4(FrameDecoder<T>),
 }
 
 impl<'a, T: Read> CompressedReader<'a, T> {
@@ -116,8 +232,11 @@ impl<'a, T: Read> CompressedReader<'a, T> {
     fn new(type_: Compression, file: T) -> anyhow::Result<CompressedReader<'a, T>> {
         match type_ {
             Compression::Raw => Ok(Self::Raw(file)),
-            Compression::Gzip => Ok(Self::Gzip(GzDecoder::new(file))),
+            Compression::Gzip => Ok(Self::Gzip(MultiGzDecoder::new(file))),
             Compression::Zstd => Ok(Self::Zstd(Decoder::new(file)?)),
+            Compression::Xz => Ok(Self::Xz(XzDecoder::new(file))),
+            Compression::Bzip2 => Ok(Self::Bzip2(BzDecoder::new(file))),
+            Compression::Lz4 => Ok(Self::Lz4(FrameDecoder::new(file))),
         }
     }
 }
@@ -125,11 +244,37 @@ impl<'a, T: Read> CompressedReader<'a, T> {
 impl<T: Read> Read for CompressedReader<'_, T> {
     #[inline(always)]
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self {
+        let read = match self {
             CompressedReader::Raw(f) => f.read(buf),
             CompressedReader::Gzip(f) => f.read(buf),
             CompressedReader::Zstd(f) => f.read(buf),
+            CompressedReader::Xz(f) => f.read(buf),
+            CompressedReader::Bzip2(f) => f.read(buf),
+            CompressedReader::Lz4(f) => f.read(buf),
+        }?;
+        // Decoders can stop before the end of the compressed stream. Read the rest, so that readers verifying the
+        // compressed content see all of it.
+        if read == 0 && !buf.is_empty() {
+            match self {
+                CompressedReader::Raw(_) => {}
+                CompressedReader::Gzip(f) => {
+                    std::io::copy(f.get_mut(), &mut std::io::sink())?;
+                }
+                CompressedReader::Zstd(f) => {
+                    std::io::copy(f.get_mut(), &mut std::io::sink())?;
+                }
+                CompressedReader::Xz(f) => {
+                    std::io::copy(f.get_mut(), &mut std::io::sink())?;
+                }
+                CompressedReader::Bzip2(f) => {
+                    std::io::copy(f.get_mut(), &mut std::io::sink())?;
+                }
+                CompressedReader::Lz4(f) => {
+                    std::io::copy(f.get_mut(), &mut std::io::sink())?;
+                }
+            }
         }
+        Ok(read)
     }
 }
 
@@ -170,6 +315,131 @@ mod tests {
         assert_eq!(output, CONTENT);
     }
 
+    #[test]
+    fn lazy_pull_read() {
+        // eStargz concatenates gzip members, and zstd:chunked appends its TOC in skippable frames
+        let (first, second) = CONTENT.split_at(5);
+        let gzip_member = |content: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(content).unwrap();
+            encoder.finish().unwrap()
+        };
+        let gzip = [gzip_member(first), gzip_member(second)].concat();
+        let skippable_frame = [&[0x50, 0x2a, 0x4d, 0x18, 4, 0, 0, 0][..], b"toc!"].concat();
+        let zstd = [
+            zstd::encode_all(first, 1).unwrap(),
+            zstd::encode_all(second, 1).unwrap(),
+            skippable_frame,
+        ]
+        .concat();
+        for (compression, content) in [(Compression::Gzip, gzip), (Compression::Zstd, zstd)] {
+            let mut reader = compression.new_reader(content.as_slice()).unwrap();
+            let mut output = vec![];
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, CONTENT, "{compression:?}");
+        }
+    }
+
+    fn legacy_compressed() -> [(Compression, Vec<u8>); 3] {
+        let mut xz = xz2::write::XzEncoder::new(vec![], 1);
+        xz.write_all(CONTENT).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::fast());
+        bzip2.write_all(CONTENT).unwrap();
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(vec![]);
+        lz4.write_all(CONTENT).unwrap();
+        [
+            (Compression::Xz, xz.finish().unwrap()),
+            (Compression::Bzip2, bzip2.finish().unwrap()),
+            (Compression::Lz4, lz4.finish().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn legacy_read() {
+        for (compression, content) in legacy_compressed() {
+            assert_eq!(Compression::from_magic(&content), Some(compression));
+            let mut reader = compression.new_reader(content.as_slice()).unwrap();
+            let mut output = vec![];
+            std::io::copy(&mut reader, &mut output).unwrap();
+            assert_eq!(output, CONTENT, "{compression}");
+
+            let mut reader = Compression::new_detected_reader(content.as_slice()).unwrap();
+            let mut output = vec![];
+            std::io::copy(&mut reader, &mut output).unwrap();
+            assert_eq!(output, CONTENT, "{compression}");
+            assert!(compression.new_writer(vec![], 1).is_err());
+        }
+    }
+
+    #[test]
+    fn from_magic() {
+        let gzip = {
+            let mut content = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            content.write_all(CONTENT).unwrap();
+            content.finish().unwrap()
+        };
+        assert_eq!(Compression::from_magic(&gzip), Some(Compression::Gzip));
+        let zstd = zstd::encode_all(CONTENT, 1).unwrap();
+        assert_eq!(Compression::from_magic(&zstd), Some(Compression::Zstd));
+        assert_eq!(Compression::from_magic(CONTENT), None);
+        assert_eq!(Compression::from_magic(&[]), None);
+    }
+
+    #[test]
+    fn sniff() {
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(CONTENT.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "file.txt", CONTENT).unwrap();
+        let tar = tar.into_inner().unwrap();
+        assert_eq!(Compression::sniff(&tar), Some(Compression::Raw));
+        assert_eq!(Compression::sniff(&tar[..100]), None);
+        assert_eq!(Compression::sniff(&[0x5e, 0x2a, 0x4d, 0x18]), Some(Compression::Zstd));
+        assert_eq!(Compression::sniff(CONTENT), None);
+
+        // A tar archive whose first file name looks like the start of a bzip2 stream
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(CONTENT.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "BZh91AY&SY.txt", CONTENT).unwrap();
+        let tar = tar.into_inner().unwrap();
+        assert_eq!(Compression::sniff(&tar), Some(Compression::Raw));
+        let mut reader = Compression::new_detected_reader(tar.as_slice()).unwrap();
+        let mut output = vec![];
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, tar);
+        assert_eq!(Compression::from_magic(b"BZh1.txt\0\0"), None);
+        let empty_bzip2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::fast())
+            .finish()
+            .unwrap();
+        assert_eq!(Compression::from_magic(&empty_bzip2), Some(Compression::Bzip2));
+    }
+
+    #[test]
+    fn checked_read() {
+        let zstd = zstd::encode_all(CONTENT, 1).unwrap();
+        let gzip = {
+            let mut content = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            content.write_all(CONTENT).unwrap();
+            content.finish().unwrap()
+        };
+        let cases: [(Option<Compression>, &[u8]); 5] = [
+            (Some(Compression::Gzip), &zstd),
+            (Some(Compression::Zstd), &gzip),
+            (None, &gzip),
+            (Some(Compression::Gzip), CONTENT),
+            (None, CONTENT),
+        ];
+        for (declared, content) in cases {
+            let mut reader = Compression::new_checked_reader(declared, content, "layer").unwrap();
+            let mut output = vec![];
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, CONTENT, "{declared:?}");
+        }
+    }
+
     #[test]
     fn raw_write() {
         let mut writer = Compression::Raw.new_writer(vec![], 0).unwrap();
@@ -0,0 +1,298 @@
+use anyhow::{bail, Context};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tar::{Builder, EntryType, Header, HeaderMode};
+use tracing::{debug, warn};
+
+/// A host directory whose contents are added to the image below `destination`, given as `HOST_DIR:IMAGE_PATH`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedDirectory {
+    pub source: PathBuf,
+    /// Relative to the image root
+    pub destination: PathBuf,
+}
+
+impl FromStr for AddedDirectory {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // Image paths rarely contain colons, so the last one separates the two
+        let Some((source, destination)) = value.rsplit_once(':') else {
+            bail!("Invalid directory to add {value}, expected HOST_DIR:IMAGE_PATH, e.g. ./certs:/etc/ssl/certs");
+        };
+        if source.is_empty() {
+            bail!("Invalid directory to add {value}, the host directory is empty");
+        }
+        let mut relative = PathBuf::new();
+        for component in Path::new(destination).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    bail!("Invalid image path {destination}, it must not contain `..`")
+                }
+            }
+        }
+        Ok(Self {
+            source: PathBuf::from(source),
+            destination: relative,
+        })
+    }
+}
+
+/// The owner of added files, given as `UID:GID`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Owner {
+    pub uid: u64,
+    pub gid: u64,
+}
+
+impl FromStr for Owner {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (uid, gid) = value.split_once(':').unwrap_or((value, value));
+        let parse = |id: &str| {
+            id.parse::<u64>()
+                .with_context(|| format!("Invalid owner {value}, expected numeric UID:GID"))
+        };
+        Ok(Self {
+            uid: parse(uid)?,
+            gid: parse(gid)?,
+        })
+    }
+}
+
+/// Parses a file mode given in octal, e.g. `0644` or `755`
+pub fn parse_mode(value: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(value.trim_start_matches("0o"), 8)
+        .with_context(|| format!("Invalid mode {value}, expected an octal number such as 0644"))?;
+    if mode > 0o7777 {
+        bail!("Invalid mode {value}, it is larger than 07777");
+    }
+    Ok(mode)
+}
+
+/// Host directories added on top of every image's filesystem with `--add`, before it is repacked
+#[derive(Debug, Clone, Default)]
+pub struct AddedFiles {
+    pub directories: Vec<AddedDirectory>,
+    pub owner: Owner,
+    /// Replaces the permissions of added regular files, otherwise those on the host are kept. Directories always keep
+    /// theirs, so that they stay traversable.
+    pub mode: Option<u32>,
+    /// Modification time of added entries in seconds since the epoch, otherwise those on the host are kept
+    pub mtime: Option<u64>,
+}
+
+impl AddedFiles {
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let Some(missing) = self.directories.iter().find(|added| !added.source.is_dir()) {
+            bail!("{} is not a directory", missing.source.display());
+        }
+        Ok(())
+    }
+
+    /// Writes the contents of every directory to a tar stream, at their path in the image. Later directories win
+    /// when they add the same path. The destination directories themselves are not written, so that those already
+    /// in the image keep their owner and permissions; see [`AddedFiles::write_destinations_tar`].
+    pub fn write_tar(&self, out: impl Write) -> anyhow::Result<()> {
+        let mut builder = Builder::new(out);
+        for added in self.directories.iter().rev() {
+            self.append_directory(&mut builder, &added.source, &added.destination)
+                .with_context(|| format!("Adding {}", added.source.display()))?;
+        }
+        builder.finish()?;
+        Ok(())
+    }
+
+    /// Writes every destination directory and its parents to a tar stream, as `0755` directories. This is merged
+    /// below the image's layers, so only the directories missing from the image are added.
+    pub fn write_destinations_tar(&self, out: impl Write) -> anyhow::Result<()> {
+        let directories: BTreeSet<&Path> = self
+            .directories
+            .iter()
+            .flat_map(|added| added.destination.ancestors())
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .collect();
+        let mut builder = Builder::new(out);
+        for directory in directories {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_uid(self.owner.uid);
+            header.set_gid(self.owner.gid);
+            header.set_mtime(self.mtime.unwrap_or_default());
+            header.set_size(0);
+            let mut name = directory.as_os_str().to_owned();
+            name.push("/");
+            builder.append_data(&mut header, name, std::io::empty())?;
+        }
+        builder.finish()?;
+        Ok(())
+    }
+
+    fn append_directory(
+        &self,
+        builder: &mut Builder<impl Write>,
+        directory: &Path,
+        image_path: &Path,
+    ) -> anyhow::Result<()> {
+        let mut entries = std::fs::read_dir(directory)
+            .with_context(|| format!("Reading directory {}", directory.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let entry_image_path = image_path.join(entry.file_name());
+            let metadata = std::fs::symlink_metadata(&path)?;
+            let mut header = Header::new_gnu();
+            header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+            header.set_uid(self.owner.uid);
+            header.set_gid(self.owner.gid);
+            if let Some(mtime) = self.mtime {
+                header.set_mtime(mtime);
+            }
+            if metadata.is_file() {
+                if let Some(mode) = self.mode {
+                    header.set_mode(mode);
+                }
+            }
+            debug!("Adding {} as {}", path.display(), entry_image_path.display());
+            match header.entry_type() {
+                EntryType::Regular => {
+                    let file = File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+                    builder.append_data(&mut header, &entry_image_path, file)?;
+                }
+                EntryType::Directory => {
+                    // Directories end with a slash, like in layers built by Docker
+                    let mut name = entry_image_path.clone().into_os_string();
+                    name.push("/");
+                    builder.append_data(&mut header, name, std::io::empty())?;
+                    self.append_directory(builder, &path, &entry_image_path)?;
+                }
+                EntryType::Symlink => {
+                    let target = std::fs::read_link(&path)?;
+                    builder.append_link(&mut header, &entry_image_path, target)?;
+                }
+                _ => warn!("Skipping {}, which is not a file, directory or symlink", path.display()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::read_tar_entries_content;
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+    use tar::Archive;
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            AddedDirectory::from_str("./certs:/etc/ssl/certs").unwrap(),
+            AddedDirectory {
+                source: PathBuf::from("./certs"),
+                destination: PathBuf::from("etc/ssl/certs"),
+            }
+        );
+        assert!(AddedDirectory::from_str("./certs").is_err());
+        assert!(AddedDirectory::from_str("./certs:/etc/../..").is_err());
+        assert_eq!(Owner::from_str("1000:100").unwrap(), Owner { uid: 1000, gid: 100 });
+        assert_eq!(Owner::from_str("1000").unwrap(), Owner { uid: 1000, gid: 1000 });
+        assert!(Owner::from_str("root:root").is_err());
+        assert_eq!(parse_mode("0644").unwrap(), 0o644);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert!(parse_mode("999").is_err());
+    }
+
+    #[test]
+    fn test_write_tar() {
+        let first = tempfile::tempdir().unwrap();
+        std::fs::create_dir(first.path().join("conf.d")).unwrap();
+        std::fs::set_permissions(first.path().join("conf.d"), Permissions::from_mode(0o750)).unwrap();
+        std::fs::write(first.path().join("conf.d/app.conf"), b"first").unwrap();
+        std::fs::write(first.path().join("ca.crt"), b"ca").unwrap();
+        let second = tempfile::tempdir().unwrap();
+        std::fs::write(second.path().join("app.conf"), b"second").unwrap();
+
+        let added = AddedFiles {
+            directories: vec![
+                AddedDirectory::from_str(&format!("{}:/etc/app", first.path().display())).unwrap(),
+                AddedDirectory::from_str(&format!("{}:/etc/app/conf.d", second.path().display())).unwrap(),
+            ],
+            owner: Owner { uid: 1000, gid: 100 },
+            mode: Some(0o600),
+            mtime: Some(1_700_000_000),
+        };
+        let mut content = vec![];
+        added.write_tar(&mut content).unwrap();
+
+        let entries = read_tar_entries_content(&content);
+        assert_eq!(entries[&PathBuf::from("etc/app/ca.crt")], b"ca");
+        // The later directory is written first, so it wins when the layers are combined
+        let mut archive = Archive::new(content.as_slice());
+        let headers = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    String::from_utf8(entry.path_bytes().to_vec()).unwrap(),
+                    header.uid().unwrap(),
+                    header.mode().unwrap() & 0o7777,
+                    header.mtime().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            [
+                ("etc/app/conf.d/app.conf".to_string(), 1000, 0o600, 1_700_000_000),
+                ("etc/app/ca.crt".to_string(), 1000, 0o600, 1_700_000_000),
+                ("etc/app/conf.d/".to_string(), 1000, 0o750, 1_700_000_000),
+                ("etc/app/conf.d/app.conf".to_string(), 1000, 0o600, 1_700_000_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_destinations_tar() {
+        let added = AddedFiles {
+            directories: vec![
+                AddedDirectory::from_str("./certs:/etc/ssl/certs").unwrap(),
+                AddedDirectory::from_str("./app:/etc/app").unwrap(),
+                AddedDirectory::from_str("./root:/").unwrap(),
+            ],
+            mode: Some(0o644),
+            ..Default::default()
+        };
+        let mut content = vec![];
+        added.write_destinations_tar(&mut content).unwrap();
+
+        let mut archive = Archive::new(content.as_slice());
+        let directories = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                assert_eq!(entry.header().entry_type(), EntryType::Directory);
+                assert_eq!(entry.header().mode().unwrap(), 0o755);
+                String::from_utf8(entry.path_bytes().to_vec()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(directories, ["etc/", "etc/app/", "etc/ssl/", "etc/ssl/certs/"]);
+    }
+}
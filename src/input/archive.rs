@@ -0,0 +1,224 @@
+use crate::compression::{CompressedReader, Compression, SNIFF_LENGTH};
+use anyhow::{bail, Context};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Take};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+use tracing::{debug, instrument};
+
+/// Links are followed this many times before we give up, to avoid loops in crafted archives
+const MAX_LINK_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+struct ArchiveEntry {
+    offset: u64,
+    size: u64,
+}
+
+enum ArchiveData {
+    Mapped(Mmap),
+    Compressed(Compression),
+}
+
+/// A tar archive on disk that has been indexed once, so individual files can be opened by their path without
+/// unpacking the archive. Uncompressed archives are memory-mapped and entries are served directly from the map.
+/// Compressed archives are re-read from the start and skipped forward to the entry when it is opened.
+pub struct TarArchive {
+    path: PathBuf,
+    data: ArchiveData,
+    entries: HashMap<PathBuf, ArchiveEntry>,
+    links: HashMap<PathBuf, PathBuf>,
+}
+
+impl Display for TarArchive {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+pub enum ArchiveEntryReader<'a> {
+    Mapped(Cursor<&'a [u8]>),
+    Compressed(Box<Take<CompressedReader<'static, BufReader<File>>>>),
+}
+
+impl Read for ArchiveEntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveEntryReader::Mapped(reader) => reader.read(buf),
+            ArchiveEntryReader::Compressed(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl TarArchive {
+    #[instrument(name = "index_archive")]
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("Opening archive {path:?}"))?;
+        let mut header = vec![];
+        (&mut file).take(SNIFF_LENGTH as u64).read_to_end(&mut header)?;
+        let path = path.to_path_buf();
+
+        let mut entries = HashMap::new();
+        let mut links = HashMap::new();
+        let data = match Compression::sniff(&header).filter(|compression| *compression != Compression::Raw) {
+            None => {
+                let data = unsafe { memmap2::MmapOptions::new().map(&file) }?;
+                let mut archive = Archive::new(Cursor::new(&data[..]));
+                index_entries(archive.entries_with_seek()?, &mut entries, &mut links)?;
+                ArchiveData::Mapped(data)
+            }
+            Some(compression) => {
+                debug!("Archive is compressed with {compression}");
+                let reader = compression.new_reader(BufReader::new(File::open(&path)?))?;
+                let mut archive = Archive::new(reader);
+                index_entries(archive.entries()?, &mut entries, &mut links)?;
+                ArchiveData::Compressed(compression)
+            }
+        };
+        debug!("Indexed {} files and {} links", entries.len(), links.len());
+        Ok(Self {
+            path,
+            data,
+            entries,
+            links,
+        })
+    }
+
+    pub fn contains(&self, path: impl AsRef<Path>) -> bool {
+        self.resolve(path.as_ref()).is_ok()
+    }
+
+    pub fn open_entry(&self, path: impl AsRef<Path>) -> anyhow::Result<ArchiveEntryReader<'_>> {
+        let path = path.as_ref();
+        let entry = self
+            .resolve(path)
+            .with_context(|| format!("Opening {path:?} in archive {}", self.path.display()))?;
+        match &self.data {
+            ArchiveData::Mapped(data) => {
+                let content = &data[entry.offset as usize..(entry.offset + entry.size) as usize];
+                Ok(ArchiveEntryReader::Mapped(Cursor::new(content)))
+            }
+            ArchiveData::Compressed(compression) => {
+                let file = File::open(&self.path).with_context(|| format!("Opening archive {:?}", self.path))?;
+                let mut reader = compression.new_reader(BufReader::new(file))?;
+                let skipped = std::io::copy(&mut (&mut reader).take(entry.offset), &mut std::io::sink())?;
+                if skipped != entry.offset {
+                    bail!("Archive {} ended before {path:?}", self.path.display());
+                }
+                Ok(ArchiveEntryReader::Compressed(Box::new(reader.take(entry.size))))
+            }
+        }
+    }
+
+    pub fn read_entry(&self, path: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
+        let mut content = vec![];
+        self.open_entry(path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn resolve(&self, path: &Path) -> anyhow::Result<ArchiveEntry> {
+        let mut path = normalize_path(path);
+        for _ in 0..MAX_LINK_DEPTH {
+            if let Some(entry) = self.entries.get(&path) {
+                return Ok(*entry);
+            }
+            match self.links.get(&path) {
+                Some(target) => path = target.clone(),
+                None => bail!("File {path:?} not found"),
+            }
+        }
+        bail!("Too many levels of links resolving {path:?}")
+    }
+}
+
+fn index_entries<'a, R: 'a + Read>(
+    archive_entries: impl Iterator<Item = std::io::Result<tar::Entry<'a, R>>>,
+    entries: &mut HashMap<PathBuf, ArchiveEntry>,
+    links: &mut HashMap<PathBuf, PathBuf>,
+) -> anyhow::Result<()> {
+    for entry in archive_entries {
+        let entry = entry?;
+        let path = normalize_path(&entry.path()?);
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                let archive_entry = ArchiveEntry {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                };
+                entries.insert(path, archive_entry);
+            }
+            // Docker deduplicates identical layers in saved archives by symlinking them to each other.
+            EntryType::Symlink => {
+                if let Some(target) = entry.link_name()? {
+                    let parent = path.parent().unwrap_or(Path::new(""));
+                    links.insert(path.clone(), normalize_path(&parent.join(target)));
+                }
+            }
+            EntryType::Link => {
+                if let Some(target) = entry.link_name()? {
+                    links.insert(path, normalize_path(&target));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Turns `./a/../b/c` and `/b/c` into `b/c`, so paths from manifests and tar headers can be compared
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{add_file, add_symlink, setup_tar};
+    use std::io::Write;
+
+    fn write_archive(compression: Compression) -> tempfile::NamedTempFile {
+        let mut tar = setup_tar();
+        add_file(&mut tar, "./manifest.json", b"[]");
+        add_file(&mut tar, "abc/layer.tar", b"layer content");
+        add_symlink(&mut tar, "def/layer.tar", "../abc/layer.tar");
+        let data = tar.into_inner().unwrap();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = compression.new_writer(file.as_file_mut(), 1).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_read_entries() {
+        for compression in [Compression::Raw, Compression::Gzip, Compression::Zstd] {
+            let file = write_archive(compression);
+            let archive = TarArchive::open(file.path()).unwrap();
+            assert_eq!(archive.read_entry("manifest.json").unwrap(), b"[]");
+            assert_eq!(archive.read_entry("./abc/layer.tar").unwrap(), b"layer content");
+            assert_eq!(archive.read_entry("def/layer.tar").unwrap(), b"layer content");
+            assert!(!archive.contains("missing.json"));
+            assert!(archive.open_entry("missing.json").is_err());
+        }
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(Path::new("./a/b")), PathBuf::from("a/b"));
+        assert_eq!(normalize_path(Path::new("/a/../b/./c")), PathBuf::from("b/c"));
+    }
+}
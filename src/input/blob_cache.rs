@@ -0,0 +1,384 @@
+use crate::io_utils::VerifyingReader;
+use crate::output_image::image::hash_reader;
+use anyhow::{bail, Context};
+use oci_client::Reference;
+use oci_spec::image::Digest;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+use tracing::{debug, instrument, warn};
+
+/// The cache directory when `XDG_CACHE_HOME` or `HOME` is set
+pub fn default_cache_root() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(cache_home) => Some(PathBuf::from(cache_home).join("docker-repack")),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache/docker-repack")),
+    }
+}
+
+/// A content-addressed cache of blobs pulled from registries, laid out like the `blobs` directory of an OCI layout.
+/// Blobs are verified against their digest every time they are read, and the least recently used blobs are removed
+/// once the cache grows beyond its maximum size. The manifest digests of tags are kept too, so images can be read
+/// entirely from the cache in offline mode.
+#[derive(Debug)]
+pub struct BlobCache {
+    root: PathBuf,
+    max_size: u64,
+    offline: bool,
+}
+
+impl BlobCache {
+    pub fn new(root: PathBuf, max_size: u64, offline: bool) -> Self {
+        Self {
+            root,
+            max_size,
+            offline,
+        }
+    }
+
+    /// In offline mode nothing is fetched from registries, and a cache miss is an error
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.root
+            .join("blobs")
+            .join(digest.algorithm().as_ref())
+            .join(digest.digest())
+    }
+
+    fn reference_path(&self, reference: &Reference) -> PathBuf {
+        self.root
+            .join("refs")
+            .join(reference.resolve_registry())
+            .join(reference.repository())
+            .join(reference.tag().unwrap_or("latest"))
+    }
+
+    /// Whether the blob is in the cache. Its content is only verified when it is opened.
+    pub fn contains(&self, digest: &Digest) -> bool {
+        self.blob_path(digest).is_file()
+    }
+
+    /// Opens a cached blob and marks it as recently used. The reader fails at the end of the blob if the content
+    /// does not match `digest`.
+    pub fn open(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<Option<VerifyingReader<BufReader<File>>>> {
+        let path = self.blob_path(digest);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Opening cached blob {}", path.display())),
+        };
+        if let Err(e) = file.set_modified(SystemTime::now()) {
+            debug!("Could not update the modification time of {}: {e}", path.display());
+        }
+        debug!("Reading {digest} from the cache");
+        let reader = VerifyingReader::new("Cached blob", BufReader::new(file), digest.clone(), size)?;
+        Ok(Some(reader))
+    }
+
+    /// Reads a whole cached blob. A blob that does not match its digest is removed and treated as missing.
+    pub fn read(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(mut reader) = self.open(digest, size)? else {
+            return Ok(None);
+        };
+        let mut content = vec![];
+        match reader.read_to_end(&mut content) {
+            Ok(_) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                self.remove_corrupt(digest, e)?;
+                Ok(None)
+            }
+            Err(e) => Err(e).with_context(|| format!("Reading cached blob {digest}")),
+        }
+    }
+
+    /// Reads a cached blob through to check its content, without keeping it. A blob that does not match its digest
+    /// is removed and treated as missing.
+    pub fn verify(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<bool> {
+        let Some(mut reader) = self.open(digest, size)? else {
+            return Ok(false);
+        };
+        match std::io::copy(&mut reader, &mut std::io::sink()) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                self.remove_corrupt(digest, e)?;
+                Ok(false)
+            }
+            Err(e) => Err(e).with_context(|| format!("Reading cached blob {digest}")),
+        }
+    }
+
+    fn remove_corrupt(&self, digest: &Digest, error: std::io::Error) -> anyhow::Result<()> {
+        warn!("Removing corrupt blob from the cache: {error}");
+        let path = self.blob_path(digest);
+        match std::fs::remove_file(&path) {
+            // Another process may have removed it already
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e).with_context(|| format!("Removing {}", path.display())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts writing a blob to the cache. The blob only appears in the cache once [`CacheWriter::commit`] is
+    /// called, so the content must be verified before then.
+    pub fn writer(self: &Arc<Self>, digest: &Digest) -> anyhow::Result<CacheWriter> {
+        let temp_dir = self.root.join("tmp");
+        std::fs::create_dir_all(&temp_dir).with_context(|| format!("Creating {}", temp_dir.display()))?;
+        let file =
+            NamedTempFile::new_in(&temp_dir).with_context(|| format!("Creating a file in {}", temp_dir.display()))?;
+        Ok(CacheWriter {
+            cache: self.clone(),
+            file: BufWriter::new(file),
+            path: self.blob_path(digest),
+        })
+    }
+
+    /// Adds content that has already been verified to the cache
+    pub fn insert(self: &Arc<Self>, digest: &Digest, content: &[u8]) -> anyhow::Result<()> {
+        let mut writer = self.writer(digest)?;
+        writer.write_all(content)?;
+        writer.commit()
+    }
+
+    /// The digest of the manifest `reference` pointed to when it was last fetched
+    pub fn resolve_reference(&self, reference: &Reference) -> anyhow::Result<Option<Digest>> {
+        if let Some(digest) = reference.digest() {
+            return Ok(Some(Digest::from_str(digest)?));
+        }
+        let path = self.reference_path(reference);
+        match std::fs::read_to_string(&path) {
+            Ok(digest) => Ok(Some(
+                Digest::from_str(digest.trim()).with_context(|| format!("Invalid digest in {}", path.display()))?,
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+        }
+    }
+
+    /// Records the manifest a tagged reference points to. References by digest are not recorded.
+    pub fn set_reference(&self, reference: &Reference, digest: &Digest) -> anyhow::Result<()> {
+        if reference.digest().is_some() {
+            return Ok(());
+        }
+        let path = self.reference_path(reference);
+        let parent = path.parent().context("Reference path has no parent")?;
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating {}", parent.display()))?;
+        let mut file = NamedTempFile::new_in(parent)?;
+        file.write_all(digest.to_string().as_bytes())?;
+        file.persist(&path)
+            .with_context(|| format!("Writing {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Removes the least recently used blobs until the cache is no larger than its maximum size
+    #[instrument(skip(self), fields(root = %self.root.display()))]
+    fn evict(&self) -> anyhow::Result<()> {
+        let mut blobs = vec![];
+        let blobs_dir = self.root.join("blobs");
+        for algorithm in std::fs::read_dir(&blobs_dir).with_context(|| format!("Reading {}", blobs_dir.display()))? {
+            for blob in std::fs::read_dir(algorithm?.path())? {
+                let blob = blob?;
+                let metadata = blob.metadata()?;
+                blobs.push((metadata.modified()?, metadata.len(), blob.path()));
+            }
+        }
+        let mut total_size = blobs.iter().map(|(_, size, _)| size).sum::<u64>();
+        if total_size <= self.max_size {
+            return Ok(());
+        }
+        blobs.sort();
+        for (_, size, path) in blobs {
+            if total_size <= self.max_size {
+                break;
+            }
+            debug!("Evicting {}", path.display());
+            match std::fs::remove_file(&path) {
+                Ok(()) => total_size -= size,
+                // Another process may have evicted it already
+                Err(e) if e.kind() == ErrorKind::NotFound => total_size -= size,
+                Err(e) => return Err(e).with_context(|| format!("Removing {}", path.display())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a blob to a temporary file in the cache, which is moved into place when it is committed. Blobs that are
+/// never committed are removed.
+pub struct CacheWriter {
+    cache: Arc<BlobCache>,
+    file: BufWriter<NamedTempFile>,
+    path: PathBuf,
+}
+
+impl CacheWriter {
+    pub fn commit(self) -> anyhow::Result<()> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        let parent = self.path.parent().context("Blob path has no parent")?;
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating {}", parent.display()))?;
+        file.persist(&self.path)
+            .with_context(|| format!("Writing {}", self.path.display()))?;
+        self.cache.evict()
+    }
+}
+
+impl Write for CacheWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Copies everything read from a verified reader into the cache, and commits the blob once the reader reaches the
+/// end. Failing to write to the cache does not fail the read.
+pub struct CachingReader<R: Read> {
+    inner: R,
+    writer: Option<CacheWriter>,
+}
+
+impl<R: Read> CachingReader<R> {
+    pub fn new(inner: R, writer: CacheWriter) -> Self {
+        Self {
+            inner,
+            writer: Some(writer),
+        }
+    }
+}
+
+impl<R: Read> Read for CachingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            if let Some(writer) = self.writer.take() {
+                if let Err(e) = writer.commit() {
+                    warn!("Could not add blob to the cache: {e:#}");
+                }
+            }
+        } else if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.write_all(&buf[..read]) {
+                warn!("Could not write blob to the cache: {e}");
+                self.writer = None;
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// The digest of content that is not addressed by its own digest, such as a manifest fetched by tag
+pub fn content_digest(content: &[u8]) -> anyhow::Result<Digest> {
+    let (_, digest) = hash_reader(content)?;
+    Ok(digest.into())
+}
+
+/// Fails with an error explaining that `what` has to be fetched, when the cache is offline
+pub fn ensure_online(cache: Option<&BlobCache>, what: impl std::fmt::Display) -> anyhow::Result<()> {
+    if cache.is_some_and(|cache| cache.is_offline()) {
+        bail!("{what} is not in the cache, and cannot be fetched in offline mode");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn cache(root: &Path, max_size: u64) -> Arc<BlobCache> {
+        Arc::new(BlobCache::new(root.to_path_buf(), max_size, false))
+    }
+
+    #[test]
+    fn test_insert_and_read() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = cache(root.path(), 1024);
+        let digest = content_digest(b"hello").unwrap();
+        assert!(cache.read(&digest, None).unwrap().is_none());
+
+        cache.insert(&digest, b"hello").unwrap();
+        assert_eq!(cache.read(&digest, Some(5)).unwrap().unwrap(), b"hello");
+        assert!(!root.path().join("tmp").read_dir().unwrap().any(|_| true));
+
+        std::fs::write(cache.blob_path(&digest), b"jello").unwrap();
+        assert!(cache.read(&digest, None).unwrap().is_none());
+        assert!(!cache.blob_path(&digest).exists());
+    }
+
+    #[test]
+    fn test_verify() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = cache(root.path(), 1024);
+        let digest = content_digest(b"hello").unwrap();
+        assert!(!cache.verify(&digest, None).unwrap());
+
+        cache.insert(&digest, b"hello").unwrap();
+        assert!(cache.verify(&digest, Some(5)).unwrap());
+
+        std::fs::write(cache.blob_path(&digest), b"jello").unwrap();
+        assert!(!cache.verify(&digest, None).unwrap());
+        assert!(!cache.contains(&digest));
+    }
+
+    #[test]
+    fn test_caching_reader() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = cache(root.path(), 1024);
+        let digest = content_digest(b"hello").unwrap();
+
+        let reader = VerifyingReader::new("Layer", &b"jello"[..], digest.clone(), None).unwrap();
+        let mut reader = CachingReader::new(reader, cache.writer(&digest).unwrap());
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+        drop(reader);
+        assert!(cache.open(&digest, None).unwrap().is_none());
+
+        let reader = VerifyingReader::new("Layer", &b"hello"[..], digest.clone(), None).unwrap();
+        let mut reader = CachingReader::new(reader, cache.writer(&digest).unwrap());
+        reader.read_to_end(&mut vec![]).unwrap();
+        assert_eq!(cache.read(&digest, None).unwrap().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = cache(root.path(), 10);
+        let old = content_digest(b"old").unwrap();
+        let used = content_digest(b"used").unwrap();
+        cache.insert(&old, b"old").unwrap();
+        cache.insert(&used, b"used").unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for digest in [&old, &used] {
+            let file = File::options().write(true).open(cache.blob_path(digest)).unwrap();
+            file.set_modified(an_hour_ago).unwrap();
+        }
+        // Reading a blob marks it as recently used
+        cache.read(&used, None).unwrap().unwrap();
+
+        let new = content_digest(b"new").unwrap();
+        cache.insert(&new, b"new!").unwrap();
+        assert!(!cache.blob_path(&old).exists());
+        assert!(cache.blob_path(&used).exists());
+        assert!(cache.blob_path(&new).exists());
+    }
+
+    #[test]
+    fn test_references() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = cache(root.path(), 1024);
+        let digest = content_digest(b"manifest").unwrap();
+        let reference = Reference::from_str("example.com/app:1.0").unwrap();
+        assert_eq!(cache.resolve_reference(&reference).unwrap(), None);
+        cache.set_reference(&reference, &digest).unwrap();
+        assert_eq!(cache.resolve_reference(&reference).unwrap(), Some(digest.clone()));
+
+        let by_digest = reference.clone_with_digest(digest.to_string());
+        assert_eq!(cache.resolve_reference(&by_digest).unwrap(), Some(digest));
+    }
+}
@@ -0,0 +1,425 @@
+use crate::input::layers::InputLayer;
+use crate::input::rootfs::write_overlay_diff_tar;
+use crate::input::InputImage;
+use crate::io_utils::spawn_reader;
+use crate::output_image::image::hash_reader;
+use crate::platform_matcher::PlatformMatcher;
+use anyhow::{bail, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use flate2::read::GzDecoder;
+use itertools::Itertools;
+use oci_client::Reference;
+use oci_spec::image::{Digest, ImageConfiguration, MediaType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, instrument};
+
+const ROOT_STORAGE: &str = "/var/lib/containers/storage";
+const ROOTLESS_STORAGE: &str = ".local/share/containers/storage";
+
+/// The default store: the rootless one in the home directory if it exists, otherwise the system-wide one
+pub fn default_storage_root() -> PathBuf {
+    let rootless = match std::env::var_os("XDG_DATA_HOME") {
+        Some(data_home) => Some(PathBuf::from(data_home).join("containers/storage")),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(ROOTLESS_STORAGE)),
+    };
+    rootless
+        .filter(|path| path.join("overlay-images").exists())
+        .unwrap_or_else(|| PathBuf::from(ROOT_STORAGE))
+}
+
+/// An entry of `overlay-images/images.json`
+#[derive(Debug, Deserialize)]
+struct StorageImage {
+    id: String,
+    names: Option<Vec<String>>,
+    layer: Option<String>,
+}
+
+impl StorageImage {
+    fn names(&self) -> &[String] {
+        self.names.as_deref().unwrap_or_default()
+    }
+
+    /// Matches image IDs, their prefixes and names the way `podman` does, where a name without a registry is
+    /// either a local image or one from Docker Hub.
+    fn matches(&self, name: &str) -> bool {
+        if name.len() >= 3 && name.bytes().all(|b| b.is_ascii_hexdigit()) && self.id.starts_with(name) {
+            return true;
+        }
+        let candidates = [name.to_string(), format!("localhost/{name}")]
+            .into_iter()
+            .filter_map(|candidate| Reference::from_str(&candidate).ok())
+            .map(|reference| reference.whole())
+            .collect_vec();
+        self.names().iter().any(|image_name| {
+            image_name == name
+                || Reference::from_str(image_name).is_ok_and(|reference| candidates.contains(&reference.whole()))
+        })
+    }
+}
+
+/// An entry of `overlay-layers/layers.json`
+#[derive(Debug, Deserialize)]
+struct StorageLayer {
+    id: String,
+    parent: Option<String>,
+    #[serde(rename = "diff-digest")]
+    diff_digest: Option<String>,
+}
+
+/// A line of a tar-split file. Segments hold the raw tar headers and padding, files refer to the content of the
+/// file with the given name in the layer's diff directory.
+#[derive(Debug, Deserialize)]
+struct TarSplitEntry {
+    #[serde(rename = "type")]
+    type_: u8,
+    name: Option<String>,
+    name_raw: Option<String>,
+    #[serde(default)]
+    size: u64,
+    payload: Option<String>,
+}
+
+const TAR_SPLIT_FILE: u8 = 1;
+const TAR_SPLIT_SEGMENT: u8 = 2;
+
+/// An image in a podman/buildah store using the overlay driver
+pub struct ContainersStorageImage {
+    root: PathBuf,
+    id: String,
+    config_digest: Digest,
+    image_config: ImageConfiguration,
+    /// The uncompressed digest and ID of every layer, from the bottom up
+    layers: Vec<(Digest, String)>,
+}
+
+impl PartialEq for ContainersStorageImage {
+    fn eq(&self, other: &Self) -> bool {
+        self.image_digest() == other.image_digest()
+    }
+}
+
+impl Eq for ContainersStorageImage {}
+
+impl Hash for ContainersStorageImage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let digest = self.image_digest();
+        digest.digest().hash(state);
+        digest.algorithm().as_ref().hash(state);
+    }
+}
+
+impl Debug for ContainersStorageImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+impl Display for ContainersStorageImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.platform().fmt(f)
+    }
+}
+
+/// Big data items, like the image configuration, are stored in files named after their key. Keys with characters
+/// other than lowercase letters, digits and dots are base64 encoded.
+fn big_data_file_name(key: &str) -> String {
+    if key
+        .bytes()
+        .all(|b| b == b'.' || b.is_ascii_digit() || b.is_ascii_lowercase())
+    {
+        key.to_string()
+    } else {
+        format!("={}", BASE64_STANDARD.encode(key))
+    }
+}
+
+fn read_json<T: for<'a> Deserialize<'a>>(path: &Path) -> anyhow::Result<T> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file)).with_context(|| format!("Parsing {}", path.display()))
+}
+
+impl ContainersStorageImage {
+    #[instrument(name = "load_images", skip(platform_matcher))]
+    pub fn from_storage(root: &Path, name: &str, platform_matcher: &PlatformMatcher) -> anyhow::Result<Vec<Self>> {
+        let images: Vec<StorageImage> = read_json(&root.join("overlay-images/images.json"))?;
+        let Some(image) = images.iter().find(|image| image.matches(name)) else {
+            bail!(
+                "Image {name} not found in {}. Available images: {}",
+                root.display(),
+                images.iter().flat_map(|image| image.names()).join(", ")
+            );
+        };
+        debug!("Found image {} for {name}", image.id);
+
+        let mut layers: Vec<StorageLayer> = read_json(&root.join("overlay-layers/layers.json"))?;
+        let volatile_layers = root.join("overlay-layers/volatile-layers.json");
+        if volatile_layers.exists() {
+            layers.extend(read_json::<Vec<StorageLayer>>(&volatile_layers)?);
+        }
+        let layers_by_id: HashMap<_, _> = layers.iter().map(|layer| (layer.id.as_str(), layer)).collect();
+
+        let mut image_layers = vec![];
+        let mut next = image.layer.as_deref();
+        while let Some(id) = next {
+            let Some(layer) = layers_by_id.get(id) else {
+                bail!("Layer {id} of image {} not found in {}", image.id, root.display());
+            };
+            let Some(diff_digest) = &layer.diff_digest else {
+                bail!("Layer {id} of image {} has no uncompressed digest", image.id);
+            };
+            image_layers.push((Digest::from_str(diff_digest)?, layer.id.clone()));
+            next = layer.parent.as_deref();
+        }
+        image_layers.reverse();
+
+        let config_path = root
+            .join("overlay-images")
+            .join(&image.id)
+            .join(big_data_file_name(&format!("sha256:{}", image.id)));
+        let config_content = std::fs::read(&config_path)
+            .with_context(|| format!("Reading image configuration from {}", config_path.display()))?;
+        let (_, config_digest) = hash_reader(config_content.as_slice())?;
+        let image_config = ImageConfiguration::from_reader(config_content.as_slice())
+            .with_context(|| format!("Error reading image configuration from {}", config_path.display()))?;
+
+        let image = Self {
+            root: root.to_path_buf(),
+            id: image.id.clone(),
+            config_digest: config_digest.into(),
+            image_config,
+            layers: image_layers,
+        };
+        if !platform_matcher.matches_image_config(image.config()) {
+            bail!(
+                "Image {name} is {}, which does not match the platform filter {}",
+                image.platform(),
+                platform_matcher
+            );
+        }
+        Ok(vec![image])
+    }
+}
+
+impl InputImage for ContainersStorageImage {
+    fn image_digest(&self) -> Digest {
+        self.config_digest.clone()
+    }
+
+    fn layers_from_manifest(
+        &self,
+    ) -> anyhow::Result<impl ExactSizeIterator<Item = anyhow::Result<InputLayer<impl Read>>>> {
+        Ok(self.layers.iter().rev().map(|(digest, id)| {
+            let diff_directory = self.root.join("overlay").join(id).join("diff");
+            let tar_split = self.root.join("overlay-layers").join(format!("{id}.tar-split.gz"));
+            let reader = if tar_split.exists() {
+                debug!("Reading layer {id} from {}", tar_split.display());
+                spawn_reader("tar-split", move |writer| {
+                    write_tar_split(&tar_split, &diff_directory, writer)
+                })?
+            } else {
+                debug!("Reading layer {id} from {}", diff_directory.display());
+                spawn_reader("overlay-diff", move |writer| {
+                    write_overlay_diff_tar(&diff_directory, writer)
+                })?
+            };
+            InputLayer::new(digest.clone(), reader)
+        }))
+    }
+
+    fn config(&self) -> &ImageConfiguration {
+        &self.image_config
+    }
+
+    fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
+        Ok(self
+            .layers
+            .iter()
+            .map(|(digest, _)| (MediaType::ImageLayer, digest.clone()))
+            .collect())
+    }
+}
+
+/// Reassembles the original layer tarball from its tar-split metadata and the files in its diff directory
+fn write_tar_split(tar_split: &Path, diff_directory: &Path, mut out: impl Write) -> anyhow::Result<()> {
+    let file = File::open(tar_split).with_context(|| format!("Opening {}", tar_split.display()))?;
+    for line in BufReader::new(GzDecoder::new(file)).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: TarSplitEntry = serde_json::from_str(&line).context("Parsing tar-split entry")?;
+        match entry.type_ {
+            TAR_SPLIT_SEGMENT => {
+                if let Some(payload) = entry.payload {
+                    out.write_all(&BASE64_STANDARD.decode(payload)?)?;
+                }
+            }
+            TAR_SPLIT_FILE if entry.size > 0 => {
+                let name = match (entry.name, entry.name_raw) {
+                    (Some(name), _) => PathBuf::from(name),
+                    (None, Some(name_raw)) => PathBuf::from(String::from_utf8(BASE64_STANDARD.decode(name_raw)?)?),
+                    (None, None) => bail!("tar-split file entry without a name"),
+                };
+                let path = diff_directory.join(&name);
+                let file = File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+                let copied = std::io::copy(&mut file.take(entry.size), &mut out)?;
+                if copied != entry.size {
+                    bail!("{} is {copied} bytes, expected {}", path.display(), entry.size);
+                }
+            }
+            TAR_SPLIT_FILE => {}
+            type_ => bail!("Unknown tar-split entry type {type_}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_combiner::LayerCombiner;
+    use crate::test_utils::{build_layer, read_tar_entries_content};
+    use flate2::write::GzEncoder;
+    use std::ffi::CString;
+    use std::io::Cursor;
+    use std::os::unix::ffi::OsStringExt;
+
+    /// Splits a tarball into tar-split metadata, and unpacks its files into `diff_directory`
+    fn split_tar(content: &[u8], diff_directory: &Path) -> Vec<u8> {
+        let mut lines = vec![];
+        let mut position = 0;
+        let mut archive = tar::Archive::new(Cursor::new(content));
+        for entry in archive.entries_with_seek().unwrap() {
+            let entry = entry.unwrap();
+            let start = entry.raw_file_position() as usize;
+            let size = entry.size() as usize;
+            let name = entry.path().unwrap().to_path_buf();
+            lines.push(serde_json::json!({"type": 2, "payload": BASE64_STANDARD.encode(&content[position..start])}));
+            lines.push(serde_json::json!({"type": 1, "name": name, "size": size}));
+            let path = diff_directory.join(&name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, &content[start..start + size]).unwrap();
+            position = start + size;
+        }
+        lines.push(serde_json::json!({"type": 2, "payload": BASE64_STANDARD.encode(&content[position..])}));
+
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        for line in lines {
+            writeln!(encoder, "{line}").unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    fn write_storage(root: &Path) -> Vec<u8> {
+        let bottom = build_layer()
+            .with_files(&[("etc/os-release", b"bottom"), ("var/cache/old.txt", b"old")])
+            .build_raw();
+        let diff_directory = root.join("overlay/bottom/diff");
+        std::fs::create_dir_all(&diff_directory).unwrap();
+        let tar_split = split_tar(&bottom, &diff_directory);
+        std::fs::create_dir_all(root.join("overlay-layers")).unwrap();
+        std::fs::write(root.join("overlay-layers/bottom.tar-split.gz"), tar_split).unwrap();
+
+        let top = root.join("overlay/top/diff");
+        std::fs::create_dir_all(top.join("app")).unwrap();
+        std::fs::write(top.join("app/run.sh"), b"top").unwrap();
+        // A whiteout of a file from the bottom layer, and a directory that was deleted and created again
+        std::fs::create_dir_all(top.join("etc")).unwrap();
+        let whiteout = CString::new(top.join("etc/os-release").into_os_string().into_vec()).unwrap();
+        assert_eq!(unsafe { libc::mknod(whiteout.as_ptr(), libc::S_IFCHR | 0o600, 0) }, 0);
+        std::fs::create_dir_all(top.join("var/cache")).unwrap();
+        std::fs::write(top.join("var/cache/new.txt"), b"new").unwrap();
+        let opaque = CString::new(top.join("var/cache").into_os_string().into_vec()).unwrap();
+        let name = c"user.overlay.opaque";
+        assert_eq!(
+            unsafe { libc::lsetxattr(opaque.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) },
+            0
+        );
+
+        let layers = serde_json::json!([
+            {"id": "top", "parent": "bottom", "diff-digest": format!("sha256:{}", "1".repeat(64))},
+            {"id": "bottom", "diff-digest": format!("sha256:{}", "2".repeat(64))},
+        ]);
+        std::fs::write(root.join("overlay-layers/layers.json"), layers.to_string()).unwrap();
+
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": []},
+            "history": [],
+        });
+        let id = "a".repeat(64);
+        let image_directory = root.join("overlay-images").join(&id);
+        std::fs::create_dir_all(&image_directory).unwrap();
+        let config_file = image_directory.join(big_data_file_name(&format!("sha256:{id}")));
+        std::fs::write(config_file, config.to_string()).unwrap();
+        let images = serde_json::json!([{"id": id, "names": ["localhost/app:latest"], "layer": "top"}]);
+        std::fs::write(root.join("overlay-images/images.json"), images.to_string()).unwrap();
+        bottom
+    }
+
+    #[test]
+    fn test_read_storage() {
+        let root = tempfile::tempdir().unwrap();
+        let bottom = write_storage(root.path());
+        let matcher = PlatformMatcher::match_all();
+
+        assert!(ContainersStorageImage::from_storage(root.path(), "other", &matcher).is_err());
+        assert_eq!(
+            ContainersStorageImage::from_storage(root.path(), "aaaa", &matcher).unwrap()[0].id,
+            "a".repeat(64)
+        );
+        let images = ContainersStorageImage::from_storage(root.path(), "app", &matcher).unwrap();
+        let layers = images[0]
+            .layers_from_manifest()
+            .unwrap()
+            .map(|layer| {
+                let mut content = vec![];
+                layer.unwrap().into_inner().read_to_end(&mut content).unwrap();
+                content
+            })
+            .collect_vec();
+
+        let top = read_tar_entries_content(&layers[0]);
+        assert_eq!(top[&PathBuf::from("app/run.sh")], b"top");
+        assert_eq!(top[&PathBuf::from("etc/.wh.os-release")], b"");
+        assert!(!top.contains_key(&PathBuf::from("etc/os-release")));
+
+        // The opaque marker follows the directory's contents, so that they are kept when the layers are merged
+        let mut output = vec![];
+        let mut combiner = LayerCombiner::new(&mut output);
+        for layer in &layers {
+            combiner
+                .merge_entries(tar::Archive::new(layer.as_slice()).entries().unwrap())
+                .unwrap();
+        }
+        combiner.finish().unwrap();
+        let merged = read_tar_entries_content(&output);
+        assert_eq!(merged[&PathBuf::from("var/cache/new.txt")], b"new");
+        assert!(!merged.contains_key(&PathBuf::from("etc/os-release")));
+        assert!(!merged.contains_key(&PathBuf::from("var/cache/old.txt")));
+        let top_paths = tar::Archive::new(layers[0].as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_path_buf())
+            .collect_vec();
+        let position = |path: &str| top_paths.iter().position(|entry| entry == Path::new(path)).unwrap();
+        assert!(position("var/cache/.wh..wh..opq") > position("var/cache/new.txt"));
+        // Layers with tar-split data are reassembled byte-for-byte
+        assert_eq!(layers[1], bottom);
+    }
+
+    #[test]
+    fn test_big_data_file_name() {
+        assert_eq!(big_data_file_name("manifest"), "manifest");
+        assert_eq!(big_data_file_name("sha256:abc"), "=c2hhMjU2OmFiYw==");
+    }
+}
@@ -0,0 +1,283 @@
+use crate::compression::Compression;
+use crate::input::archive::TarArchive;
+use crate::input::layers::InputLayer;
+use crate::input::InputImage;
+use crate::output_image::image::hash_reader;
+use crate::platform_matcher::PlatformMatcher;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use oci_client::Reference;
+use oci_spec::image::{Digest, ImageConfiguration, MediaType};
+use serde::Deserialize;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::{debug, instrument};
+
+/// An entry of the `manifest.json` file written by `docker save`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+impl ArchiveManifest {
+    fn repo_tags(&self) -> &[String] {
+        self.repo_tags.as_deref().unwrap_or_default()
+    }
+
+    fn has_tag(&self, reference: &Reference) -> bool {
+        self.repo_tags()
+            .iter()
+            .filter_map(|tag| Reference::from_str(tag).ok())
+            .any(|tag| tag.whole() == reference.whole())
+    }
+}
+
+/// An image read straight out of a `docker save` tarball, without unpacking it
+pub struct DockerArchiveImage {
+    archive: Arc<TarArchive>,
+    config_digest: Digest,
+    image_config: ImageConfiguration,
+    layers: Vec<(Digest, PathBuf)>,
+}
+
+impl PartialEq for DockerArchiveImage {
+    fn eq(&self, other: &Self) -> bool {
+        self.image_digest() == other.image_digest()
+    }
+}
+
+impl Eq for DockerArchiveImage {}
+
+impl Hash for DockerArchiveImage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let digest = self.image_digest();
+        digest.digest().hash(state);
+        digest.algorithm().as_ref().hash(state);
+    }
+}
+
+impl Debug for DockerArchiveImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.config_digest.digest())
+    }
+}
+
+impl Display for DockerArchiveImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.platform().fmt(f)
+    }
+}
+
+impl DockerArchiveImage {
+    #[instrument(name = "load_images", skip(platform_matcher))]
+    pub fn from_archive(
+        path: &Path,
+        tag: Option<&Reference>,
+        platform_matcher: &PlatformMatcher,
+    ) -> anyhow::Result<Vec<Self>> {
+        let archive = Arc::new(TarArchive::open(path)?);
+        let manifest_content = archive.read_entry("manifest.json")?;
+        let manifests: Vec<ArchiveManifest> = serde_json::from_slice(&manifest_content)
+            .with_context(|| format!("Error parsing manifest.json from {}", path.display()))?;
+        let available_tags = || manifests.iter().flat_map(|m| m.repo_tags()).join(", ");
+
+        let manifest = match (tag, manifests.as_slice()) {
+            (_, []) => bail!("No images found in {}", path.display()),
+            (Some(tag), _) => match manifests.iter().find(|m| m.has_tag(tag)) {
+                Some(manifest) => manifest,
+                None => bail!(
+                    "Tag {tag} not found in {}. Available tags: {}",
+                    path.display(),
+                    available_tags()
+                ),
+            },
+            (None, [manifest]) => manifest,
+            (None, _) => bail!(
+                "{} contains {} images, select one by adding a tag to the path. Available tags: {}",
+                path.display(),
+                manifests.len(),
+                available_tags()
+            ),
+        };
+
+        let image = Self::from_manifest(archive, manifest)?;
+        if !platform_matcher.matches_image_config(image.config()) {
+            bail!(
+                "Image {} in {} does not match the platform filter {}",
+                image.platform(),
+                path.display(),
+                platform_matcher
+            );
+        }
+        Ok(vec![image])
+    }
+
+    fn from_manifest(archive: Arc<TarArchive>, manifest: &ArchiveManifest) -> anyhow::Result<Self> {
+        debug!("Reading config {} for {:?}", manifest.config, manifest.repo_tags());
+        let config_content = archive.read_entry(&manifest.config)?;
+        let (_, config_digest) = hash_reader(config_content.as_slice())?;
+        let image_config = ImageConfiguration::from_reader(config_content.as_slice())
+            .with_context(|| format!("Error reading image configuration {}", manifest.config))?;
+
+        // The diff IDs are the digests of the uncompressed layer tarballs, which is exactly what the archive holds
+        let diff_ids = image_config.rootfs().diff_ids();
+        if diff_ids.len() != manifest.layers.len() {
+            bail!(
+                "Image configuration {} lists {} layers, but the manifest has {}",
+                manifest.config,
+                diff_ids.len(),
+                manifest.layers.len()
+            );
+        }
+        let layers = diff_ids
+            .iter()
+            .zip(&manifest.layers)
+            .map(|(diff_id, path)| {
+                if !archive.contains(path) {
+                    bail!("Layer {path} not found in {archive}");
+                }
+                Ok((Digest::from_str(diff_id)?, PathBuf::from(path)))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            archive,
+            config_digest: config_digest.into(),
+            image_config,
+            layers,
+        })
+    }
+}
+
+impl InputImage for DockerArchiveImage {
+    fn image_digest(&self) -> Digest {
+        self.config_digest.clone()
+    }
+
+    fn layers_from_manifest(
+        &self,
+    ) -> anyhow::Result<impl ExactSizeIterator<Item = anyhow::Result<InputLayer<impl Read>>>> {
+        Ok(self.layers.iter().rev().map(|(digest, path)| {
+            // `docker save` writes uncompressed layers, but `docker load` accepts compressed ones as well
+            let reader = Compression::new_detected_reader(self.archive.open_entry(path)?)?;
+            InputLayer::new(digest.clone(), reader)
+        }))
+    }
+
+    fn config(&self) -> &ImageConfiguration {
+        &self.image_config
+    }
+
+    fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
+        Ok(self
+            .layers
+            .iter()
+            .map(|(digest, _)| (MediaType::ImageLayer, digest.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{add_file, build_layer, read_tar_entries_content, setup_tar};
+    use globset::Glob;
+    use std::io::Write;
+
+    fn sha256(content: &[u8]) -> String {
+        let (_, digest) = hash_reader(content).unwrap();
+        Digest::from(digest).to_string()
+    }
+
+    fn image_config(layers: &[&[u8]]) -> Vec<u8> {
+        let diff_ids = layers.iter().map(|layer| sha256(layer)).collect_vec();
+        serde_json::to_vec(&serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": diff_ids},
+            "history": [],
+        }))
+        .unwrap()
+    }
+
+    fn write_archive(compression: Compression) -> tempfile::NamedTempFile {
+        let layer_1 = build_layer().with_files(&[("one.txt", b"one")]).build_raw();
+        let layer_2 = build_layer().with_files(&[("two.txt", b"two")]).build_raw();
+        let config_1 = image_config(&[&layer_1]);
+        let config_2 = image_config(&[&layer_1, &layer_2]);
+        let manifest = serde_json::json!([
+            {"Config": "config-1.json", "RepoTags": ["app:1"], "Layers": ["layer-1/layer.tar"]},
+            {"Config": "config-2.json", "RepoTags": ["app:2", "example.com/app:2"], "Layers": ["layer-1/layer.tar", "layer-2/layer.tar"]},
+        ]);
+
+        let mut tar = setup_tar();
+        add_file(&mut tar, "layer-1/layer.tar", &layer_1);
+        add_file(&mut tar, "layer-2/layer.tar", &layer_2);
+        add_file(&mut tar, "config-1.json", &config_1);
+        add_file(&mut tar, "config-2.json", &config_2);
+        add_file(&mut tar, "manifest.json", &serde_json::to_vec(&manifest).unwrap());
+        let data = tar.into_inner().unwrap();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = compression.new_writer(file.as_file_mut(), 1).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_select_tag() {
+        let file = write_archive(Compression::Raw);
+        let matcher = PlatformMatcher::match_all();
+        let error = DockerArchiveImage::from_archive(file.path(), None, &matcher).unwrap_err();
+        assert!(error.to_string().contains("app:1, app:2"), "{error}");
+
+        let tag = Reference::from_str("example.com/app:2").unwrap();
+        let images = DockerArchiveImage::from_archive(file.path(), Some(&tag), &matcher).unwrap();
+        assert_eq!(images[0].layers().unwrap().len(), 2);
+
+        let tag = Reference::from_str("app:3").unwrap();
+        assert!(DockerArchiveImage::from_archive(file.path(), Some(&tag), &matcher).is_err());
+
+        let linux_arm = PlatformMatcher::from_glob(Glob::new("linux/arm64").unwrap()).unwrap();
+        let tag = Reference::from_str("app:1").unwrap();
+        assert!(DockerArchiveImage::from_archive(file.path(), Some(&tag), &linux_arm).is_err());
+    }
+
+    #[test]
+    fn test_read_layers() {
+        for compression in [Compression::Raw, Compression::Gzip] {
+            let file = write_archive(compression);
+            let tag = Reference::from_str("app:2").unwrap();
+            let images =
+                DockerArchiveImage::from_archive(file.path(), Some(&tag), &PlatformMatcher::match_all()).unwrap();
+            let layers = images[0]
+                .layers_from_manifest()
+                .unwrap()
+                .map(|layer| {
+                    let mut layer = layer.unwrap();
+                    let mut content = vec![];
+                    let mut builder = tar::Builder::new(&mut content);
+                    for entry in layer.entries().unwrap() {
+                        let mut entry = entry.unwrap();
+                        let header = entry.header().clone();
+                        builder.append(&header, &mut entry).unwrap();
+                    }
+                    builder.finish().unwrap();
+                    drop(builder);
+                    read_tar_entries_content(&content)
+                })
+                .collect_vec();
+            // Layers are returned top-down
+            assert_eq!(layers[0][&PathBuf::from("two.txt")], b"two");
+            assert_eq!(layers[1][&PathBuf::from("one.txt")], b"one");
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::header::{HOST, USER_AGENT};
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tracing::{debug, instrument};
+
+pub const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// The socket to reach the Docker daemon on. Falls back to a `unix://` `DOCKER_HOST`, then the default socket.
+pub fn docker_socket_path(socket: Option<&Path>) -> PathBuf {
+    if let Some(socket) = socket {
+        return socket.to_path_buf();
+    }
+    match std::env::var("DOCKER_HOST") {
+        Ok(host) if host.starts_with("unix://") => PathBuf::from(&host["unix://".len()..]),
+        _ => PathBuf::from(DEFAULT_DOCKER_SOCKET),
+    }
+}
+
+/// Streams `image` from the Docker daemon into `destination`, in the same format as `docker save`.
+/// Returns the number of bytes written.
+#[instrument(skip(destination))]
+pub async fn save_image(socket: &Path, image: &str, destination: &Path) -> anyhow::Result<u64> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Connecting to the Docker daemon at {}", socket.display()))?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Docker daemon connection closed: {e}");
+        }
+    });
+
+    let request = Request::get(format!("/images/{image}/get"))
+        .header(HOST, "docker")
+        .header(USER_AGENT, crate::registry::USER_AGENT)
+        .body(Empty::<Bytes>::new())
+        .with_context(|| format!("Invalid image name {image}"))?;
+    let response = sender.send_request(request).await.context("Requesting image export")?;
+    let status = response.status();
+    let mut body = response.into_body();
+    if !status.is_success() {
+        let content = body.collect().await?.to_bytes();
+        let message = serde_json::from_slice::<serde_json::Value>(&content)
+            .ok()
+            .and_then(|value| value["message"].as_str().map(str::to_string))
+            .unwrap_or_else(|| String::from_utf8_lossy(&content).into_owned());
+        bail!("Docker daemon could not export {image}: {status} {message}");
+    }
+
+    let mut file = tokio::fs::File::create(destination)
+        .await
+        .with_context(|| format!("Creating {}", destination.display()))?;
+    let mut written = 0;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame.context("Reading image export")?.into_data() {
+            file.write_all(&data).await?;
+            written += data.len() as u64;
+        }
+    }
+    file.flush().await?;
+    debug!("Saved {written} bytes to {}", destination.display());
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{serve_http_unix, TestResponse};
+
+    #[tokio::test]
+    async fn test_save_image() {
+        let directory = tempfile::tempdir().unwrap();
+        let socket = directory.path().join("docker.sock");
+        serve_http_unix(&socket, |request| match request.path.as_str() {
+            "/images/example.com/app:1.0/get" => TestResponse::new(200).body("archive content"),
+            _ => TestResponse::new(404).body(r#"{"message": "No such image: app:2.0"}"#),
+        })
+        .await;
+
+        let destination = directory.path().join("image.tar");
+        let written = save_image(&socket, "example.com/app:1.0", &destination).await.unwrap();
+        assert_eq!(written, 15);
+        assert_eq!(std::fs::read(&destination).unwrap(), b"archive content");
+
+        let error = save_image(&socket, "app:2.0", &destination).await.unwrap_err();
+        assert!(error.to_string().contains("No such image: app:2.0"), "{error}");
+    }
+
+    #[test]
+    fn test_docker_socket_path() {
+        assert_eq!(
+            docker_socket_path(Some(Path::new("/run/user/1000/docker.sock"))),
+            PathBuf::from("/run/user/1000/docker.sock")
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::input::IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE;
+use crate::registry::config::RegistryConfig;
+use anyhow::{bail, Context};
+use futures_util::TryStreamExt;
+use oci_spec::image::{Descriptor, ImageConfiguration, MediaType};
+use std::fmt::Display;
+use std::io::Read;
+use strum::{Display, EnumString};
+use tokio::io::AsyncRead;
+use tokio::runtime::Handle;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{debug, warn};
+
+/// What to do with layers that registries do not serve themselves: Docker's foreign layers and OCI's
+/// non-distributable layers, used for Windows base images and licensed content. Their descriptors list the URLs they
+/// are downloaded from instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum ForeignLayerPolicy {
+    /// Download them from their URLs, checking their digest, and repack them with the other layers
+    Fetch,
+    /// Reference them from the output manifest as they are, below the repacked layers. Only layers with URLs can be
+    /// kept, and the others are repacked.
+    #[default]
+    Keep,
+    /// Leave them out of the output image
+    Drop,
+}
+
+/// A foreign layer that is referenced by the output manifest without being repacked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignLayer {
+    pub descriptor: Descriptor,
+    /// The digest of the uncompressed layer, from the image configuration
+    pub diff_id: String,
+}
+
+pub fn is_foreign(descriptor: &Descriptor) -> bool {
+    let foreign_media_type = match descriptor.media_type() {
+        MediaType::ImageLayerNonDistributable
+        | MediaType::ImageLayerNonDistributableGzip
+        | MediaType::ImageLayerNonDistributableZstd => true,
+        media_type => media_type.as_ref() == IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE,
+    };
+    foreign_media_type || has_urls(descriptor)
+}
+
+/// Whether the layer lists URLs to download it from. Layers without any are only served alongside the image, so
+/// cannot be referenced by an output image that does not include them.
+pub fn has_urls(descriptor: &Descriptor) -> bool {
+    descriptor.urls().as_ref().is_some_and(|urls| !urls.is_empty())
+}
+
+/// Applies `policy` to the layers of a manifest, returning the layers to read and the foreign layers to keep as
+/// they are. Layers to read are left in order, and still include foreign layers if they are to be fetched or have no
+/// URLs to keep them by.
+pub fn split_foreign_layers(
+    layers: &[Descriptor],
+    config: &ImageConfiguration,
+    policy: ForeignLayerPolicy,
+    image: impl Display,
+) -> anyhow::Result<(Vec<Descriptor>, Vec<ForeignLayer>)> {
+    let mut read = vec![];
+    let mut kept = vec![];
+    for (index, descriptor) in layers.iter().enumerate() {
+        if !is_foreign(descriptor) || policy == ForeignLayerPolicy::Fetch {
+            read.push(descriptor.clone());
+            continue;
+        }
+        let digest = descriptor.digest();
+        if policy == ForeignLayerPolicy::Drop {
+            warn!("Dropping foreign layer {digest} from {image}");
+            continue;
+        }
+        if !has_urls(descriptor) {
+            debug!("Repacking foreign layer {digest} of {image}, as it has no URLs to be fetched from");
+            read.push(descriptor.clone());
+            continue;
+        }
+        if !read.is_empty() {
+            warn!(
+                "Foreign layer {digest} of {image} is above other layers, but will be kept below the repacked layers"
+            );
+        }
+        let diff_id = config
+            .rootfs()
+            .diff_ids()
+            .get(index)
+            .with_context(|| format!("The configuration of {image} has no diff ID for foreign layer {digest}"))?;
+        debug!("Keeping foreign layer {digest} of {image} as it is");
+        kept.push(ForeignLayer {
+            descriptor: descriptor.clone(),
+            diff_id: diff_id.clone(),
+        });
+    }
+    Ok((read, kept))
+}
+
+pub type UrlReader = SyncIoBridge<Box<dyn AsyncRead + Send + Unpin>>;
+
+/// Opens a foreign layer's URL for reading in synchronous code, using the TLS and proxy settings for its host
+pub fn open_url(url: &str, config: &RegistryConfig, handle: &Handle) -> anyhow::Result<UrlReader> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("Invalid foreign layer URL {url}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("Foreign layer URL {url} is not http or https");
+    }
+    let host = match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        (None, _) => bail!("Foreign layer URL {url} has no host"),
+    };
+    let client = config.http_client(&host)?;
+    debug!("Fetching foreign layer from {url}");
+    let response = handle
+        .block_on(client.get(parsed).send())
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Fetching {url}"))?;
+    let stream = response.bytes_stream().map_err(std::io::Error::other);
+    let reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(StreamReader::new(stream));
+    Ok(SyncIoBridge::new_with_handle(reader, handle.clone()))
+}
+
+/// Reads the foreign layer from the first of `urls` that can be opened
+pub fn open_urls(urls: &[String], config: &RegistryConfig, handle: &Handle) -> anyhow::Result<impl Read> {
+    let mut last_error = None;
+    for url in urls {
+        match open_url(url, config, handle) {
+            Ok(reader) => return Ok(reader),
+            Err(e) => {
+                warn!("{e:#}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("The foreign layer has no URLs")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{DescriptorBuilder, ImageConfigurationBuilder, RootFsBuilder};
+    use std::str::FromStr;
+
+    fn layer(media_type: &str, digest: &str, urls: &[&str]) -> Descriptor {
+        let digest = format!("sha256:{}", digest.repeat(64));
+        let mut builder = DescriptorBuilder::default()
+            .media_type(MediaType::from(media_type))
+            .digest(oci_spec::image::Digest::from_str(&digest).unwrap())
+            .size(1u64);
+        if !urls.is_empty() {
+            builder = builder.urls(urls.iter().map(|url| url.to_string()).collect::<Vec<_>>());
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_split_foreign_layers() {
+        let layers = [
+            layer(IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE, "a", &["https://example.com/a"]),
+            layer("application/vnd.oci.image.layer.nondistributable.v1.tar+gzip", "b", &[]),
+            layer("application/vnd.oci.image.layer.v1.tar+gzip", "c", &[]),
+        ];
+        let config = ImageConfigurationBuilder::default()
+            .rootfs(
+                RootFsBuilder::default()
+                    .diff_ids(["sha256:1", "sha256:2", "sha256:3"].map(String::from).to_vec())
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        assert!(is_foreign(&layers[0]) && is_foreign(&layers[1]) && !is_foreign(&layers[2]));
+        assert!(has_urls(&layers[0]) && !has_urls(&layers[1]));
+
+        // The non-distributable layer has no URLs, so an output image could not reference it
+        let (read, kept) = split_foreign_layers(&layers, &config, ForeignLayerPolicy::Keep, "image").unwrap();
+        assert_eq!(read, layers[1..]);
+        assert_eq!(
+            kept.iter().map(|layer| layer.diff_id.as_str()).collect::<Vec<_>>(),
+            ["sha256:1"]
+        );
+
+        let (read, kept) = split_foreign_layers(&layers, &config, ForeignLayerPolicy::Drop, "image").unwrap();
+        assert_eq!((read, kept), (vec![layers[2].clone()], vec![]));
+
+        let (read, kept) = split_foreign_layers(&layers, &config, ForeignLayerPolicy::Fetch, "image").unwrap();
+        assert_eq!((read, kept), (layers.to_vec(), vec![]));
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::Context;
 use oci_spec::image::Digest;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::Read;
@@ -17,6 +18,31 @@ impl<T: Read> InputLayer<T> {
     pub fn entries(&mut self) -> anyhow::Result<impl Iterator<Item = std::io::Result<Entry<T>>>> {
         Ok(self.archive.entries()?)
     }
+
+    /// Erases the type of the layer's reader, for images made of layers from different sources. The layer must not
+    /// have been read yet.
+    pub fn boxed<'a>(self) -> InputLayer<Box<dyn Read + 'a>>
+    where
+        T: 'a,
+    {
+        InputLayer {
+            name: self.name,
+            archive: Archive::new(Box::new(self.archive.into_inner())),
+        }
+    }
+
+    /// Reads the layer to the end. Tar archives end before the data does, and readers that verify the layer
+    /// content only do so once they reach the end.
+    pub fn finish(self) -> anyhow::Result<()> {
+        let mut reader = self.archive.into_inner();
+        std::io::copy(&mut reader, &mut std::io::sink()).with_context(|| format!("Reading layer {}", self.name))?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> T {
+        self.archive.into_inner()
+    }
 }
 
 impl<T: Read> Display for InputLayer<T> {
@@ -0,0 +1,92 @@
+use oci_spec::image::{Descriptor, Digest};
+use strum::Display;
+
+/// Set on eStargz layers, holding the digest of their table of contents
+const ESTARGZ_TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+/// Set on zstd:chunked layers, holding the digest of the table of contents in their trailing skippable frames
+const ZSTD_CHUNKED_MANIFEST_CHECKSUM_ANNOTATION: &str = "io.github.containers.zstd-chunked.manifest-checksum";
+
+/// Entries eStargz adds at the root of each layer for lazy pulling: the table of contents, and the landmarks marking
+/// the end of the files to prefetch
+const ESTARGZ_METADATA_ENTRIES: [&[u8]; 3] = [b"stargz.index.json", b".prefetch.landmark", b".no.prefetch.landmark"];
+
+/// Layer formats that let snapshotters mount an image before pulling it, by adding a table of contents to each layer.
+/// They are ordinary gzip or zstd layers otherwise: eStargz adds entries to the tar stream, while zstd:chunked adds
+/// zstd skippable frames that decoders pass over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum LazyPullFormat {
+    #[strum(serialize = "eStargz")]
+    Estargz,
+    #[strum(serialize = "zstd:chunked")]
+    ZstdChunked,
+}
+
+impl LazyPullFormat {
+    /// The format of a layer, from the annotations on its descriptor
+    pub fn from_descriptor(descriptor: &Descriptor) -> Option<Self> {
+        let annotations = descriptor.annotations().as_ref()?;
+        if annotations.contains_key(ESTARGZ_TOC_DIGEST_ANNOTATION) {
+            Some(Self::Estargz)
+        } else if annotations.contains_key(ZSTD_CHUNKED_MANIFEST_CHECKSUM_ANNOTATION) {
+            Some(Self::ZstdChunked)
+        } else {
+            None
+        }
+    }
+
+    /// The format of each layer that has one, in order
+    pub fn of_layers<'a>(layers: impl IntoIterator<Item = &'a Descriptor>) -> Vec<(Digest, Self)> {
+        layers
+            .into_iter()
+            .filter_map(|layer| Some((layer.digest().clone(), Self::from_descriptor(layer)?)))
+            .collect()
+    }
+}
+
+/// Whether a tar entry of a lazy-pull layer is its metadata rather than part of the image's filesystem. Plain layers
+/// may have files of the same names.
+pub fn is_lazy_pull_metadata(path: &[u8]) -> bool {
+    let path = path.strip_prefix(b"./").or(path.strip_prefix(b"/")).unwrap_or(path);
+    ESTARGZ_METADATA_ENTRIES.contains(&path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::{DescriptorBuilder, Digest, MediaType};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn layer(annotation: Option<&str>) -> Descriptor {
+        let mut builder = DescriptorBuilder::default()
+            .media_type(MediaType::ImageLayerGzip)
+            .digest(
+                Digest::from_str("sha256:0d90d93a5cab3fd2879040420c7b7e4958aee8997fef78e9a5dd80cb01f3bd9c").unwrap(),
+            )
+            .size(1u64);
+        if let Some(annotation) = annotation {
+            builder = builder.annotations(HashMap::from([(annotation.to_string(), "sha256:abc".to_string())]));
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_lazy_pull_format() {
+        assert_eq!(LazyPullFormat::of_layers(&[layer(None)]), []);
+        let estargz = layer(Some(ESTARGZ_TOC_DIGEST_ANNOTATION));
+        assert_eq!(
+            LazyPullFormat::of_layers(&[layer(None), estargz.clone()]),
+            [(estargz.digest().clone(), LazyPullFormat::Estargz)]
+        );
+        assert_eq!(
+            LazyPullFormat::from_descriptor(&layer(Some(ZSTD_CHUNKED_MANIFEST_CHECKSUM_ANNOTATION))),
+            Some(LazyPullFormat::ZstdChunked)
+        );
+        assert_eq!(LazyPullFormat::ZstdChunked.to_string(), "zstd:chunked");
+
+        assert!(is_lazy_pull_metadata(b"stargz.index.json"));
+        assert!(is_lazy_pull_metadata(b"./.prefetch.landmark"));
+        assert!(is_lazy_pull_metadata(b".no.prefetch.landmark"));
+        assert!(!is_lazy_pull_metadata(b"app/stargz.index.json"));
+    }
+}
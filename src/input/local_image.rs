@@ -1,19 +1,167 @@
+use crate::compression::Compression;
+use crate::input::archive::{ArchiveEntryReader, TarArchive};
+use crate::input::foreign_layers::{is_foreign, split_foreign_layers, ForeignLayer, ForeignLayerPolicy};
 use crate::input::layers::InputLayer;
+use crate::input::lazy_pull::LazyPullFormat;
 use crate::input::InputImage;
+use crate::location::ImageSelector;
 use crate::platform_matcher::PlatformMatcher;
 use crate::progress;
 use anyhow::{bail, Context};
-use oci_spec::image::{Descriptor, Digest, ImageConfiguration, ImageIndex, ImageManifest, MediaType};
+use itertools::Itertools;
+use oci_spec::image::{
+    Descriptor, Digest, ImageConfiguration, ImageIndex, ImageManifest, MediaType, ANNOTATION_REF_NAME,
+};
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tracing::{debug, instrument, warn};
 
+/// Where the files of an OCI image layout are read from
+#[derive(Clone)]
+enum OciLayout {
+    Directory(PathBuf),
+    Archive(Arc<TarArchive>),
+}
+
+impl Display for OciLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OciLayout::Directory(directory) => write!(f, "{}", directory.display()),
+            OciLayout::Archive(archive) => write!(f, "{archive}"),
+        }
+    }
+}
+
+enum OciLayoutReader<'a> {
+    File(File),
+    Archive(ArchiveEntryReader<'a>),
+}
+
+impl Read for OciLayoutReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            OciLayoutReader::File(file) => file.read(buf),
+            OciLayoutReader::Archive(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl OciLayout {
+    fn exists(&self, path: impl AsRef<Path>) -> bool {
+        match self {
+            OciLayout::Directory(directory) => directory.join(path).exists(),
+            OciLayout::Archive(archive) => archive.contains(path),
+        }
+    }
+
+    fn open(&self, path: impl AsRef<Path>) -> anyhow::Result<OciLayoutReader<'_>> {
+        let path = path.as_ref();
+        match self {
+            OciLayout::Directory(directory) => {
+                let path = directory.join(path);
+                let file = File::open(&path).with_context(|| format!("Opening {path:?}"))?;
+                Ok(OciLayoutReader::File(file))
+            }
+            OciLayout::Archive(archive) => Ok(OciLayoutReader::Archive(archive.open_entry(path)?)),
+        }
+    }
+
+    fn open_blob(&self, digest: &Digest) -> anyhow::Result<OciLayoutReader<'_>> {
+        self.open(blob_path(digest))
+    }
+
+    fn read_image_manifest(&self, path: impl AsRef<Path>) -> anyhow::Result<ImageManifest> {
+        let path = path.as_ref();
+        ImageManifest::from_reader(self.open(path)?)
+            .with_context(|| format!("Error reading image manifest from {path:?} in {self}"))
+    }
+
+    fn read_image_index(&self, path: impl AsRef<Path>) -> anyhow::Result<ImageIndex> {
+        let path = path.as_ref();
+        ImageIndex::from_reader(self.open(path)?)
+            .with_context(|| format!("Error reading image index from {path:?} in {self}"))
+    }
+}
+
+fn blob_path(digest: &Digest) -> PathBuf {
+    Path::new("blobs")
+        .join(digest.algorithm().as_ref())
+        .join(digest.digest())
+}
+
+fn descriptor_path(descriptor: &Descriptor) -> PathBuf {
+    blob_path(descriptor.digest())
+}
+
+fn ref_name(descriptor: &Descriptor) -> Option<&str> {
+    descriptor
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(ANNOTATION_REF_NAME))
+        .map(|name| name.as_str())
+}
+
+/// Picks the entries of the top-level index to read images from
+fn select_manifests<'a>(
+    index: &'a ImageIndex,
+    selector: Option<&ImageSelector>,
+    layout: &OciLayout,
+) -> anyhow::Result<Vec<&'a Descriptor>> {
+    let manifests = index.manifests();
+    let (description, selected) = match selector {
+        None => {
+            let ref_names = manifests.iter().filter_map(ref_name).unique().collect_vec();
+            if ref_names.len() > 1 {
+                warn!(
+                    "{layout} holds {} images ({}), reading all of them. Pick one with `:<ref name>`",
+                    ref_names.len(),
+                    ref_names.join(", ")
+                );
+            }
+            return Ok(manifests.iter().collect());
+        }
+        Some(ImageSelector::RefName(name)) => (
+            format!("ref name {name}"),
+            manifests
+                .iter()
+                .filter(|descriptor| ref_name(descriptor) == Some(name.as_str()))
+                .collect_vec(),
+        ),
+        Some(ImageSelector::Digest(digest)) => (
+            format!("digest {digest}"),
+            manifests
+                .iter()
+                .filter(|descriptor| descriptor.digest() == digest)
+                .collect_vec(),
+        ),
+    };
+    if selected.is_empty() {
+        let available = manifests
+            .iter()
+            .map(|descriptor| match ref_name(descriptor) {
+                Some(name) => format!("{name} ({})", descriptor.digest()),
+                None => descriptor.digest().to_string(),
+            })
+            .join(", ");
+        bail!("No image with {description} in {layout}. Available refs: {available}");
+    }
+    Ok(selected)
+}
+
 pub struct LocalOciImage {
-    blob_directory: PathBuf,
+    layout: OciLayout,
     manifest: ImageManifest,
+    /// The digest of the manifest, unless it was read from a `manifest.json` without an index
+    manifest_digest: Option<Digest>,
+    /// The layers that are read, which excludes foreign layers that are kept or dropped
+    layers: Vec<Descriptor>,
+    foreign_layers: Vec<ForeignLayer>,
+    /// The layers that are in a lazy-pull format, and which format
+    lazy_pull_layers: Vec<(Digest, LazyPullFormat)>,
     image_config: ImageConfiguration,
 }
 
@@ -45,39 +193,48 @@ impl Display for LocalOciImage {
     }
 }
 
-fn get_blob_path(blob_directory: &Path, descriptor: &Descriptor) -> PathBuf {
-    let digest = descriptor.digest();
-    blob_directory.join(digest.digest())
-}
-
-fn read_blob_image_manifest(blob_directory: &Path, descriptor: &Descriptor) -> anyhow::Result<ImageManifest> {
-    let digest_path = get_blob_path(blob_directory, descriptor);
-    ImageManifest::from_file(&digest_path).with_context(|| format!("Error reading image manifest from {digest_path:?}"))
-}
-
-fn read_blob_image_index(blob_directory: &Path, descriptor: &Descriptor) -> anyhow::Result<ImageIndex> {
-    let digest_path = get_blob_path(blob_directory, descriptor);
-    ImageIndex::from_file(&digest_path).with_context(|| format!("Error reading image index from {digest_path:?}"))
-}
-
 impl LocalOciImage {
     #[instrument(name = "load_images")]
     pub fn from_oci_directory(
         directory: impl AsRef<Path> + Debug,
+        selector: Option<&ImageSelector>,
         platform_matcher: &PlatformMatcher,
+        foreign_layers: ForeignLayerPolicy,
     ) -> anyhow::Result<Vec<Self>> {
-        let directory = directory.as_ref();
-        let blob_directory = directory.join("blobs").join("sha256");
+        let layout = OciLayout::Directory(directory.as_ref().to_path_buf());
+        Self::from_layout(layout, selector, platform_matcher, foreign_layers)
+    }
 
-        let index_path = directory.join("index.json");
-        let manifest_path = directory.join("manifest.json");
+    /// Reads images from a tarball of an OCI layout, as written by `skopeo copy` or `buildah push` to an
+    /// `oci-archive:` destination. The archive is indexed once and never unpacked.
+    #[instrument(name = "load_images")]
+    pub fn from_oci_archive(
+        path: impl AsRef<Path> + Debug,
+        selector: Option<&ImageSelector>,
+        platform_matcher: &PlatformMatcher,
+        foreign_layers: ForeignLayerPolicy,
+    ) -> anyhow::Result<Vec<Self>> {
+        let archive = TarArchive::open(path.as_ref())?;
+        Self::from_layout(
+            OciLayout::Archive(Arc::new(archive)),
+            selector,
+            platform_matcher,
+            foreign_layers,
+        )
+    }
 
-        if index_path.exists() {
-            debug!("Reading index from {index_path:?}");
-            let index = ImageIndex::from_file(&index_path)
-                .with_context(|| format!("Error reading index from {index_path:?}"))?;
+    fn from_layout(
+        layout: OciLayout,
+        selector: Option<&ImageSelector>,
+        platform_matcher: &PlatformMatcher,
+        foreign_layers: ForeignLayerPolicy,
+    ) -> anyhow::Result<Vec<Self>> {
+        if layout.exists("index.json") {
+            debug!("Reading index from {layout}");
+            let index = layout.read_image_index("index.json")?;
+            let manifests = select_manifests(&index, selector, &layout)?;
             let mut images = vec![];
-            let manifest_iterator = progress::progress_iter("Reading Manifests", index.manifests().iter());
+            let manifest_iterator = progress::progress_iter("Reading Manifests", manifests.into_iter());
             for manifest_descriptor in manifest_iterator {
                 if !platform_matcher.matches_oci_spec_platform(manifest_descriptor.platform().as_ref()) {
                     continue;
@@ -85,18 +242,21 @@ impl LocalOciImage {
                 match manifest_descriptor.media_type() {
                     MediaType::ImageManifest => {
                         debug!("Reading image manifest from {}", manifest_descriptor.digest());
-                        let manifest = read_blob_image_manifest(&blob_directory, manifest_descriptor)
+                        let manifest = layout
+                            .read_image_manifest(descriptor_path(manifest_descriptor))
                             .context("Reading manifest")?;
-                        let img = Self::from_image_manifest(manifest, blob_directory.clone())
+                        let digest = manifest_descriptor.digest().clone();
+                        let img = Self::from_image_manifest(manifest, Some(digest), layout.clone(), foreign_layers)
                             .context("Constructing LocalOciImage")?;
                         images.push(img);
                     }
                     MediaType::ImageIndex => {
                         debug!("Reading image index from {}", manifest_descriptor.digest());
-                        let index =
-                            read_blob_image_index(&blob_directory, manifest_descriptor).context("Reading index")?;
+                        let index = layout
+                            .read_image_index(descriptor_path(manifest_descriptor))
+                            .context("Reading index")?;
                         images.extend(
-                            Self::from_image_index(index, blob_directory.clone(), platform_matcher)
+                            Self::from_image_index(index, layout.clone(), platform_matcher, foreign_layers)
                                 .context("Parsing image index")?,
                         );
                     }
@@ -106,44 +266,67 @@ impl LocalOciImage {
                 }
             }
             Ok(images)
-        } else if manifest_path.exists() {
-            debug!("Reading manifest from {manifest_path:?}");
-            let manifest = ImageManifest::from_file(&manifest_path)
-                .with_context(|| format!("Error reading manifest from {manifest_path:?}"))?;
-            let img = Self::from_image_manifest(manifest, blob_directory).context("Constructing LocalOciImage")?;
+        } else if let Some(selector) = selector {
+            bail!("Cannot select {selector} from {layout}, it has no index.json");
+        } else if layout.exists("manifest.json") {
+            debug!("Reading manifest from {layout}");
+            let manifest = layout.read_image_manifest("manifest.json")?;
+            let img = Self::from_image_manifest(manifest, None, layout, foreign_layers)
+                .context("Constructing LocalOciImage")?;
             Ok(vec![img])
         } else {
-            bail!("No manifest or index found in {directory:?}");
+            bail!("No manifest or index found in {layout}");
         }
     }
 
     fn from_image_index(
         index: ImageIndex,
-        blob_directory: PathBuf,
+        layout: OciLayout,
         platform_matcher: &PlatformMatcher,
+        foreign_layers: ForeignLayerPolicy,
     ) -> anyhow::Result<Vec<Self>> {
         let mut images = vec![];
         for manifest_descriptor in index.manifests() {
             if !platform_matcher.matches_oci_spec_platform(manifest_descriptor.platform().as_ref()) {
                 continue;
             }
-            let manifest = read_blob_image_manifest(&blob_directory, manifest_descriptor)?;
-            let img = Self::from_image_manifest(manifest, blob_directory.clone())
+            let manifest = layout.read_image_manifest(descriptor_path(manifest_descriptor))?;
+            let digest = manifest_descriptor.digest().clone();
+            let img = Self::from_image_manifest(manifest, Some(digest), layout.clone(), foreign_layers)
                 .with_context(|| format!("Constructing LocalOciImage for {}", manifest_descriptor.digest()))?;
             images.push(img);
         }
         Ok(images)
     }
 
-    fn from_image_manifest(manifest: ImageManifest, blob_directory: PathBuf) -> anyhow::Result<Self> {
-        let config_descriptor = manifest.config();
-        let config_digest = config_descriptor.digest();
-        let config_path = blob_directory.join(config_digest.digest());
-        let image_config = ImageConfiguration::from_file(&config_path)
-            .with_context(|| format!("Error reading image configuration from {config_path:?}"))?;
+    fn from_image_manifest(
+        manifest: ImageManifest,
+        manifest_digest: Option<Digest>,
+        layout: OciLayout,
+        foreign_layers: ForeignLayerPolicy,
+    ) -> anyhow::Result<Self> {
+        let config_path = descriptor_path(manifest.config());
+        let image_config = ImageConfiguration::from_reader(layout.open(&config_path)?)
+            .with_context(|| format!("Error reading image configuration from {config_path:?} in {layout}"))?;
+        let (layers, foreign_layers) = split_foreign_layers(manifest.layers(), &image_config, foreign_layers, &layout)?;
+        // Layouts copied with their foreign layers have the blobs, and fetching them is left to registry sources
+        if let Some(missing) = layers
+            .iter()
+            .find(|layer| is_foreign(layer) && !layout.exists(descriptor_path(layer)))
+        {
+            bail!(
+                "Foreign layer {} is not in {layout}, and is only fetched from its URLs for registry sources. \
+                 Use --foreign-layers=keep or --foreign-layers=drop instead",
+                missing.digest()
+            );
+        }
         Ok(Self {
-            blob_directory,
+            layout,
             manifest,
+            manifest_digest,
+            lazy_pull_layers: LazyPullFormat::of_layers(&layers),
+            layers,
+            foreign_layers,
             image_config,
         })
     }
@@ -159,9 +342,11 @@ impl InputImage for LocalOciImage {
         &self,
     ) -> anyhow::Result<impl ExactSizeIterator<Item = anyhow::Result<InputLayer<impl Read>>>> {
         Ok(self.layers_with_compression()?.map(|(compression, digest)| {
-            let path = self.blob_directory.join(digest.digest());
-            let file = File::open(&path).with_context(|| format!("Error reading input layer from {path:?}"))?;
-            let reader = compression.new_reader(file)?;
+            let blob = self
+                .layout
+                .open_blob(&digest)
+                .with_context(|| format!("Error reading input layer {digest}"))?;
+            let reader = Compression::new_checked_reader(compression, blob, format_args!("Layer {digest}"))?;
             InputLayer::new(digest, reader)
         }))
     }
@@ -172,8 +357,7 @@ impl InputImage for LocalOciImage {
 
     fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
         Ok(self
-            .manifest
-            .layers()
+            .layers
             .iter()
             .map(|d| {
                 let stripped_digest = d.digest();
@@ -181,4 +365,157 @@ impl InputImage for LocalOciImage {
             })
             .collect())
     }
+
+    fn manifest_digest(&self) -> Option<Digest> {
+        self.manifest_digest.clone()
+    }
+
+    fn layer_size(&self, digest: &Digest) -> Option<u64> {
+        self.manifest
+            .layers()
+            .iter()
+            .find_map(|layer| (layer.digest() == digest).then_some(layer.size()))
+    }
+
+    fn foreign_layers(&self) -> &[ForeignLayer] {
+        &self.foreign_layers
+    }
+
+    fn lazy_pull_format(&self) -> Option<LazyPullFormat> {
+        self.lazy_pull_layers.first().map(|(_, format)| *format)
+    }
+
+    fn layer_lazy_pull_format(&self, digest: &Digest) -> Option<LazyPullFormat> {
+        self.lazy_pull_layers
+            .iter()
+            .find_map(|(layer, format)| (layer == digest).then_some(*format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::inspect::ImageSummary;
+    use crate::output_image::image::hash_reader;
+    use crate::test_utils::{add_file, build_layer, setup_tar};
+    use std::io::Write;
+
+    fn add_blob(tar: &mut tar::Builder<Vec<u8>>, content: &[u8], media_type: MediaType) -> serde_json::Value {
+        let (size, digest) = hash_reader(content).unwrap();
+        let digest = Digest::from(digest);
+        add_file(tar, blob_path(&digest), content);
+        serde_json::json!({"mediaType": media_type.to_string(), "digest": digest.to_string(), "size": size})
+    }
+
+    /// Writes an OCI layout archive with an image for each ref name, holding a single `name.txt` file
+    fn write_archive(ref_names: &[&str]) -> tempfile::NamedTempFile {
+        let mut tar = setup_tar();
+        let mut manifests = vec![];
+        for ref_name in ref_names {
+            let layer = build_layer()
+                .with_files(&[("name.txt", ref_name.as_bytes())])
+                .build_raw();
+            let mut compressed_layer = Compression::Gzip.new_writer(vec![], 1).unwrap();
+            compressed_layer.write_all(&layer).unwrap();
+            let compressed_layer = compressed_layer.into_inner().unwrap();
+            let (_, diff_id) = hash_reader(layer.as_slice()).unwrap();
+            let config = serde_json::json!({
+                "architecture": "amd64",
+                "os": "linux",
+                "rootfs": {"type": "layers", "diff_ids": [Digest::from(diff_id).to_string()]},
+                "history": [],
+            });
+
+            let config_descriptor = add_blob(&mut tar, &serde_json::to_vec(&config).unwrap(), MediaType::ImageConfig);
+            let layer_descriptor = add_blob(&mut tar, &compressed_layer, MediaType::ImageLayerGzip);
+            let manifest = serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": MediaType::ImageManifest.to_string(),
+                "config": config_descriptor,
+                "layers": [layer_descriptor],
+            });
+            let mut manifest_descriptor = add_blob(
+                &mut tar,
+                &serde_json::to_vec(&manifest).unwrap(),
+                MediaType::ImageManifest,
+            );
+            manifest_descriptor["annotations"] = serde_json::json!({ANNOTATION_REF_NAME: ref_name});
+            manifests.push(manifest_descriptor);
+        }
+        let index = serde_json::json!({"schemaVersion": 2, "manifests": manifests});
+        add_file(&mut tar, "index.json", &serde_json::to_vec(&index).unwrap());
+        add_file(&mut tar, "oci-layout", br#"{"imageLayoutVersion": "1.0.0"}"#);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&tar.into_inner().unwrap()).unwrap();
+        file
+    }
+
+    fn read_name(image: &LocalOciImage) -> String {
+        let mut layers = image.layers_from_manifest().unwrap();
+        assert_eq!(layers.len(), 1);
+        let mut layer = layers.next().unwrap().unwrap();
+        let mut entry = layer.entries().unwrap().next().unwrap().unwrap();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_inspect_gzip_layer() {
+        let file = write_archive(&["1.0"]);
+        let matcher = PlatformMatcher::match_all();
+        let images = LocalOciImage::from_oci_archive(file.path(), None, &matcher, ForeignLayerPolicy::Keep).unwrap();
+        let summary = ImageSummary::new(&images[0], &matcher).unwrap();
+        // The gzip trailer only holds the size of the last member modulo 4 GiB, so the size is not taken from it
+        assert!(summary.layers[0].size.is_some());
+        assert_eq!(summary.layers[0].uncompressed_size, None);
+        assert_eq!(summary.uncompressed_size, None);
+    }
+
+    #[test]
+    fn test_read_oci_archive() {
+        let file = write_archive(&["1.0"]);
+        let images = LocalOciImage::from_oci_archive(
+            file.path(),
+            None,
+            &PlatformMatcher::match_all(),
+            ForeignLayerPolicy::Keep,
+        )
+        .unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].platform().to_string(), "linux/amd64");
+        assert_eq!(read_name(&images[0]), "1.0");
+    }
+
+    #[test]
+    fn test_select_image() {
+        let file = write_archive(&["app:1.0", "app:2.0"]);
+        let matcher = PlatformMatcher::match_all();
+        let all = LocalOciImage::from_oci_archive(file.path(), None, &matcher, ForeignLayerPolicy::Keep).unwrap();
+        assert_eq!(all.len(), 2);
+
+        let selector = ImageSelector::RefName("app:2.0".to_string());
+        let images =
+            LocalOciImage::from_oci_archive(file.path(), Some(&selector), &matcher, ForeignLayerPolicy::Keep).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(read_name(&images[0]), "app:2.0");
+
+        let index = OciLayout::Archive(Arc::new(TarArchive::open(file.path()).unwrap()))
+            .read_image_index("index.json")
+            .unwrap();
+        let selector = ImageSelector::Digest(index.manifests()[0].digest().clone());
+        let images =
+            LocalOciImage::from_oci_archive(file.path(), Some(&selector), &matcher, ForeignLayerPolicy::Keep).unwrap();
+        assert_eq!(read_name(&images[0]), "app:1.0");
+
+        let selector = ImageSelector::RefName("app:3.0".to_string());
+        let error = LocalOciImage::from_oci_archive(file.path(), Some(&selector), &matcher, ForeignLayerPolicy::Keep)
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Available refs: app:1.0 (sha256:"),
+            "{error}"
+        );
+    }
 }
@@ -1,23 +1,46 @@
 use crate::compression::Compression;
+use crate::input::foreign_layers::ForeignLayer;
 use crate::input::layers::InputLayer;
+use crate::input::lazy_pull::LazyPullFormat;
+use anyhow::Context;
 use itertools::Itertools;
 use oci_client::manifest::{
     IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE,
     IMAGE_LAYER_MEDIA_TYPE, IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE, IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE,
 };
-use oci_spec::image::{Digest, ImageConfiguration, MediaType};
+use oci_spec::image::{Digest, ImageConfiguration, MediaType, PlatformBuilder};
 use std::fmt::{Display, Formatter, Write};
 use std::hash::Hash;
 use std::io::Read;
 
+pub mod added_files;
+pub mod archive;
+pub mod blob_cache;
+pub mod containers_storage;
+pub mod docker_archive;
+pub mod docker_daemon;
+pub mod foreign_layers;
 pub mod layers;
+pub mod lazy_pull;
 pub mod local_image;
+pub mod overlay;
+pub mod prefetch;
 pub mod remote_image;
+pub mod rootfs;
 
+/// Windows base layers, which registries don't serve themselves. See [`foreign_layers`].
+pub const IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
 const IMAGE_DOCKER_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.zstd";
 const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
 const IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
+// There are no standard media types for these, so the OCI and Docker naming schemes are both followed
+const IMAGE_LAYER_XZ_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+xz";
+const IMAGE_DOCKER_LAYER_XZ_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.xz";
+const IMAGE_LAYER_BZIP2_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+bzip2";
+const IMAGE_DOCKER_LAYER_BZIP2_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.bzip2";
+const IMAGE_LAYER_LZ4_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+lz4";
+const IMAGE_DOCKER_LAYER_LZ4_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.lz4";
 
 pub fn get_layer_media_type(value: &str) -> Option<MediaType> {
     match value {
@@ -30,6 +53,32 @@ pub fn get_layer_media_type(value: &str) -> Option<MediaType> {
         IMAGE_DOCKER_LAYER_ZSTD_MEDIA_TYPE
         | IMAGE_LAYER_ZSTD_MEDIA_TYPE
         | IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE => Some(MediaType::ImageLayerZstd),
+        IMAGE_DOCKER_LAYER_XZ_MEDIA_TYPE | IMAGE_LAYER_XZ_MEDIA_TYPE => {
+            Some(MediaType::Other(IMAGE_LAYER_XZ_MEDIA_TYPE.to_string()))
+        }
+        IMAGE_DOCKER_LAYER_BZIP2_MEDIA_TYPE | IMAGE_LAYER_BZIP2_MEDIA_TYPE => {
+            Some(MediaType::Other(IMAGE_LAYER_BZIP2_MEDIA_TYPE.to_string()))
+        }
+        IMAGE_DOCKER_LAYER_LZ4_MEDIA_TYPE | IMAGE_LAYER_LZ4_MEDIA_TYPE => {
+            Some(MediaType::Other(IMAGE_LAYER_LZ4_MEDIA_TYPE.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// The compression declared by a layer media type, including Docker's
+pub fn layer_compression(media_type: &MediaType) -> Option<Compression> {
+    let media_type = get_layer_media_type(media_type.as_ref()).unwrap_or_else(|| media_type.clone());
+    match media_type {
+        MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => Some(Compression::Raw),
+        MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => Some(Compression::Gzip),
+        MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => Some(Compression::Zstd),
+        MediaType::Other(media_type) => match media_type.as_str() {
+            IMAGE_LAYER_XZ_MEDIA_TYPE => Some(Compression::Xz),
+            IMAGE_LAYER_BZIP2_MEDIA_TYPE => Some(Compression::Bzip2),
+            IMAGE_LAYER_LZ4_MEDIA_TYPE => Some(Compression::Lz4),
+            _ => None,
+        },
         _ => None,
     }
 }
@@ -40,6 +89,23 @@ pub struct Platform {
 }
 
 impl Platform {
+    /// The platform as it is given on a manifest's descriptor in an image index
+    pub fn descriptor_platform(&self) -> anyhow::Result<oci_spec::image::Platform> {
+        let mut platform = PlatformBuilder::default()
+            .os(self.config.os().clone())
+            .architecture(self.config.architecture().clone());
+        if let Some(variant) = self.config.variant() {
+            platform = platform.variant(variant.clone());
+        }
+        if let Some(os_version) = self.config.os_version() {
+            platform = platform.os_version(os_version.clone());
+        }
+        if let Some(os_features) = self.config.os_features() {
+            platform = platform.os_features(os_features.clone());
+        }
+        platform.build().context("PlatformBuilder Build")
+    }
+
     pub fn file_key(&self) -> anyhow::Result<String> {
         let mut f = String::new();
         f.write_fmt(format_args!("{}-{}", self.config.os(), self.config.architecture()))?;
@@ -84,21 +150,55 @@ pub trait InputImage: Display + Sized + Send + Sync + Hash + Eq + PartialEq {
 
     fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>>;
 
-    fn layers_with_compression(&self) -> anyhow::Result<impl ExactSizeIterator<Item = (Compression, Digest)>> {
+    /// The digest of the manifest the image was read from, if it has one
+    fn manifest_digest(&self) -> Option<Digest> {
+        None
+    }
+
+    /// The size of a layer blob as declared by the manifest, if it declares one
+    fn layer_size(&self, _digest: &Digest) -> Option<u64> {
+        None
+    }
+
+    /// Foreign layers that are referenced by the output manifest as they are, rather than being read
+    fn foreign_layers(&self) -> &[ForeignLayer] {
+        &[]
+    }
+
+    /// The lazy-pull format the manifest declares for the image's layers, if any
+    fn lazy_pull_format(&self) -> Option<LazyPullFormat> {
+        None
+    }
+
+    /// The lazy-pull format the manifest declares for a layer, whose metadata entries are dropped when it is merged
+    fn layer_lazy_pull_format(&self, _digest: &Digest) -> Option<LazyPullFormat> {
+        None
+    }
+
+    /// The compression each layer's media type declares, or `None` for media types that aren't recognised. Layers are
+    /// never skipped for their media type, as their compression is checked against their content when read.
+    fn layers_with_compression(&self) -> anyhow::Result<impl ExactSizeIterator<Item = (Option<Compression>, Digest)>> {
         let iterator = self
             .layers()?
             .into_iter()
-            .filter_map(|(media_type, digest)| match media_type {
-                MediaType::ImageLayer | MediaType::ImageLayerNonDistributable => Some((Compression::Raw, digest)),
-                MediaType::ImageLayerGzip | MediaType::ImageLayerNonDistributableGzip => {
-                    Some((Compression::Gzip, digest))
-                }
-                MediaType::ImageLayerZstd | MediaType::ImageLayerNonDistributableZstd => {
-                    Some((Compression::Zstd, digest))
-                }
-                _ => None,
-            })
+            .map(|(media_type, digest)| (layer_compression(&media_type), digest))
             .rev();
         Ok(iterator.collect_vec().into_iter())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_compression() {
+        let compression = |media_type: &str| layer_compression(&MediaType::from(media_type));
+        assert_eq!(compression(IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE), Some(Compression::Gzip));
+        assert_eq!(compression(IMAGE_LAYER_ZSTD_MEDIA_TYPE), Some(Compression::Zstd));
+        assert_eq!(compression(IMAGE_DOCKER_LAYER_XZ_MEDIA_TYPE), Some(Compression::Xz));
+        assert_eq!(compression(IMAGE_LAYER_BZIP2_MEDIA_TYPE), Some(Compression::Bzip2));
+        assert_eq!(compression(IMAGE_DOCKER_LAYER_LZ4_MEDIA_TYPE), Some(Compression::Lz4));
+        assert_eq!(compression("application/vnd.example.layer"), None);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::input::foreign_layers::ForeignLayer;
+use crate::input::layers::InputLayer;
+use crate::input::lazy_pull::LazyPullFormat;
+use crate::input::{InputImage, Platform};
+use crate::location::SourcePlatform;
+use crate::output_image::image::hash_reader;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use oci_spec::image::{Arch, Digest, ImageConfiguration, MediaType, Os};
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+
+type BoxedLayers<'a> = Box<dyn ExactSizeIterator<Item = anyhow::Result<InputLayer<Box<dyn Read + 'a>>>> + 'a>;
+
+/// The parts of [`InputImage`] that overlaying uses, in a form that can be boxed. Each source is read into a different
+/// image type, so the images of an overlay are boxed to be held together.
+pub trait AnyImage: Display + Send + Sync {
+    fn platform(&self) -> Platform;
+    fn config(&self) -> &ImageConfiguration;
+    fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>>;
+    fn layer_size(&self, digest: &Digest) -> Option<u64>;
+    fn foreign_layers(&self) -> &[ForeignLayer];
+    fn lazy_pull_format(&self) -> Option<LazyPullFormat>;
+    fn layer_lazy_pull_format(&self, digest: &Digest) -> Option<LazyPullFormat>;
+    fn boxed_layers(&self) -> anyhow::Result<BoxedLayers<'_>>;
+}
+
+impl<T: InputImage> AnyImage for T {
+    fn platform(&self) -> Platform {
+        InputImage::platform(self)
+    }
+
+    fn config(&self) -> &ImageConfiguration {
+        InputImage::config(self)
+    }
+
+    fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
+        InputImage::layers(self)
+    }
+
+    fn layer_size(&self, digest: &Digest) -> Option<u64> {
+        InputImage::layer_size(self, digest)
+    }
+
+    fn foreign_layers(&self) -> &[ForeignLayer] {
+        InputImage::foreign_layers(self)
+    }
+
+    fn lazy_pull_format(&self) -> Option<LazyPullFormat> {
+        InputImage::lazy_pull_format(self)
+    }
+
+    fn layer_lazy_pull_format(&self, digest: &Digest) -> Option<LazyPullFormat> {
+        InputImage::layer_lazy_pull_format(self, digest)
+    }
+
+    fn boxed_layers(&self) -> anyhow::Result<BoxedLayers<'_>> {
+        let layers = self.layers_from_manifest()?;
+        Ok(Box::new(layers.map(|layer| layer.map(InputLayer::boxed))))
+    }
+}
+
+pub type BoxedImage = Box<dyn AnyImage>;
+
+/// Merges the environment of images stacked bottom to top. Variables set by later images replace earlier ones, except
+/// for `PATH`, whose entries are combined with those of later images first.
+pub fn merge_env<'a>(envs: impl IntoIterator<Item = &'a [String]>) -> Vec<String> {
+    let mut merged: Vec<(&str, String)> = vec![];
+    for env in envs {
+        for variable in env {
+            let (key, value) = variable.split_once('=').unwrap_or((variable, ""));
+            let value = match merged.iter().find(|(existing, _)| *existing == key) {
+                Some((_, existing)) if key == "PATH" => value
+                    .split(':')
+                    .chain(existing.split(':'))
+                    .filter(|entry| !entry.is_empty())
+                    .unique()
+                    .join(":"),
+                _ => value.to_string(),
+            };
+            match merged.iter_mut().find(|(existing, _)| *existing == key) {
+                Some((_, existing)) => *existing = value,
+                None => merged.push((key, value)),
+            }
+        }
+    }
+    merged
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect()
+}
+
+/// Images of the same platform from several sources, with their filesystems stacked in order. Later images win, and
+/// their whiteouts hide files of the images below them.
+pub struct OverlayImage {
+    /// Bottom to top
+    images: Vec<BoxedImage>,
+    config_digest: Digest,
+    image_config: ImageConfiguration,
+    foreign_layers: Vec<ForeignLayer>,
+}
+
+impl PartialEq for OverlayImage {
+    fn eq(&self, other: &Self) -> bool {
+        InputImage::image_digest(self) == InputImage::image_digest(other)
+    }
+}
+
+impl Eq for OverlayImage {}
+
+impl Hash for OverlayImage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let digest = InputImage::image_digest(self);
+        digest.digest().hash(state);
+        digest.algorithm().as_ref().hash(state);
+    }
+}
+
+impl Debug for OverlayImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.images.iter().join(" + "))
+    }
+}
+
+impl Display for OverlayImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        InputImage::platform(self).fmt(f)
+    }
+}
+
+impl OverlayImage {
+    /// Stacks `images`, given bottom to top, using the configuration of the image at `config_from` with the
+    /// environment of every image merged into it
+    pub fn new(images: Vec<BoxedImage>, config_from: usize) -> anyhow::Result<Self> {
+        let Some(config_image) = images.get(config_from) else {
+            bail!(
+                "Cannot take the configuration from source {config_from}, there are {} sources",
+                images.len()
+            );
+        };
+        let mut image_config = config_image.config().clone();
+        let env = merge_env(
+            images
+                .iter()
+                .filter_map(|image| image.config().config().as_ref()?.env().as_deref()),
+        );
+        if !env.is_empty() {
+            let mut config = image_config.config().clone().unwrap_or_default();
+            config.set_env(Some(env));
+            image_config.set_config(Some(config));
+        }
+        let (_, config_digest) = hash_reader(image_config.to_string()?.as_bytes())?;
+        let foreign_layers = images
+            .iter()
+            .flat_map(|image| image.foreign_layers().iter().cloned())
+            .collect();
+        Ok(Self {
+            images,
+            config_digest: config_digest.into(),
+            image_config,
+            foreign_layers,
+        })
+    }
+
+    /// Replaces the platform in the configuration, for sources built for one platform whose configuration does not say
+    /// so. A single image is stacked on its own to do this.
+    pub fn with_platform(mut self, platform: &SourcePlatform) -> anyhow::Result<Self> {
+        self.image_config.set_os(Os::from(platform.os.as_str()));
+        self.image_config
+            .set_architecture(Arch::from(platform.architecture.as_str()));
+        self.image_config.set_variant(platform.variant.clone());
+        let (_, config_digest) = hash_reader(self.image_config.to_string()?.as_bytes())?;
+        self.config_digest = config_digest.into();
+        Ok(self)
+    }
+
+    /// Pairs every image of `base` with the image of the same platform from each of `overlays`, stacked on top of it
+    /// in order
+    pub fn stack(
+        base: Vec<BoxedImage>,
+        mut overlays: Vec<(String, Vec<BoxedImage>)>,
+        config_from: usize,
+    ) -> anyhow::Result<Vec<Self>> {
+        base.into_iter()
+            .map(|base_image| {
+                let platform = base_image.platform();
+                let mut images = vec![base_image];
+                for (source, overlay_images) in &mut overlays {
+                    let index = overlay_images
+                        .iter()
+                        .position(|image| image.platform().to_string() == platform.to_string())
+                        .with_context(|| format!("{source} has no image for platform {platform}"))?;
+                    images.push(overlay_images.remove(index));
+                }
+                Self::new(images, config_from)
+            })
+            .collect()
+    }
+}
+
+/// The layers of several images in turn. [`Iterator::flatten`] does the same, but loses their exact size.
+struct ChainedLayers<'a> {
+    images: std::vec::IntoIter<BoxedLayers<'a>>,
+    current: Option<BoxedLayers<'a>>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for ChainedLayers<'a> {
+    type Item = anyhow::Result<InputLayer<Box<dyn Read + 'a>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(layer) = self.current.as_mut().and_then(|layers| layers.next()) {
+                self.remaining -= 1;
+                return Some(layer);
+            }
+            self.current = Some(self.images.next()?);
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for ChainedLayers<'_> {}
+
+impl InputImage for OverlayImage {
+    fn image_digest(&self) -> Digest {
+        self.config_digest.clone()
+    }
+
+    fn layers_from_manifest(
+        &self,
+    ) -> anyhow::Result<impl ExactSizeIterator<Item = anyhow::Result<InputLayer<impl Read>>>> {
+        // Every image's layers are opened up front, so that their downloads start together
+        let layers = self
+            .images
+            .iter()
+            .rev()
+            .map(|image| image.boxed_layers())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(ChainedLayers {
+            remaining: layers.iter().map(|layers| layers.len()).sum(),
+            images: layers.into_iter(),
+            current: None,
+        })
+    }
+
+    fn config(&self) -> &ImageConfiguration {
+        &self.image_config
+    }
+
+    fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
+        Ok(self
+            .images
+            .iter()
+            .map(|image| image.layers())
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    fn layer_size(&self, digest: &Digest) -> Option<u64> {
+        self.images.iter().find_map(|image| image.layer_size(digest))
+    }
+
+    fn foreign_layers(&self) -> &[ForeignLayer] {
+        &self.foreign_layers
+    }
+
+    fn lazy_pull_format(&self) -> Option<LazyPullFormat> {
+        self.images.iter().find_map(|image| image.lazy_pull_format())
+    }
+
+    fn layer_lazy_pull_format(&self, digest: &Digest) -> Option<LazyPullFormat> {
+        self.images
+            .iter()
+            .find_map(|image| image.layer_lazy_pull_format(digest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::rootfs::{RootfsConfig, RootfsImage, RootfsSource};
+    use crate::layer_combiner::LayerCombiner;
+    use crate::location::parse_platform;
+    use crate::test_utils::read_tar_entries_content;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn directory_image(files: &[(&str, &str)], env: &[&str], architecture: &str) -> (TempDir, BoxedImage) {
+        let root = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            std::fs::write(root.path().join(path), content).unwrap();
+        }
+        let config = RootfsConfig {
+            architecture: Some(architecture.to_string()),
+            env: env.iter().map(|v| v.to_string()).collect(),
+            ..Default::default()
+        };
+        let image = RootfsImage::new(RootfsSource::Directory(root.path().to_path_buf()), &config).unwrap();
+        (root, Box::new(image))
+    }
+
+    #[test]
+    fn test_merge_env() {
+        let base = ["PATH=/usr/bin:/bin", "LANG=C"].map(String::from);
+        let runtime = ["PATH=/opt/python/bin:/usr/bin", "PYTHONHOME=/opt/python"].map(String::from);
+        let agent = ["LANG=C.UTF-8", "PATH=/opt/agent/bin"].map(String::from);
+        assert_eq!(
+            merge_env([&base[..], &runtime[..], &agent[..]]),
+            [
+                "PATH=/opt/agent/bin:/opt/python/bin:/usr/bin:/bin",
+                "LANG=C.UTF-8",
+                "PYTHONHOME=/opt/python"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stack() {
+        let (_base_dir, base) = directory_image(&[("a.txt", "base")], &[], "amd64");
+        let (_top_dir, top) = directory_image(
+            &[("b.txt", "top"), (".wh.a.txt", "")],
+            &["PATH=/opt/bin", "LANG=C.UTF-8"],
+            "amd64",
+        );
+        let (_other_dir, other) = directory_image(&[("c.txt", "other")], &[], "arm64");
+        let error = OverlayImage::stack(vec![base], vec![("other".to_string(), vec![other])], 0).unwrap_err();
+        assert_eq!(error.to_string(), "other has no image for platform linux/amd64");
+
+        let (_base_dir, base) = directory_image(&[("a.txt", "base"), ("b.txt", "base")], &["LANG=C"], "amd64");
+        let images = OverlayImage::stack(vec![base], vec![("top".to_string(), vec![top])], 1).unwrap();
+        assert_eq!(images.len(), 1);
+        let image = &images[0];
+        let env = InputImage::config(image)
+            .config()
+            .as_ref()
+            .unwrap()
+            .env()
+            .clone()
+            .unwrap();
+        assert_eq!(env, ["LANG=C.UTF-8", "PATH=/opt/bin"]);
+
+        let mut output = vec![];
+        let mut combiner = LayerCombiner::new(&mut output);
+        let layers = image.layers_from_manifest().unwrap();
+        assert_eq!(layers.len(), 2);
+        for layer in layers {
+            let mut layer = layer.unwrap();
+            combiner.merge_entries(layer.entries().unwrap()).unwrap();
+        }
+        combiner.finish().unwrap();
+        let entries = read_tar_entries_content(&output);
+        assert_eq!(entries[Path::new("b.txt")], b"top");
+        assert!(!entries.contains_key(Path::new("a.txt")));
+    }
+
+    #[test]
+    fn test_with_platform() {
+        let (_dir, image) = directory_image(&[("a.txt", "a")], &["PATH=/bin"], "amd64");
+        let image = OverlayImage::new(vec![image], 0).unwrap();
+        let digest = InputImage::image_digest(&image);
+        let image = image.with_platform(&parse_platform("linux/arm64/v8").unwrap()).unwrap();
+        assert_eq!(InputImage::platform(&image).to_string(), "linux/arm64/v8");
+        assert_ne!(InputImage::image_digest(&image), digest);
+        assert_eq!(image.layers_from_manifest().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::anyhow;
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::debug;
+
+pub const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 4;
+
+/// Limits how many downloads run at once across every image being read
+#[derive(Debug)]
+pub struct DownloadLimiter {
+    limit: usize,
+    running: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl DownloadLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            running: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Blocks until a download can start. The download ends when the permit is dropped.
+    pub fn acquire(&self) -> DownloadPermit<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit {
+            running = self.changed.wait(running).unwrap();
+        }
+        *running += 1;
+        DownloadPermit { limiter: self }
+    }
+}
+
+impl Default for DownloadLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_DOWNLOAD_CONCURRENCY)
+    }
+}
+
+pub struct DownloadPermit<'a> {
+    limiter: &'a DownloadLimiter,
+}
+
+impl Drop for DownloadPermit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.running.lock().unwrap() -= 1;
+        self.limiter.changed.notify_one();
+    }
+}
+
+struct PrefetchState<T> {
+    results: Vec<Option<anyhow::Result<T>>>,
+    /// The index of the next item to fetch
+    next: usize,
+    /// The number of items taken by the consumer
+    taken: usize,
+    cancelled: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<PrefetchState<T>>,
+    changed: Condvar,
+}
+
+/// Fetches items on background threads ahead of a consumer that takes them in order. At most `limiter.limit()`
+/// items are fetched ahead of the consumer, so the items waiting to be taken don't pile up.
+pub struct Prefetcher<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Send + 'static> Prefetcher<T> {
+    pub fn spawn<I: Send + 'static>(
+        name: &str,
+        items: Vec<I>,
+        limiter: Arc<DownloadLimiter>,
+        fetch: impl Fn(I) -> anyhow::Result<T> + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        let total = items.len();
+        let window = limiter.limit();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PrefetchState {
+                results: (0..total).map(|_| None).collect(),
+                next: 0,
+                taken: 0,
+                cancelled: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let items = Arc::new(Mutex::new(items.into_iter().map(Some).collect::<Vec<_>>()));
+        let fetch = Arc::new(fetch);
+        for worker in 0..window.min(total) {
+            let shared = shared.clone();
+            let items = items.clone();
+            let limiter = limiter.clone();
+            let fetch = fetch.clone();
+            std::thread::Builder::new()
+                .name(format!("{name}-{worker}"))
+                .spawn(move || loop {
+                    let index = {
+                        let mut state = shared.state.lock().unwrap();
+                        while !state.cancelled && state.next < total && state.next >= state.taken + window {
+                            state = shared.changed.wait(state).unwrap();
+                        }
+                        if state.cancelled || state.next == total {
+                            return;
+                        }
+                        state.next += 1;
+                        state.next - 1
+                    };
+                    let item = items.lock().unwrap()[index].take().unwrap();
+                    let result = {
+                        let _permit = limiter.acquire();
+                        debug!("Prefetching item {index}");
+                        fetch(item)
+                    };
+                    shared.state.lock().unwrap().results[index] = Some(result);
+                    shared.changed.notify_all();
+                })?;
+        }
+        Ok(Self { shared })
+    }
+
+    /// Waits for the item at `index` to be fetched. Items must be taken in order.
+    pub fn take(&self, index: usize) -> anyhow::Result<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.results.get_mut(index).and_then(Option::take) {
+                state.taken = index + 1;
+                self.shared.changed.notify_all();
+                return result;
+            }
+            if index >= state.results.len() {
+                return Err(anyhow!("No item {index} to prefetch"));
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl<T> Drop for Prefetcher<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().cancelled = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_prefetch_in_order() {
+        let limiter = Arc::new(DownloadLimiter::new(3));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (fetch_running, fetch_max_running) = (running.clone(), max_running.clone());
+        let prefetcher = Prefetcher::spawn("test", (0..10u64).collect(), limiter, move |item| {
+            let now = fetch_running.fetch_add(1, Ordering::SeqCst) + 1;
+            fetch_max_running.fetch_max(now, Ordering::SeqCst);
+            // Later items finish first
+            std::thread::sleep(Duration::from_millis(30 - item * 3));
+            fetch_running.fetch_sub(1, Ordering::SeqCst);
+            if item == 7 {
+                anyhow::bail!("Failed to fetch {item}");
+            }
+            Ok(item * 2)
+        })
+        .unwrap();
+
+        for index in 0..7 {
+            assert_eq!(prefetcher.take(index).unwrap(), index as u64 * 2);
+        }
+        assert_eq!(prefetcher.take(7).unwrap_err().to_string(), "Failed to fetch 7");
+        assert_eq!(prefetcher.take(8).unwrap(), 16);
+        assert!(max_running.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn test_shared_limit() {
+        let limiter = Arc::new(DownloadLimiter::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let prefetchers = (0..3)
+            .map(|_| {
+                let (running, max_running) = (running.clone(), max_running.clone());
+                Prefetcher::spawn("test", vec![(); 4], limiter.clone(), move |_| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for prefetcher in &prefetchers {
+            for index in 0..4 {
+                prefetcher.take(index).unwrap();
+            }
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+}
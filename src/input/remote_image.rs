@@ -1,69 +1,260 @@
+use crate::compression::Compression;
+use crate::input::blob_cache::{content_digest, ensure_online, BlobCache, CachingReader};
+use crate::input::foreign_layers::{is_foreign, open_urls, split_foreign_layers, ForeignLayer, ForeignLayerPolicy};
 use crate::input::layers::InputLayer;
+use crate::input::lazy_pull::LazyPullFormat;
+use crate::input::prefetch::{DownloadLimiter, Prefetcher};
 use crate::input::{get_layer_media_type, InputImage};
+use crate::io_utils::VerifyingReader;
+use crate::output_image::image::hash_reader;
 use crate::platform_matcher::PlatformMatcher;
 use crate::progress;
-use anyhow::Context;
-use docker_credential::{CredentialRetrievalError, DockerCredential};
+use crate::registry::auth::Credentials;
+use crate::registry::blob_reader::{BlobReader, DEFAULT_BLOB_RETRIES};
+use crate::registry::config::RegistryConfig;
+use crate::registry::credentials::{find_credentials, RegistryRole};
+use crate::registry::mirrors::MirrorRules;
+use crate::registry::{pull_scope, RegistryClient};
+use anyhow::{bail, Context};
+use futures_util::{StreamExt, TryStreamExt};
 use itertools::Itertools;
-use oci_client::manifest::{OciImageManifest, OciManifest, IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
-use oci_client::secrets::RegistryAuth;
-use oci_client::{Client, Reference};
-use oci_spec::image::{Digest, ImageConfiguration, MediaType};
+use oci_client::manifest::{
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+};
+use oci_client::Reference;
+use oci_spec::image::{
+    Config, Digest, History, HistoryBuilder, ImageConfiguration, ImageConfigurationBuilder, ImageIndex, ImageManifest,
+    MediaType, RootFsBuilder,
+};
+use serde::Deserialize;
 use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::Read;
+use std::io::{BufReader, Read, Seek};
+use std::path::PathBuf;
 use std::str::FromStr;
-use tokio::io::BufReader;
+use std::sync::Arc;
 use tokio::runtime::Handle;
-use tokio_util::io::SyncIoBridge;
-use tracing::{debug, instrument, trace, warn};
-
-#[instrument(skip_all, fields(reference = %reference))]
-fn build_auth(reference: &Reference) -> RegistryAuth {
-    let server = reference
-        .resolve_registry()
-        .strip_suffix('/')
-        .unwrap_or_else(|| reference.resolve_registry());
-
-    let auth_results = [
-        ("docker", docker_credential::get_credential(server)),
-        ("podman", docker_credential::get_podman_credential(server)),
-    ];
-
-    for (name, cred_result) in auth_results.into_iter() {
-        match cred_result {
-            Err(e) => match e {
-                CredentialRetrievalError::HelperFailure { stdout, stderr } => {
-                    let base_message =
-                        "Credential helper returned non-zero response code, falling back to anonymous auth";
-                    if !stderr.is_empty() || !stdout.is_empty() {
-                        let extra = [stdout.trim(), stderr.trim()].join(" - ");
-                        warn!("{name}: {base_message}: stdout/stderr = {extra}");
-                    } else {
-                        warn!("{name}: {base_message}");
-                    };
-                }
-                e => {
-                    debug!("{name}: {e}");
-                }
-            },
-            Ok(DockerCredential::UsernamePassword(username, password)) => {
-                debug!("{name}: Found docker credentials");
-                return RegistryAuth::Basic(username, password);
+use tracing::{debug, info, instrument, trace, warn};
+
+const SCHEMA1_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v1+json";
+const SCHEMA1_SIGNED_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v1+prettyjws";
+const MANIFEST_MEDIA_TYPES: [&str; 6] = [
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    OCI_IMAGE_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    SCHEMA1_SIGNED_MEDIA_TYPE,
+    SCHEMA1_MEDIA_TYPE,
+];
+pub const DEFAULT_MANIFEST_CONCURRENCY: usize = 8;
+/// Indexes are followed this many levels deep before we give up, to avoid loops between indexes
+const MAX_INDEX_DEPTH: usize = 8;
+
+enum RemoteManifest {
+    Image(Box<ImageManifest>),
+    Index(Box<ImageIndex>),
+    Schema1(Schema1Manifest),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestHeader {
+    schema_version: u32,
+    media_type: Option<String>,
+    manifests: Option<serde::de::IgnoredAny>,
+}
+
+impl RemoteManifest {
+    /// Older manifests have no `mediaType` field and registries don't always send a content type, so the shape of
+    /// the document decides when neither says what it is.
+    fn parse(content: &[u8], content_type: Option<&str>) -> anyhow::Result<Self> {
+        let header: ManifestHeader = serde_json::from_slice(content).context("Parse manifest")?;
+        if header.schema_version == 1 {
+            let manifest = serde_json::from_slice(content).context("Parse schema1 manifest")?;
+            return Ok(Self::Schema1(manifest));
+        }
+        let is_index = match header.media_type.as_deref().or(content_type) {
+            Some(OCI_IMAGE_INDEX_MEDIA_TYPE | IMAGE_MANIFEST_LIST_MEDIA_TYPE) => true,
+            Some(OCI_IMAGE_MEDIA_TYPE | IMAGE_MANIFEST_MEDIA_TYPE) => false,
+            _ => header.manifests.is_some(),
+        };
+        if is_index {
+            Ok(Self::Index(Box::new(
+                ImageIndex::from_reader(content).context("Parse ImageIndex")?,
+            )))
+        } else {
+            Ok(Self::Image(Box::new(
+                ImageManifest::from_reader(content).context("Parse ImageManifest")?,
+            )))
+        }
+    }
+}
+
+/// A Docker schema1 manifest. Layers and history are listed from the top of the image down, and the image
+/// configuration is spread over the `v1Compatibility` JSON documents in the history.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Schema1Manifest {
+    architecture: Option<String>,
+    fs_layers: Vec<Schema1Layer>,
+    history: Vec<Schema1History>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Schema1Layer {
+    blob_sum: Digest,
+}
+
+#[derive(Debug, Deserialize)]
+struct Schema1History {
+    #[serde(rename = "v1Compatibility")]
+    v1_compatibility: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct V1Compatibility {
+    created: Option<String>,
+    author: Option<String>,
+    comment: Option<String>,
+    /// Set on entries that did not change the filesystem, whose blob is an empty tar
+    #[serde(default)]
+    throwaway: bool,
+    container_config: Option<V1ContainerConfig>,
+    config: Option<Config>,
+    os: Option<String>,
+    architecture: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct V1ContainerConfig {
+    #[serde(rename = "Cmd")]
+    cmd: Option<Vec<String>>,
+}
+
+impl Schema1Manifest {
+    /// Synthesizes the configuration and layers of an equivalent schema2 image. The config has no diff IDs, as
+    /// those are only known once the layers are decompressed.
+    fn convert(self) -> anyhow::Result<(ImageConfiguration, Vec<(MediaType, Digest)>)> {
+        if self.fs_layers.len() != self.history.len() {
+            bail!(
+                "Manifest has {} layers but {} history entries",
+                self.fs_layers.len(),
+                self.history.len()
+            );
+        }
+        let mut entries = self
+            .history
+            .iter()
+            .map(|entry| serde_json::from_str::<V1Compatibility>(&entry.v1_compatibility))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Parse v1Compatibility")?;
+
+        let mut history: Vec<History> = vec![];
+        let mut layers = vec![];
+        for (layer, entry) in self.fs_layers.into_iter().zip(&mut entries).rev() {
+            let mut builder = HistoryBuilder::default().empty_layer(entry.throwaway);
+            if let Some(created) = entry.created.clone() {
+                builder = builder.created(created);
             }
-            Ok(DockerCredential::IdentityToken(_)) => {
-                warn!("{name}: Cannot use contents of docker config, identity token not supported.");
+            if let Some(author) = entry.author.take() {
+                builder = builder.author(author);
             }
-        };
+            if let Some(comment) = entry.comment.take() {
+                builder = builder.comment(comment);
+            }
+            if let Some(cmd) = entry.container_config.take().and_then(|config| config.cmd) {
+                builder = builder.created_by(cmd.join(" "));
+            }
+            history.push(builder.build().context("HistoryBuilder Build")?);
+            if !entry.throwaway {
+                layers.push((MediaType::ImageLayerGzip, layer.blob_sum));
+            }
+        }
+
+        let top = entries.into_iter().next().context("Manifest has no layers")?;
+        let architecture = top.architecture.or(self.architecture).unwrap_or_default();
+        let rootfs = RootFsBuilder::default()
+            .typ("layers")
+            .diff_ids(vec![])
+            .build()
+            .context("RootFsBuilder Build")?;
+        let mut builder = ImageConfigurationBuilder::default()
+            .architecture(architecture.as_str())
+            .os(top.os.as_deref().unwrap_or("linux"))
+            .rootfs(rootfs)
+            .history(history);
+        if let Some(config) = top.config {
+            builder = builder.config(config);
+        }
+        if let Some(created) = top.created {
+            builder = builder.created(created);
+        }
+        let image_config = builder.build().context("ImageConfigurationBuilder Build")?;
+        Ok((image_config, layers))
+    }
+}
+
+/// Settings for reading images from registries
+#[derive(Debug, Clone)]
+pub struct RemoteOptions {
+    /// Blobs are read from and added to this cache
+    pub cache: Option<Arc<BlobCache>>,
+    /// How many times a failed layer download is resumed before giving up
+    pub max_retries: usize,
+    /// Shared by every image, to limit how many layers are downloaded at once
+    pub downloads: Arc<DownloadLimiter>,
+    /// Layers are downloaded into this directory when there is no cache
+    pub spool_dir: PathBuf,
+    /// How many manifests of an index are fetched at once
+    pub manifest_concurrency: usize,
+    /// Credentials given on the command line, used instead of looking them up
+    pub credentials: Option<Credentials>,
+    /// How to connect to the registry
+    pub registry_config: RegistryConfig,
+    /// Rules for pulling images through mirrors
+    pub mirrors: MirrorRules,
+    /// What to do with foreign and non-distributable layers
+    pub foreign_layers: ForeignLayerPolicy,
+}
+
+impl Default for RemoteOptions {
+    fn default() -> Self {
+        Self {
+            cache: None,
+            max_retries: DEFAULT_BLOB_RETRIES,
+            downloads: Arc::default(),
+            spool_dir: std::env::temp_dir(),
+            manifest_concurrency: DEFAULT_MANIFEST_CONCURRENCY,
+            credentials: None,
+            registry_config: RegistryConfig::default(),
+            mirrors: MirrorRules::default(),
+            foreign_layers: ForeignLayerPolicy::default(),
+        }
+    }
+}
+
+impl RemoteOptions {
+    fn is_offline(&self) -> bool {
+        self.cache.as_ref().is_some_and(|cache| cache.is_offline())
     }
-    debug!("No credentials found, using anonymous auth");
-    RegistryAuth::Anonymous
 }
 
 pub struct RemoteImage {
-    client: Client,
+    client: Arc<RegistryClient>,
+    options: RemoteOptions,
     reference: Reference,
+    manifest_digest: Digest,
     layers: Vec<(MediaType, Digest)>,
+    /// The sizes of the layer blobs, when the manifest declares them
+    layer_sizes: Vec<(Digest, u64)>,
+    /// Foreign layers kept as they are, which are not in `layers`
+    foreign_layers: Vec<ForeignLayer>,
+    /// Where foreign layers that are read are downloaded from
+    foreign_urls: Vec<(Digest, Vec<String>)>,
+    /// The layers that are in a lazy-pull format, and which format
+    lazy_pull_layers: Vec<(Digest, LazyPullFormat)>,
     config_digest: Digest,
     image_config: ImageConfiguration,
     handle: Handle,
@@ -107,116 +298,416 @@ impl Display for RemoteImage {
 }
 
 impl RemoteImage {
+    fn layer_fetcher(&self) -> LayerFetcher {
+        LayerFetcher {
+            client: self.client.clone(),
+            repository: self.reference.repository().to_string(),
+            options: self.options.clone(),
+            foreign_urls: self.foreign_urls.clone(),
+            handle: self.handle.clone(),
+        }
+    }
+
     #[instrument(name = "load_images", skip_all, fields(image = %reference))]
     pub fn create_remote_images(
         handle: &Handle,
         reference: Reference,
         platform: &PlatformMatcher,
+        options: RemoteOptions,
     ) -> anyhow::Result<Vec<Self>> {
-        handle.block_on(Self::from_list_async(reference, platform))
+        let candidates = options.mirrors.candidates(&reference)?;
+        let mut last_error = None;
+        for candidate in candidates {
+            let mut options = options.clone();
+            if candidate.insecure {
+                let registry = candidate.reference.resolve_registry().to_string();
+                options.registry_config.insecure.push(registry);
+            }
+            if candidate.reference != reference {
+                info!("Pulling {reference} from {}", candidate.reference);
+            }
+            match handle.block_on(Self::from_list_async(candidate.reference.clone(), platform, options)) {
+                Ok(images) => return Ok(images),
+                Err(e) => {
+                    warn!("Pulling {} failed: {e:#}", candidate.reference);
+                    last_error = Some(e);
+                }
+            }
+        }
+        // The upstream registry is tried last, so its error is the most relevant
+        Err(last_error.context("No registries to pull from")?)
     }
 
-    async fn from_list_async(reference: Reference, platform_matcher: &PlatformMatcher) -> anyhow::Result<Vec<Self>> {
-        let auth = build_auth(&reference);
-        let client = Client::new(Default::default());
-        debug!("Fetching manifest list for {}", reference);
-        let (manifest_content, _) = client
-            .pull_manifest(&reference, &auth)
-            .await
-            .context("Fetch manifest list")?;
-        match manifest_content {
-            OciManifest::Image(image) => {
+    async fn from_list_async(
+        reference: Reference,
+        platform_matcher: &PlatformMatcher,
+        options: RemoteOptions,
+    ) -> anyhow::Result<Vec<Self>> {
+        let auth = if options.is_offline() {
+            Credentials::Anonymous
+        } else {
+            find_credentials(&reference, RegistryRole::Source, options.credentials.as_ref())
+        };
+        let client = RegistryClient::new(
+            &reference,
+            auth,
+            vec![pull_scope(reference.repository())],
+            &options.registry_config,
+        )?;
+        let client = Arc::new(client);
+        let images = Self::from_reference(client.clone(), options, reference, platform_matcher, 0).await?;
+        debug!("Found {} images", images.len());
+        if let Some(rate_limit) = client.rate_limit() {
+            info!(
+                "{} of {} registry requests remaining",
+                rate_limit.remaining,
+                rate_limit
+                    .limit
+                    .map(|limit| limit.to_string())
+                    .unwrap_or("?".to_string())
+            );
+        }
+        Ok(images)
+    }
+
+    async fn from_reference(
+        client: Arc<RegistryClient>,
+        options: RemoteOptions,
+        reference: Reference,
+        platform_matcher: &PlatformMatcher,
+        depth: usize,
+    ) -> anyhow::Result<Vec<Self>> {
+        if depth > MAX_INDEX_DEPTH {
+            bail!("Image indexes are nested more than {MAX_INDEX_DEPTH} levels deep at {reference}");
+        }
+        let (manifest, manifest_digest) = fetch_manifest(&client, options.cache.as_ref(), &reference).await?;
+        match manifest {
+            RemoteManifest::Image(manifest) => {
                 debug!("Found single image manifest");
-                let img = Self::from_image_manifest(reference, image, client).await?;
+                let img = Self::from_image_manifest(client, options, reference, manifest_digest, *manifest)
+                    .await
+                    .context("from_image_manifest")?;
+                Ok(vec![img])
+            }
+            RemoteManifest::Schema1(manifest) => {
+                debug!("Found schema1 manifest");
+                let img = Self::from_schema1_manifest(client, options, reference, manifest_digest, manifest)
+                    .context("from_schema1_manifest")?;
                 Ok(vec![img])
             }
-            OciManifest::ImageIndex(index) => {
+            RemoteManifest::Index(index) => {
                 debug!("Found image index");
-                let iterator = progress::progress_iter("Reading Manifests", index.manifests.into_iter());
-                let manifests = iterator
-                    .filter(|entry| platform_matcher.matches_oci_client_platform(entry.platform.as_ref()))
-                    .filter_map(|entry| {
-                        let media_type = entry.media_type.as_str();
+                let entries = index
+                    .manifests()
+                    .iter()
+                    .filter(|entry| platform_matcher.matches_oci_spec_platform(entry.platform().as_ref()))
+                    .filter(|entry| {
+                        let media_type = entry.media_type().to_string();
                         trace!("Checking entry media type ({media_type}) {:?}", entry);
-                        match media_type {
-                            OCI_IMAGE_MEDIA_TYPE | IMAGE_MANIFEST_MEDIA_TYPE => {
-                                trace!("Found image manifest");
-                                Some(reference.clone_with_digest(entry.digest))
-                            }
-                            _ => {
-                                trace!("Skipped");
-                                None
-                            }
+                        let supported = matches!(
+                            media_type.as_str(),
+                            OCI_IMAGE_MEDIA_TYPE
+                                | IMAGE_MANIFEST_MEDIA_TYPE
+                                | OCI_IMAGE_INDEX_MEDIA_TYPE
+                                | IMAGE_MANIFEST_LIST_MEDIA_TYPE
+                        );
+                        if !supported {
+                            trace!("Skipped");
                         }
-                    });
-                let mut images = vec![];
-                for manifest in manifests {
-                    {
-                        let img = Self::from_image_reference(manifest, client.clone(), auth.clone()).await?;
-                        images.push(img);
-                        // Super hacky, but we need to sleep here to avoid rate limiting.
-                        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-                    }
-                }
-                debug!("Found {} images", images.len());
-                Ok(images)
+                        supported
+                    })
+                    .collect_vec();
+                let concurrency = options.manifest_concurrency.max(1);
+                let images: Vec<Vec<Self>> =
+                    futures_util::stream::iter(progress::progress_iter("Reading Manifests", entries.into_iter()))
+                        .map(|entry| {
+                            let entry_reference = reference.clone_with_digest(entry.digest().to_string());
+                            let nested = Box::pin(Self::from_reference(
+                                client.clone(),
+                                options.clone(),
+                                entry_reference,
+                                platform_matcher,
+                                depth + 1,
+                            ));
+                            async move {
+                                nested
+                                    .await
+                                    .with_context(|| format!("Reading manifest {}", entry.digest()))
+                            }
+                        })
+                        .buffered(concurrency)
+                        .try_collect()
+                        .await?;
+                Ok(images.into_iter().flatten().collect())
             }
         }
     }
 
-    async fn from_image_reference(reference: Reference, client: Client, auth: RegistryAuth) -> anyhow::Result<Self> {
-        debug!("Fetching manifest for {}", reference);
-        let (manifest_content, _) = client
-            .pull_manifest_raw(&reference, &auth, &[OCI_IMAGE_MEDIA_TYPE])
-            .await
-            .with_context(|| format!("Fetching manifest {reference}"))?;
-        let manifest: OciImageManifest = serde_json::from_slice(&manifest_content).context("Parse ImageManifest")?;
-        trace!("Manifest parsed for {}: {:#?}", reference, manifest);
-        Self::from_image_manifest(reference, manifest, client)
-            .await
-            .context("from_image_manifest")
-    }
-
     async fn from_image_manifest(
+        client: Arc<RegistryClient>,
+        options: RemoteOptions,
         reference: Reference,
-        manifest: OciImageManifest,
-        client: Client,
+        manifest_digest: Digest,
+        manifest: ImageManifest,
     ) -> anyhow::Result<Self> {
-        let mut config_data = vec![];
-        let config_digest = Digest::from_str(&manifest.config.digest)?;
-        debug!("Fetching config for {}", config_digest);
-        client
-            .pull_blob(&reference, &manifest.config, &mut config_data)
-            .await
-            .with_context(|| format!("Fetch config {}", manifest.config))?;
+        let config_digest = manifest.config().digest().clone();
+        let config_size = Some(manifest.config().size());
+        let cached = match &options.cache {
+            Some(cache) => cache.read(&config_digest, config_size)?,
+            None => None,
+        };
+        let config_data = match cached {
+            Some(config_data) => config_data,
+            None => {
+                ensure_online(options.cache.as_deref(), format_args!("Config {config_digest}"))?;
+                debug!("Fetching config for {}", config_digest);
+                let response = client.get_blob(reference.repository(), &config_digest).await?;
+                let content = response
+                    .bytes()
+                    .await
+                    .with_context(|| format!("Fetch config {config_digest}"))?;
+                let mut config_data = vec![];
+                VerifyingReader::new("Config", &content[..], config_digest.clone(), config_size)?
+                    .read_to_end(&mut config_data)?;
+                if let Some(cache) = &options.cache {
+                    cache.insert(&config_digest, &config_data)?;
+                }
+                config_data
+            }
+        };
         let image_config = ImageConfiguration::from_reader(&config_data[..]).context("Parse ImageConfiguration")?;
 
-        let layers = manifest
-            .layers
-            .into_iter()
+        let (read_layers, foreign_layers) =
+            split_foreign_layers(manifest.layers(), &image_config, options.foreign_layers, &reference)?;
+        let layers = read_layers
+            .iter()
             .map(|v| {
-                let media_type = v.media_type.as_str();
-                if let Some(parsed_media_type) = get_layer_media_type(media_type) {
-                    trace!("Found layer descriptor: {:?}", v);
-                    let digest = Digest::from_str(&v.digest)?;
-                    Ok(Some((parsed_media_type, digest)))
-                } else {
-                    trace!("Skipping descriptor: {:?}", v);
-                    Ok(None)
-                }
+                trace!("Found layer descriptor: {:?}", v);
+                // Unknown media types are kept, as the layer's compression is detected from its content
+                let media_type =
+                    get_layer_media_type(v.media_type().as_ref()).unwrap_or_else(|| v.media_type().clone());
+                (media_type, v.digest().clone())
             })
-            .filter_map_ok(|r| r)
-            .collect::<anyhow::Result<Vec<_>>>();
+            .collect();
+        // Foreign layers that are fetched come from the URLs in their descriptor rather than the registry
+        let foreign_urls = read_layers
+            .iter()
+            .filter(|v| is_foreign(v))
+            .filter_map(|v| Some((v.digest().clone(), v.urls().clone().filter(|urls| !urls.is_empty())?)))
+            .collect();
+        let layer_sizes = manifest
+            .layers()
+            .iter()
+            .map(|v| (v.digest().clone(), v.size()))
+            .collect();
         let handle = Handle::current();
         Ok(Self {
             client,
+            options,
             reference,
-            layers: layers?,
+            manifest_digest,
+            layers,
+            layer_sizes,
+            foreign_layers,
+            foreign_urls,
+            lazy_pull_layers: LazyPullFormat::of_layers(&read_layers),
             image_config,
             handle,
             config_digest,
         })
     }
+
+    fn from_schema1_manifest(
+        client: Arc<RegistryClient>,
+        options: RemoteOptions,
+        reference: Reference,
+        manifest_digest: Digest,
+        manifest: Schema1Manifest,
+    ) -> anyhow::Result<Self> {
+        let (image_config, layers) = manifest.convert()?;
+        let (_, config_digest) = hash_reader(image_config.to_string()?.as_bytes())?;
+        let handle = Handle::current();
+        Ok(Self {
+            client,
+            options,
+            reference,
+            manifest_digest,
+            layers,
+            layer_sizes: vec![],
+            foreign_layers: vec![],
+            foreign_urls: vec![],
+            lazy_pull_layers: vec![],
+            image_config,
+            handle,
+            config_digest: config_digest.into(),
+        })
+    }
+}
+
+async fn fetch_manifest(
+    client: &RegistryClient,
+    cache: Option<&Arc<BlobCache>>,
+    reference: &Reference,
+) -> anyhow::Result<(RemoteManifest, Digest)> {
+    if let Some(cache) = cache.filter(|cache| cache.is_offline()) {
+        debug!("Reading manifest for {} from the cache", reference);
+        let cached = match cache.resolve_reference(reference)? {
+            Some(digest) => cache.read(&digest, None)?.map(|content| (content, digest)),
+            None => None,
+        };
+        let (content, digest) = cached.with_context(|| {
+            format!("Manifest {reference} is not in the cache, and cannot be fetched in offline mode")
+        })?;
+        let manifest =
+            RemoteManifest::parse(&content, None).with_context(|| format!("Parsing manifest {reference}"))?;
+        return Ok((manifest, digest));
+    }
+
+    debug!("Fetching manifest for {}", reference);
+    let tag_or_digest = reference.digest().or(reference.tag()).unwrap_or("latest");
+    let (content, content_type) = client
+        .get_manifest(reference.repository(), tag_or_digest, &MANIFEST_MEDIA_TYPES)
+        .await?;
+    let manifest = RemoteManifest::parse(&content, content_type.as_deref())
+        .with_context(|| format!("Parsing manifest {reference}"))?;
+    let digest = content_digest(&content)?;
+    if let Some(cache) = cache {
+        // Manifests are cached by the digest of their content, so that offline runs can find them from the tag
+        cache.insert(&digest, &content)?;
+        cache.set_reference(reference, &digest)?;
+    }
+    // Signed schema1 manifests are addressed by the digest of their content without the signatures
+    if let Some(expected) = reference.digest().filter(|digest| digest.starts_with("sha256:")) {
+        if !matches!(manifest, RemoteManifest::Schema1(_)) && digest.to_string() != expected {
+            bail!("Manifest {reference} has digest {digest}");
+        }
+    }
+    // Schema1 manifests are known by the digest they were referenced with, if any
+    let digest = match reference.digest() {
+        Some(expected) => Digest::from_str(expected).with_context(|| format!("Invalid digest in {reference}"))?,
+        None => digest,
+    };
+    Ok((manifest, digest))
+}
+
+/// A layer downloaded ahead of being read
+enum PrefetchedLayer {
+    /// The layer is in the blob cache
+    Cached,
+    /// The layer was downloaded into an anonymous temporary file
+    Spooled(File),
+}
+
+/// Everything needed to download layers of an image from a background thread
+struct LayerFetcher {
+    client: Arc<RegistryClient>,
+    repository: String,
+    options: RemoteOptions,
+    foreign_urls: Vec<(Digest, Vec<String>)>,
+    handle: Handle,
+}
+
+impl LayerFetcher {
+    fn open_cached(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<Option<Box<dyn Read>>> {
+        let Some(cache) = &self.options.cache else {
+            return Ok(None);
+        };
+        Ok(cache
+            .open(digest, size)?
+            .map(|reader| -> Box<dyn Read> { Box::new(reader) }))
+    }
+
+    /// Reads the layer straight from the registry, adding it to the cache as it is read
+    fn stream(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<Box<dyn Read>> {
+        ensure_online(self.options.cache.as_deref(), format_args!("Layer {digest}"))?;
+        debug!("Fetching blob stream for {}", digest);
+        let reader: Box<dyn Read> = match self.urls(digest) {
+            Some(urls) => {
+                let reader = open_urls(urls, &self.options.registry_config, &self.handle)?;
+                Box::new(VerifyingReader::new("Foreign layer", reader, digest.clone(), size)?)
+            }
+            None => Box::new(self.verified_blob(digest, size)?),
+        };
+        Ok(match &self.options.cache {
+            Some(cache) => Box::new(CachingReader::new(reader, cache.writer(digest)?)),
+            None => Box::new(reader),
+        })
+    }
+
+    /// Downloads the layer into the cache, or into the spool directory if there is no cache
+    fn prefetch(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<PrefetchedLayer> {
+        if let Some(cache) = &self.options.cache {
+            if cache.contains(digest) {
+                // A corrupt blob is removed when it is checked, so it is fetched again rather than failing every run
+                if cache.verify(digest, size)? {
+                    return Ok(PrefetchedLayer::Cached);
+                }
+                if cache.is_offline() {
+                    bail!("Layer {digest} in the cache is corrupt, and cannot be fetched again in offline mode");
+                }
+            }
+        }
+        ensure_online(self.options.cache.as_deref(), format_args!("Layer {digest}"))?;
+        let Some(urls) = self.urls(digest) else {
+            debug!("Prefetching layer {}", digest);
+            return self.download(digest, self.verified_blob(digest, size)?);
+        };
+        // A URL may serve the wrong content, so the next one is tried if the digest does not match
+        let mut last_error = None;
+        for url in urls {
+            debug!("Prefetching foreign layer {} from {}", digest, url);
+            let result = open_urls(std::slice::from_ref(url), &self.options.registry_config, &self.handle)
+                .and_then(|reader| VerifyingReader::new("Foreign layer", reader, digest.clone(), size))
+                .and_then(|reader| self.download(digest, reader));
+            match result {
+                Ok(layer) => return Ok(layer),
+                Err(e) => {
+                    warn!("Fetching foreign layer {digest} from {url} failed: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.context("The foreign layer has no URLs")?)
+    }
+
+    fn urls(&self, digest: &Digest) -> Option<&[String]> {
+        self.foreign_urls
+            .iter()
+            .find_map(|(layer, urls)| (layer == digest).then_some(urls.as_slice()))
+    }
+
+    /// Copies a layer into the cache, or into the spool directory if there is no cache
+    fn download(&self, digest: &Digest, mut reader: impl Read) -> anyhow::Result<PrefetchedLayer> {
+        match &self.options.cache {
+            Some(cache) => {
+                let mut writer = cache.writer(digest)?;
+                std::io::copy(&mut reader, &mut writer).with_context(|| format!("Downloading layer {digest}"))?;
+                writer.commit()?;
+                Ok(PrefetchedLayer::Cached)
+            }
+            None => {
+                let spool_dir = &self.options.spool_dir;
+                std::fs::create_dir_all(spool_dir).with_context(|| format!("Creating {}", spool_dir.display()))?;
+                let mut file = tempfile::tempfile_in(spool_dir)
+                    .with_context(|| format!("Creating a file in {}", spool_dir.display()))?;
+                std::io::copy(&mut reader, &mut file).with_context(|| format!("Downloading layer {digest}"))?;
+                file.rewind()?;
+                Ok(PrefetchedLayer::Spooled(file))
+            }
+        }
+    }
+
+    fn verified_blob(&self, digest: &Digest, size: Option<u64>) -> anyhow::Result<VerifyingReader<BlobReader>> {
+        let blob = BlobReader::new(
+            self.client.clone(),
+            &self.repository,
+            digest.clone(),
+            size,
+            self.handle.clone(),
+            self.options.max_retries,
+        );
+        VerifyingReader::new("Layer", blob, digest.clone(), size)
+    }
 }
 
 impl InputImage for RemoteImage {
@@ -227,19 +718,36 @@ impl InputImage for RemoteImage {
     fn layers_from_manifest(
         &self,
     ) -> anyhow::Result<impl ExactSizeIterator<Item = anyhow::Result<InputLayer<impl Read>>>> {
-        Ok(self.layers_with_compression()?.map(|(compression, digest)| {
-            debug!("Fetching blob stream for {}", digest);
-            let res = self.handle.block_on(
-                self.client
-                    .pull_blob_stream(&self.reference, digest.to_string().as_str()),
-            )?;
-
-            let reader = tokio_util::io::StreamReader::new(res);
-            let reader = BufReader::with_capacity(5 * 1024 * 1024, reader);
-            let bridge = SyncIoBridge::new_with_handle(reader, self.handle.clone());
-            let reader = compression.new_reader(bridge)?;
-            InputLayer::new(digest, reader)
-        }))
+        let layers = self
+            .layers_with_compression()?
+            .map(|(compression, digest)| {
+                let size = self.layer_size(&digest);
+                (compression, digest, size)
+            })
+            .collect_vec();
+        let fetcher = self.layer_fetcher();
+        let prefetcher = Prefetcher::spawn(
+            "download",
+            layers.iter().map(|(_, digest, size)| (digest.clone(), *size)).collect(),
+            self.options.downloads.clone(),
+            move |(digest, size)| fetcher.prefetch(&digest, size),
+        )?;
+        let fetcher = self.layer_fetcher();
+        Ok(layers
+            .into_iter()
+            .enumerate()
+            .map(move |(index, (compression, digest, size))| {
+                let reader: Box<dyn Read> = match prefetcher.take(index)? {
+                    PrefetchedLayer::Spooled(file) => Box::new(BufReader::new(file)),
+                    // The layer may have been evicted again if the cache is small, in which case it is streamed
+                    PrefetchedLayer::Cached => match fetcher.open_cached(&digest, size)? {
+                        Some(reader) => reader,
+                        None => fetcher.stream(&digest, size)?,
+                    },
+                };
+                let reader = Compression::new_checked_reader(compression, reader, format_args!("Layer {digest}"))?;
+                InputLayer::new(digest, reader)
+            }))
     }
 
     fn config(&self) -> &ImageConfiguration {
@@ -249,18 +757,415 @@ impl InputImage for RemoteImage {
     fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
         Ok(self.layers.clone())
     }
+
+    fn manifest_digest(&self) -> Option<Digest> {
+        Some(self.manifest_digest.clone())
+    }
+
+    fn layer_size(&self, digest: &Digest) -> Option<u64> {
+        self.layer_sizes
+            .iter()
+            .find_map(|(layer, size)| (layer == digest).then_some(*size))
+    }
+
+    fn foreign_layers(&self) -> &[ForeignLayer] {
+        &self.foreign_layers
+    }
+
+    fn lazy_pull_format(&self) -> Option<LazyPullFormat> {
+        self.lazy_pull_layers.first().map(|(_, format)| *format)
+    }
+
+    fn layer_lazy_pull_format(&self, digest: &Digest) -> Option<LazyPullFormat> {
+        self.lazy_pull_layers
+            .iter()
+            .find_map(|(layer, format)| (layer == digest).then_some(*format))
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::input::IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE;
+    use crate::test_utils::{serve_http, TestResponse};
+    use oci_spec::image::Arch;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+
+    fn sha256(content: &[u8]) -> String {
+        Digest::from(hash_reader(content).unwrap().1).to_string()
+    }
+
+    fn descriptor(media_type: &str, content: &[u8], platform: Option<(&str, &str)>) -> serde_json::Value {
+        let mut descriptor = json!({"mediaType": media_type, "digest": sha256(content), "size": content.len()});
+        if let Some((os, architecture)) = platform {
+            descriptor["platform"] = json!({"os": os, "architecture": architecture});
+        }
+        descriptor
+    }
+
+    #[tokio::test]
+    async fn test_nested_index() {
+        let layer = b"layer";
+        let config = json!({
+            "architecture": "arm64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": []},
+            "history": []
+        })
+        .to_string();
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": IMAGE_MANIFEST_MEDIA_TYPE,
+            "config": descriptor("application/vnd.docker.container.image.v1+json", config.as_bytes(), None),
+            "layers": [
+                descriptor("application/vnd.docker.image.rootfs.diff.tar.gzip", layer, None),
+                descriptor("application/vnd.example.layer", b"vendor layer", None),
+            ]
+        })
+        .to_string();
+        let nested = json!({
+            "schemaVersion": 2,
+            "mediaType": IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+            "manifests": [
+                descriptor(IMAGE_MANIFEST_MEDIA_TYPE, b"amd64 manifest", Some(("linux", "amd64"))),
+                descriptor(IMAGE_MANIFEST_MEDIA_TYPE, manifest.as_bytes(), Some(("linux", "arm64"))),
+            ]
+        })
+        .to_string();
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": [descriptor(OCI_IMAGE_INDEX_MEDIA_TYPE, nested.as_bytes(), None)]
+        })
+        .to_string();
+
+        let responses = HashMap::from([
+            ("/v2/test/image/manifests/latest".to_string(), index),
+            (
+                format!("/v2/test/image/manifests/{}", sha256(nested.as_bytes())),
+                nested,
+            ),
+            (
+                format!("/v2/test/image/manifests/{}", sha256(manifest.as_bytes())),
+                manifest,
+            ),
+            (format!("/v2/test/image/blobs/{}", sha256(config.as_bytes())), config),
+        ]);
+        let address = serve_http(move |request| match responses.get(&request.path) {
+            Some(body) => TestResponse::new(200).body(body.as_str()),
+            None => TestResponse::new(404),
+        })
+        .await;
+
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        let matcher = PlatformMatcher::from_glob(globset::Glob::new("linux/arm64").unwrap()).unwrap();
+        let images = RemoteImage::from_list_async(reference, &matcher, RemoteOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].config().architecture(), &Arch::ARM64);
+        assert_eq!(
+            images[0].layers().unwrap(),
+            vec![
+                (MediaType::ImageLayerGzip, Digest::from_str(&sha256(layer)).unwrap()),
+                (
+                    MediaType::Other("application/vnd.example.layer".to_string()),
+                    Digest::from_str(&sha256(b"vendor layer")).unwrap()
+                ),
+            ]
+        );
+    }
+
+    fn test_layer() -> Vec<u8> {
+        crate::test_utils::build_layer()
+            .with_files(&[("file.txt", b"content")])
+            .build_raw()
+    }
+
+    /// Serves a single image with one uncompressed layer, which is `served_layer` instead of `layer` if it differs.
+    /// Returns a reference to the image and a count of the requests the registry received.
+    fn serve_image(runtime: &Runtime, layer: &[u8], served_layer: Vec<u8>) -> (Reference, Arc<AtomicUsize>) {
+        let config = json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": []},
+            "history": []
+        })
+        .to_string();
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MEDIA_TYPE,
+            "config": descriptor("application/vnd.oci.image.config.v1+json", config.as_bytes(), None),
+            "layers": [descriptor("application/vnd.oci.image.layer.v1.tar", layer, None)]
+        })
+        .to_string();
+        let responses = HashMap::from([
+            ("/v2/test/image/manifests/latest".to_string(), manifest.into_bytes()),
+            (
+                format!("/v2/test/image/blobs/{}", sha256(config.as_bytes())),
+                config.into_bytes(),
+            ),
+            (format!("/v2/test/image/blobs/{}", sha256(layer)), served_layer),
+        ]);
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server_requests = requests.clone();
+        let address = runtime.block_on(serve_http(move |request| {
+            server_requests.fetch_add(1, Ordering::SeqCst);
+            match responses.get(&request.path) {
+                Some(body) => TestResponse::new(200).body(body.clone()),
+                None => TestResponse::new(404),
+            }
+        }));
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        (reference, requests)
+    }
+
+    fn read_layers(image: &RemoteImage) -> anyhow::Result<usize> {
+        let mut entries = 0;
+        for input_layer in image.layers_from_manifest()? {
+            let mut input_layer = input_layer?;
+            entries += input_layer.entries()?.collect::<Result<Vec<_>, _>>()?.len();
+            input_layer.finish()?;
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_corrupt_layer() {
+        let layer = test_layer();
+        let mut corrupt = layer.clone();
+        corrupt[600] ^= 1;
+        let runtime = Runtime::new().unwrap();
+        let (reference, _) = serve_image(&runtime, &layer, corrupt);
+        let matcher = PlatformMatcher::match_all();
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference, &matcher, RemoteOptions::default()).unwrap();
+
+        let error = format!("{:#}", read_layers(&images[0]).unwrap_err());
+        assert!(
+            error.contains(&format!("Layer {} is corrupt", sha256(&layer))),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_foreign_layers() {
+        let layer = test_layer();
+        let mut corrupt = layer.clone();
+        corrupt[600] ^= 1;
+        let config = json!({
+            "architecture": "amd64",
+            "os": "windows",
+            "rootfs": {"type": "layers", "diff_ids": [sha256(&layer)]},
+            "history": []
+        })
+        .to_string();
+        let runtime = Runtime::new().unwrap();
+        let responses = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let server_responses = responses.clone();
+        let address = runtime.block_on(serve_http(move |request| {
+            match server_responses.lock().unwrap().get(&request.path) {
+                Some(body) => TestResponse::new(200).body(Vec::clone(body)),
+                None => TestResponse::new(404),
+            }
+        }));
+        let mut foreign_layer = descriptor(IMAGE_DOCKER_FOREIGN_LAYER_MEDIA_TYPE, &layer, None);
+        foreign_layer["urls"] = json!([format!("http://{address}/corrupt"), format!("http://{address}/layer")]);
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": IMAGE_MANIFEST_MEDIA_TYPE,
+            "config": descriptor("application/vnd.docker.container.image.v1+json", config.as_bytes(), None),
+            "layers": [foreign_layer]
+        })
+        .to_string();
+        responses.lock().unwrap().extend([
+            ("/v2/test/image/manifests/latest".to_string(), manifest.into_bytes()),
+            (
+                format!("/v2/test/image/blobs/{}", sha256(config.as_bytes())),
+                config.into_bytes(),
+            ),
+            ("/corrupt".to_string(), corrupt),
+            ("/layer".to_string(), layer.clone()),
+        ]);
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        let matcher = PlatformMatcher::match_all();
+
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference.clone(), &matcher, RemoteOptions::default())
+                .unwrap();
+        assert_eq!(images[0].layers().unwrap(), []);
+        let foreign_layers = images[0].foreign_layers();
+        assert_eq!(foreign_layers.len(), 1);
+        assert_eq!(foreign_layers[0].diff_id, sha256(&layer));
+
+        let options = RemoteOptions {
+            foreign_layers: ForeignLayerPolicy::Fetch,
+            ..Default::default()
+        };
+        let images = RemoteImage::create_remote_images(runtime.handle(), reference, &matcher, options).unwrap();
+        assert_eq!(images[0].foreign_layers(), []);
+        assert_eq!(read_layers(&images[0]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_offline_cache() {
+        let layer = test_layer();
+        let runtime = Runtime::new().unwrap();
+        let (reference, requests) = serve_image(&runtime, &layer, layer.clone());
+        let matcher = PlatformMatcher::match_all();
+        let root = tempfile::tempdir().unwrap();
+
+        let options = |offline| RemoteOptions {
+            cache: Some(Arc::new(BlobCache::new(
+                root.path().to_path_buf(),
+                1024 * 1024,
+                offline,
+            ))),
+            ..Default::default()
+        };
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference.clone(), &matcher, options(false)).unwrap();
+        assert_eq!(read_layers(&images[0]).unwrap(), 1);
+        let online_requests = requests.load(Ordering::SeqCst);
+
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference.clone(), &matcher, options(true)).unwrap();
+        assert_eq!(read_layers(&images[0]).unwrap(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), online_requests);
+
+        // A corrupt layer in the cache fails offline runs, and is fetched again by the next online run
+        let layer_path = root
+            .path()
+            .join("blobs/sha256")
+            .join(&sha256(&layer)["sha256:".len()..]);
+        let mut corrupt = layer.clone();
+        corrupt[600] ^= 1;
+        std::fs::write(&layer_path, &corrupt).unwrap();
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference.clone(), &matcher, options(true)).unwrap();
+        let error = format!("{:#}", read_layers(&images[0]).unwrap_err());
+        assert!(error.contains("in the cache is corrupt"), "{error}");
+        std::fs::write(&layer_path, &corrupt).unwrap();
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference.clone(), &matcher, options(false)).unwrap();
+        assert_eq!(read_layers(&images[0]).unwrap(), 1);
+        assert_eq!(std::fs::read(&layer_path).unwrap(), layer);
+
+        let missing = Reference::from_str(&format!("{}/test/image:missing", reference.registry())).unwrap();
+        let error = RemoteImage::create_remote_images(runtime.handle(), missing, &matcher, options(true)).unwrap_err();
+        assert!(
+            error.to_string().contains("cannot be fetched in offline mode"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_mirror_fallback() {
+        let layer = test_layer();
+        let runtime = Runtime::new().unwrap();
+        let (upstream, upstream_requests) = serve_image(&runtime, &layer, layer.clone());
+        let (mirror, mirror_requests) = serve_image(&runtime, &layer, layer.clone());
+        let broken_requests = Arc::new(AtomicUsize::new(0));
+        let server_requests = broken_requests.clone();
+        let broken = runtime.block_on(serve_http(move |_| {
+            server_requests.fetch_add(1, Ordering::SeqCst);
+            TestResponse::new(404)
+        }));
+        let matcher = PlatformMatcher::match_all();
+
+        let options = |mirrors: &[String]| {
+            let mut options = RemoteOptions::default();
+            for mirror in mirrors {
+                options
+                    .mirrors
+                    .add_mirror(&format!("{}={mirror}", upstream.registry()))
+                    .unwrap();
+            }
+            options
+        };
+        let images = RemoteImage::create_remote_images(
+            runtime.handle(),
+            upstream.clone(),
+            &matcher,
+            options(&[broken.to_string(), mirror.registry().to_string()]),
+        )
+        .unwrap();
+        assert_eq!(read_layers(&images[0]).unwrap(), 1);
+        assert!(broken_requests.load(Ordering::SeqCst) > 0);
+        assert!(mirror_requests.load(Ordering::SeqCst) > 0);
+        assert_eq!(upstream_requests.load(Ordering::SeqCst), 0);
+
+        let images = RemoteImage::create_remote_images(
+            runtime.handle(),
+            upstream.clone(),
+            &matcher,
+            options(&[broken.to_string()]),
+        )
+        .unwrap();
+        assert_eq!(read_layers(&images[0]).unwrap(), 1);
+        assert!(upstream_requests.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn test_convert_schema1() {
+        let top = json!({
+            "architecture": "arm64",
+            "os": "linux",
+            "created": "2017-01-02T00:00:00Z",
+            "config": {"Env": ["PATH=/usr/bin"], "Cmd": ["/app"], "ExposedPorts": {"80/tcp": {}}},
+            "container_config": {"Cmd": ["/bin/sh", "-c", "#(nop) ADD file:app in /"]}
+        });
+        let throwaway = json!({
+            "created": "2017-01-01T12:00:00Z",
+            "throwaway": true,
+            "container_config": {"Cmd": ["/bin/sh", "-c", "#(nop) ENV PATH=/usr/bin"]}
+        });
+        let base = json!({"created": "2017-01-01T00:00:00Z", "container_config": {"Cmd": null}});
+        let blob = |n: u8| sha256(&[n]);
+        let history = [top, throwaway, base].map(|entry| json!({"v1Compatibility": entry.to_string()}));
+        let manifest: Schema1Manifest = serde_json::from_value(json!({
+            "schemaVersion": 1,
+            "name": "test/image",
+            "tag": "latest",
+            "architecture": "amd64",
+            "fsLayers": [{"blobSum": blob(3)}, {"blobSum": blob(2)}, {"blobSum": blob(1)}],
+            "history": history
+        }))
+        .unwrap();
+
+        let (config, layers) = manifest.convert().unwrap();
+        assert_eq!(
+            layers,
+            vec![
+                (MediaType::ImageLayerGzip, Digest::from_str(&blob(1)).unwrap()),
+                (MediaType::ImageLayerGzip, Digest::from_str(&blob(3)).unwrap()),
+            ]
+        );
+        assert_eq!(config.architecture(), &Arch::ARM64);
+        assert_eq!(config.created().as_deref(), Some("2017-01-02T00:00:00Z"));
+        let inner = config.config().as_ref().unwrap();
+        assert_eq!(inner.env().as_deref(), Some(&["PATH=/usr/bin".to_string()][..]));
+        let history = config.history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].empty_layer(), Some(true));
+        assert_eq!(
+            history[2].created_by().as_deref(),
+            Some("/bin/sh -c #(nop) ADD file:app in /")
+        );
+    }
 
     #[test_log::test]
     fn test_remote_image() {
         let runtime = tokio::runtime::Runtime::new().unwrap();
         let reference = "alpine:3.20".parse().unwrap();
         let matcher = crate::platform_matcher::PlatformMatcher::match_all();
-        let images = RemoteImage::create_remote_images(runtime.handle(), reference, &matcher).unwrap();
+        let images =
+            RemoteImage::create_remote_images(runtime.handle(), reference, &matcher, RemoteOptions::default()).unwrap();
         assert_ne!(images.len(), 0);
         for image in images {
             let layers = image.layers().unwrap();
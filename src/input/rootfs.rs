@@ -0,0 +1,309 @@
+use crate::compression::Compression;
+use crate::input::layers::InputLayer;
+use crate::input::InputImage;
+use crate::io_utils::spawn_reader;
+use crate::output_image::image::hash_reader;
+use anyhow::{bail, Context};
+use oci_spec::image::{
+    Arch, ConfigBuilder, Digest, ImageConfiguration, ImageConfigurationBuilder, MediaType, Os, RootFsBuilder,
+};
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use tar::Builder;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+pub enum RootfsSource {
+    Directory(PathBuf),
+    /// A tarball of the root filesystem, optionally compressed with gzip or zstd
+    Tarball(PathBuf),
+}
+
+impl Display for RootfsSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RootfsSource::Directory(path) | RootfsSource::Tarball(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// The settings for the configuration of an image built from a root filesystem
+#[derive(Debug, Clone, Default)]
+pub struct RootfsConfig {
+    pub os: Option<String>,
+    pub architecture: Option<String>,
+    /// `KEY=value` pairs
+    pub env: Vec<String>,
+    /// Either a JSON array, or a command that is split on whitespace
+    pub entrypoint: Option<String>,
+}
+
+impl RootfsConfig {
+    pub fn build(&self) -> anyhow::Result<ImageConfiguration> {
+        if let Some(invalid) = self.env.iter().find(|value| !value.contains('=')) {
+            bail!("Invalid environment variable {invalid:?}, expected KEY=value");
+        }
+        let mut config = ConfigBuilder::default();
+        if !self.env.is_empty() {
+            config = config.env(self.env.clone());
+        }
+        if let Some(entrypoint) = &self.entrypoint {
+            config = config.entrypoint(parse_entrypoint(entrypoint)?);
+        }
+        let rootfs = RootFsBuilder::default()
+            .typ("layers")
+            .diff_ids(vec![])
+            .build()
+            .context("RootFsBuilder Build")?;
+        ImageConfigurationBuilder::default()
+            .os(self.os.as_deref().map(Os::from).unwrap_or_default())
+            .architecture(self.architecture.as_deref().map(Arch::from).unwrap_or_default())
+            .config(config.build().context("ConfigBuilder Build")?)
+            .rootfs(rootfs)
+            .history(vec![])
+            .created(chrono::Utc::now().to_rfc3339())
+            .build()
+            .context("ImageConfigurationBuilder Build")
+    }
+}
+
+fn parse_entrypoint(value: &str) -> anyhow::Result<Vec<String>> {
+    if value.trim_start().starts_with('[') {
+        serde_json::from_str(value).with_context(|| format!("Invalid entrypoint {value}"))
+    } else {
+        Ok(value.split_whitespace().map(str::to_string).collect())
+    }
+}
+
+/// An image with a single layer holding a root filesystem, such as one created by `debootstrap`
+pub struct RootfsImage {
+    source: RootfsSource,
+    config_digest: Digest,
+    image_config: ImageConfiguration,
+}
+
+impl PartialEq for RootfsImage {
+    fn eq(&self, other: &Self) -> bool {
+        self.image_digest() == other.image_digest()
+    }
+}
+
+impl Eq for RootfsImage {}
+
+impl Hash for RootfsImage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let digest = self.image_digest();
+        digest.digest().hash(state);
+        digest.algorithm().as_ref().hash(state);
+    }
+}
+
+impl Debug for RootfsImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Display for RootfsImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.platform().fmt(f)
+    }
+}
+
+impl RootfsImage {
+    pub fn new(source: RootfsSource, config: &RootfsConfig) -> anyhow::Result<Self> {
+        match &source {
+            RootfsSource::Directory(path) if !path.is_dir() => bail!("{} is not a directory", path.display()),
+            RootfsSource::Tarball(path) if !path.is_file() => bail!("{} is not a file", path.display()),
+            _ => {}
+        }
+        let image_config = config.build()?;
+        let (_, config_digest) = hash_reader(image_config.to_string()?.as_bytes())?;
+        Ok(Self {
+            source,
+            config_digest: config_digest.into(),
+            image_config,
+        })
+    }
+}
+
+impl InputImage for RootfsImage {
+    fn image_digest(&self) -> Digest {
+        self.config_digest.clone()
+    }
+
+    fn layers_from_manifest(
+        &self,
+    ) -> anyhow::Result<impl ExactSizeIterator<Item = anyhow::Result<InputLayer<impl Read>>>> {
+        let reader: Box<dyn Read> = match &self.source {
+            RootfsSource::Directory(path) => {
+                let root = path.clone();
+                Box::new(spawn_reader("rootfs", move |writer| {
+                    write_directory_tar(&root, writer)
+                })?)
+            }
+            RootfsSource::Tarball(path) => {
+                let file = File::open(path).with_context(|| format!("Opening rootfs tarball {}", path.display()))?;
+                Box::new(Compression::new_detected_reader(BufReader::new(file))?)
+            }
+        };
+        Ok(std::iter::once(InputLayer::new(self.config_digest.clone(), reader)))
+    }
+
+    fn config(&self) -> &ImageConfiguration {
+        &self.image_config
+    }
+
+    fn layers(&self) -> anyhow::Result<Vec<(MediaType, Digest)>> {
+        Ok(vec![(MediaType::ImageLayer, self.config_digest.clone())])
+    }
+}
+
+/// Writes every file below `root` to a tar stream, with paths relative to `root`. Symlinks are not followed.
+pub fn write_directory_tar(root: &Path, out: impl Write) -> anyhow::Result<()> {
+    write_tar(root, out, false)
+}
+
+/// Like [`write_directory_tar`], for the `diff` directory of an overlayfs layer. Overlay whiteouts, which are
+/// character devices with a 0/0 device number, are written as `.wh.` files like in an image layer, and opaque
+/// directories get a `.wh..wh..opq` file.
+pub fn write_overlay_diff_tar(root: &Path, out: impl Write) -> anyhow::Result<()> {
+    write_tar(root, out, true)
+}
+
+fn write_tar(root: &Path, out: impl Write, overlay_whiteouts: bool) -> anyhow::Result<()> {
+    let mut builder = Builder::new(out);
+    builder.follow_symlinks(false);
+    append_directory(&mut builder, root, Path::new(""), overlay_whiteouts)?;
+    builder.finish()?;
+    Ok(())
+}
+
+fn append_directory(
+    builder: &mut Builder<impl Write>,
+    root: &Path,
+    relative: &Path,
+    overlay_whiteouts: bool,
+) -> anyhow::Result<()> {
+    let directory = root.join(relative);
+    let mut entries = std::fs::read_dir(&directory)
+        .with_context(|| format!("Reading directory {}", directory.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{FileTypeExt, MetadataExt};
+            if file_type.is_socket() {
+                warn!("Skipping socket {}", path.display());
+                continue;
+            }
+            if overlay_whiteouts && file_type.is_char_device() && entry.metadata()?.rdev() == 0 {
+                let mut whiteout_name = std::ffi::OsString::from(".wh.");
+                whiteout_name.push(entry.file_name());
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(0);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, relative.join(whiteout_name), std::io::empty())?;
+                continue;
+            }
+        }
+        debug!("Adding {}", path.display());
+        builder
+            .append_path_with_name(entry.path(), &path)
+            .with_context(|| format!("Adding {} to layer", entry.path().display()))?;
+        if file_type.is_dir() {
+            append_directory(builder, root, &path, overlay_whiteouts)?;
+            // Written after the directory's contents, as merging a layer skips what follows an opaque marker below it
+            if overlay_whiteouts && is_overlay_opaque(&entry.path()) {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(0);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, path.join(".wh..wh..opq"), std::io::empty())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether an overlayfs directory was recreated in its layer, hiding the contents of the layers below it. Rootless
+/// storage marks these with a `user.` xattr, as `trusted.` ones need root.
+#[cfg(target_os = "linux")]
+fn is_overlay_opaque(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    [c"trusted.overlay.opaque", c"user.overlay.opaque"].iter().any(|name| {
+        let mut value = [0u8; 1];
+        let length = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        length == 1 && value[0] == b'y'
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_overlay_opaque(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::read_tar_entries_content;
+
+    #[test]
+    fn test_write_directory_tar() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("etc/apt")).unwrap();
+        std::fs::write(root.path().join("etc/apt/sources.list"), b"deb").unwrap();
+        std::fs::write(root.path().join("etc/hostname"), b"host").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/etc/hostname", root.path().join("hostname")).unwrap();
+
+        let mut content = vec![];
+        write_directory_tar(root.path(), &mut content).unwrap();
+        let entries = read_tar_entries_content(&content);
+        assert_eq!(entries[&PathBuf::from("etc/apt/sources.list")], b"deb");
+        assert_eq!(entries[&PathBuf::from("etc/hostname")], b"host");
+        assert!(entries.contains_key(&PathBuf::from("etc/apt")));
+        #[cfg(unix)]
+        assert_eq!(entries[&PathBuf::from("hostname")], b"");
+    }
+
+    #[test]
+    fn test_build_config() {
+        let config = RootfsConfig {
+            os: Some("linux".to_string()),
+            architecture: Some("arm64".to_string()),
+            env: vec!["PATH=/usr/bin".to_string()],
+            entrypoint: Some(r#"["/bin/sh", "-c"]"#.to_string()),
+        }
+        .build()
+        .unwrap();
+        assert_eq!(config.architecture(), &Arch::ARM64);
+        let inner = config.config().as_ref().unwrap();
+        assert_eq!(inner.env().as_deref(), Some(&["PATH=/usr/bin".to_string()][..]));
+        assert_eq!(
+            inner.entrypoint().as_deref(),
+            Some(&["/bin/sh".to_string(), "-c".to_string()][..])
+        );
+
+        assert_eq!(
+            parse_entrypoint("/usr/bin/app --serve").unwrap(),
+            ["/usr/bin/app", "--serve"]
+        );
+        let invalid = RootfsConfig {
+            env: vec!["PATH".to_string()],
+            ..Default::default()
+        };
+        assert!(invalid.build().is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::compression::Compression;
+use crate::input::{layer_compression, InputImage};
+use crate::platform_matcher::PlatformMatcher;
+use crate::progress::display_bytes;
+use itertools::Itertools;
+use serde::{Serialize, Serializer};
+use std::io::Write;
+
+/// Sizes that cannot be found out without reading the whole layer are written as `"unknown"` rather than `null`
+fn serialize_size<S: Serializer>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match size {
+        Some(size) => serializer.serialize_u64(*size),
+        None => serializer.serialize_str("unknown"),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayerSummary {
+    pub digest: String,
+    pub media_type: String,
+    pub size: Option<u64>,
+    /// Only known for layers that are not compressed, as finding it out means reading the layer
+    #[serde(serialize_with = "serialize_size")]
+    pub uncompressed_size: Option<u64>,
+    /// Kept in the output manifest as it is, rather than being repacked
+    pub foreign: bool,
+}
+
+/// What `inspect` reports about each image of a source, read from its manifest and config only
+#[derive(Debug, Serialize)]
+pub struct ImageSummary {
+    pub platform: String,
+    /// Whether the `--platform` glob selects this image for repacking
+    pub selected: bool,
+    pub manifest_digest: Option<String>,
+    pub config_digest: String,
+    pub layer_count: usize,
+    pub compressed_size: Option<u64>,
+    #[serde(serialize_with = "serialize_size")]
+    pub uncompressed_size: Option<u64>,
+    pub media_types: Vec<String>,
+    pub layers: Vec<LayerSummary>,
+}
+
+impl ImageSummary {
+    pub fn new(image: &impl InputImage, platform_matcher: &PlatformMatcher) -> anyhow::Result<Self> {
+        let foreign_layers = image.foreign_layers().iter().map(|layer| LayerSummary {
+            digest: layer.descriptor.digest().to_string(),
+            media_type: layer.descriptor.media_type().to_string(),
+            size: Some(layer.descriptor.size()),
+            uncompressed_size: None,
+            foreign: true,
+        });
+        let layers = image.layers()?.into_iter().map(|(media_type, digest)| {
+            let size = image.layer_size(&digest);
+            let uncompressed_size = match layer_compression(&media_type) {
+                Some(Compression::Raw) => size,
+                _ => None,
+            };
+            LayerSummary {
+                digest: digest.to_string(),
+                media_type: media_type.to_string(),
+                size,
+                uncompressed_size,
+                foreign: false,
+            }
+        });
+        let layers = foreign_layers.chain(layers).collect_vec();
+        // Totals are only given when every layer's size is known
+        let compressed_size = layers.iter().map(|layer| layer.size).sum();
+        let uncompressed_size = layers.iter().map(|layer| layer.uncompressed_size).sum();
+        Ok(Self {
+            platform: image.platform().to_string(),
+            selected: platform_matcher.matches_image_config(image.config()),
+            manifest_digest: image.manifest_digest().map(|digest| digest.to_string()),
+            config_digest: image.image_digest().to_string(),
+            layer_count: layers.len(),
+            compressed_size,
+            uncompressed_size,
+            media_types: layers.iter().map(|layer| layer.media_type.clone()).unique().collect(),
+            layers,
+        })
+    }
+}
+
+fn format_size(size: Option<u64>, unknown: &str) -> String {
+    match size {
+        Some(size) => format!("{:#.1}", display_bytes(size)),
+        None => unknown.to_string(),
+    }
+}
+
+pub fn write_table(summaries: &[ImageSummary], out: &mut impl Write) -> std::io::Result<()> {
+    let header = [
+        "PLATFORM",
+        "SELECTED",
+        "MANIFEST",
+        "LAYERS",
+        "COMPRESSED",
+        "UNCOMPRESSED",
+        "MEDIA TYPES",
+    ]
+    .map(String::from);
+    let rows = summaries
+        .iter()
+        .map(|summary| {
+            [
+                summary.platform.clone(),
+                if summary.selected { "yes" } else { "no" }.to_string(),
+                summary.manifest_digest.clone().unwrap_or("-".to_string()),
+                summary.layer_count.to_string(),
+                format_size(summary.compressed_size, "-"),
+                format_size(summary.uncompressed_size, "unknown"),
+                summary.media_types.join(", "),
+            ]
+        })
+        .collect_vec();
+    let widths = (0..header.len())
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect_vec();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+pub fn write_json(summaries: &[ImageSummary], out: &mut impl Write) -> anyhow::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, summaries)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci_spec::image::MediaType;
+
+    fn summary(platform: &str, selected: bool, sizes: &[(MediaType, Option<u64>)]) -> ImageSummary {
+        let layers = sizes
+            .iter()
+            .map(|(media_type, size)| LayerSummary {
+                digest: "sha256:0d90d93a5cab3fd2879040420c7b7e4958aee8997fef78e9a5dd80cb01f3bd9c".to_string(),
+                media_type: media_type.to_string(),
+                size: *size,
+                uncompressed_size: None,
+                foreign: false,
+            })
+            .collect_vec();
+        ImageSummary {
+            platform: platform.to_string(),
+            selected,
+            manifest_digest: None,
+            config_digest: "sha256:abc".to_string(),
+            layer_count: layers.len(),
+            compressed_size: layers.iter().map(|layer| layer.size).sum(),
+            uncompressed_size: None,
+            media_types: layers.iter().map(|layer| layer.media_type.clone()).unique().collect(),
+            layers,
+        }
+    }
+
+    #[test]
+    fn test_write_table() {
+        let summaries = [
+            summary(
+                "linux/amd64",
+                true,
+                &[
+                    (MediaType::ImageLayerGzip, Some(1024)),
+                    (MediaType::ImageLayerGzip, Some(1024)),
+                ],
+            ),
+            summary("linux/arm64/v8", false, &[(MediaType::ImageLayerZstd, None)]),
+        ];
+        let mut out = vec![];
+        write_table(&summaries, &mut out).unwrap();
+        let expected = "\
+PLATFORM        SELECTED  MANIFEST  LAYERS  COMPRESSED  UNCOMPRESSED  MEDIA TYPES
+linux/amd64     yes       -         2       2 KiB       unknown       application/vnd.oci.image.layer.v1.tar+gzip
+linux/arm64/v8  no        -         1       -           unknown       application/vnd.oci.image.layer.v1.tar+zstd
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = vec![];
+        write_json(&summaries[1..], &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json[0]["platform"], "linux/arm64/v8");
+        assert_eq!(json[0]["selected"], false);
+        assert_eq!(json[0]["compressed_size"], serde_json::Value::Null);
+        assert_eq!(json[0]["uncompressed_size"], "unknown");
+        assert_eq!(json[0]["layers"][0]["uncompressed_size"], "unknown");
+    }
+}
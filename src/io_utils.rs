@@ -1,4 +1,8 @@
-use std::io::Write;
+use anyhow::bail;
+use oci_spec::image::{Digest, DigestAlgorithm};
+use sha2::Digest as _;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender};
 
 pub struct WriteCounter {
     count: u64,
@@ -24,3 +28,222 @@ impl Write for WriteCounter {
         Ok(())
     }
 }
+
+/// Number of chunks a [`ChannelWriter`] can buffer before writes block on the reader catching up
+const CHANNEL_BOUND: usize = 16;
+
+/// Writes chunks of data to a [`ChannelReader`] on another thread
+pub struct ChannelWriter {
+    sender: SyncSender<std::io::Result<Vec<u8>>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "Reader was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ChannelReader {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // The writer has finished
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// Runs `write` on a new thread, returning a reader of everything it writes. An error returned from `write` is
+/// surfaced by the reader once it has read all the data written before it.
+pub fn spawn_reader(
+    name: &str,
+    write: impl FnOnce(&mut ChannelWriter) -> anyhow::Result<()> + Send + 'static,
+) -> anyhow::Result<ChannelReader> {
+    let (sender, receiver) = std::sync::mpsc::sync_channel(CHANNEL_BOUND);
+    std::thread::Builder::new().name(name.to_string()).spawn(move || {
+        let mut writer = ChannelWriter { sender };
+        if let Err(e) = write(&mut writer) {
+            let _ = writer.sender.send(Err(std::io::Error::other(format!("{e:#}"))));
+        }
+    })?;
+    Ok(ChannelReader {
+        receiver,
+        chunk: vec![],
+        position: 0,
+    })
+}
+
+enum ContentHasher {
+    Sha256(sha2::Sha256),
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+}
+
+impl ContentHasher {
+    fn new(algorithm: &DigestAlgorithm) -> anyhow::Result<Self> {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Ok(Self::Sha256(sha2::Sha256::new())),
+            DigestAlgorithm::Sha384 => Ok(Self::Sha384(sha2::Sha384::new())),
+            DigestAlgorithm::Sha512 => Ok(Self::Sha512(sha2::Sha512::new())),
+            other => bail!("Unsupported digest algorithm {other}"),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha384(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize_hex(&mut self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize_reset()),
+            Self::Sha384(hasher) => format!("{:x}", hasher.finalize_reset()),
+            Self::Sha512(hasher) => format!("{:x}", hasher.finalize_reset()),
+        }
+    }
+}
+
+/// Hashes everything read through it. The read that reaches the end of the content fails if the content does not
+/// have the expected digest, or is not the expected size. Reads past the expected size fail straight away.
+pub struct VerifyingReader<R: Read> {
+    inner: R,
+    name: &'static str,
+    digest: Digest,
+    size: Option<u64>,
+    hasher: ContentHasher,
+    read: u64,
+    verified: bool,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    /// `name` describes the content in errors, for example `Layer`
+    pub fn new(name: &'static str, inner: R, digest: Digest, size: Option<u64>) -> anyhow::Result<Self> {
+        let hasher = ContentHasher::new(digest.algorithm())?;
+        Ok(Self {
+            inner,
+            name,
+            digest,
+            size,
+            hasher,
+            read: 0,
+            verified: false,
+        })
+    }
+
+    fn verify(&mut self) -> std::io::Result<()> {
+        if self.verified {
+            return Ok(());
+        }
+        self.verified = true;
+        if let Some(size) = self.size.filter(|size| *size != self.read) {
+            return Err(self.corrupt(format!("expected {size} bytes but received {}", self.read)));
+        }
+        let actual = self.hasher.finalize_hex();
+        if actual != self.digest.digest() {
+            return Err(self.corrupt(format!(
+                "received content with digest {}:{actual}",
+                self.digest.algorithm()
+            )));
+        }
+        Ok(())
+    }
+
+    fn corrupt(&self, reason: String) -> std::io::Error {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} {} is corrupt: {reason}", self.name, self.digest),
+        )
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.verify()?;
+            return Ok(0);
+        }
+        self.read += read as u64;
+        if let Some(size) = self.size.filter(|size| self.read > *size) {
+            return Err(self.corrupt(format!("expected {size} bytes but received more")));
+        }
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_reader() {
+        let mut reader = spawn_reader("test", |writer| {
+            for i in 0..100 {
+                writer.write_all(format!("{i},").as_bytes())?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, (0..100).map(|i| format!("{i},")).collect::<String>());
+
+        let mut reader = spawn_reader("test", |writer| {
+            writer.write_all(b"partial")?;
+            anyhow::bail!("Failed")
+        })
+        .unwrap();
+        let mut content = vec![];
+        let error = reader.read_to_end(&mut content).unwrap_err();
+        assert_eq!(content, b"partial");
+        assert_eq!(error.to_string(), "Failed");
+    }
+
+    #[test]
+    fn test_verifying_reader() {
+        let content = b"hello world";
+        let digest: Digest = crate::output_image::image::hash_reader(&content[..]).unwrap().1.into();
+        let read = |content: &[u8], size| {
+            let mut reader = VerifyingReader::new("Layer", content, digest.clone(), size).unwrap();
+            reader.read_to_end(&mut vec![]).map_err(|e| e.to_string())
+        };
+        assert_eq!(read(content, Some(11)), Ok(11));
+        assert_eq!(read(content, None), Ok(11));
+
+        let error = read(b"hello there", Some(11)).unwrap_err();
+        assert!(
+            error.starts_with(&format!("Layer {digest} is corrupt: received content")),
+            "{error}"
+        );
+        let error = read(content, Some(12)).unwrap_err();
+        assert!(error.ends_with("expected 12 bytes but received 11"), "{error}");
+        let error = read(content, Some(5)).unwrap_err();
+        assert!(error.ends_with("expected 5 bytes but received more"), "{error}");
+    }
+}
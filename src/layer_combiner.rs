@@ -1,9 +1,13 @@
 #[cfg(test)]
 use crate::input::layers::InputLayer;
+use crate::input::lazy_pull::is_lazy_pull_metadata;
 use memchr::memmem;
 use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::io::{Read, Write};
-use tar::{Builder, Entry};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tar::{Builder, Entry, EntryType, Header};
 use zstd::zstd_safe::WriteBuf;
 
 const WHITEOUT_OPAQUE: &[u8] = b".wh..wh..opq";
@@ -14,6 +18,8 @@ pub struct LayerCombiner<T: Write> {
     items: HashSet<Vec<u8>>,
     whiteout_directories: Vec<Vec<u8>>,
     whiteout_files: HashSet<Vec<u8>>,
+    /// Whether whiteouts are written to the output, for images whose foreign layers are kept below it
+    write_whiteouts: bool,
 }
 
 impl<T: Write> LayerCombiner<T> {
@@ -24,9 +30,17 @@ impl<T: Write> LayerCombiner<T> {
             items: HashSet::new(),
             whiteout_directories: Vec::new(),
             whiteout_files: HashSet::new(),
+            write_whiteouts: false,
         }
     }
 
+    /// Writes the whiteouts of the merged layers to the output when it is finished. Foreign layers that are kept
+    /// as they are sit below the output, and their files are only hidden by these.
+    pub fn with_whiteouts(mut self, write_whiteouts: bool) -> Self {
+        self.write_whiteouts = write_whiteouts;
+        self
+    }
+
     fn add_entry(&mut self, entry: Entry<impl Read>) -> anyhow::Result<()> {
         let entry_path = entry.path_bytes().to_vec();
         if entry_path.ends_with(WHITEOUT_OPAQUE) {
@@ -63,12 +77,31 @@ impl<T: Write> LayerCombiner<T> {
     pub fn merge_entries<'a>(
         &mut self,
         entries: impl Iterator<Item = std::io::Result<Entry<'a, impl Read + 'a>>>,
+    ) -> anyhow::Result<()> {
+        self.merge(entries, false)
+    }
+
+    /// Merges the entries of an eStargz or zstd:chunked layer, leaving out its lazy-pull metadata
+    pub fn merge_lazy_pull_entries<'a>(
+        &mut self,
+        entries: impl Iterator<Item = std::io::Result<Entry<'a, impl Read + 'a>>>,
+    ) -> anyhow::Result<()> {
+        self.merge(entries, true)
+    }
+
+    fn merge<'a>(
+        &mut self,
+        entries: impl Iterator<Item = std::io::Result<Entry<'a, impl Read + 'a>>>,
+        lazy_pull: bool,
     ) -> anyhow::Result<()> {
         for entry in entries {
             let entry = entry?;
             let entry_path = entry.path_bytes();
             let path = entry_path.as_slice();
 
+            if lazy_pull && is_lazy_pull_metadata(path) {
+                continue;
+            }
             if self.should_add_path(path) {
                 self.add_entry(entry)?
             }
@@ -76,13 +109,47 @@ impl<T: Write> LayerCombiner<T> {
         Ok(())
     }
 
+    /// Appends an empty entry for each whiteout. Files that the output has again need none, and opaque directories
+    /// only hide the contents of the layers below them, so the output keeps its own.
+    fn append_whiteouts(&mut self) -> anyhow::Result<()> {
+        if !self.write_whiteouts {
+            return Ok(());
+        }
+        let whiteout_files = self
+            .whiteout_files
+            .iter()
+            .filter(|path| !self.items.contains(*path))
+            .map(|path| match memchr::memrchr(b'/', path) {
+                Some(index) => [&path[..=index], WHITEOUT_PREFIX, &path[index + 1..]].concat(),
+                None => [WHITEOUT_PREFIX, path].concat(),
+            });
+        let opaque_directories = self
+            .whiteout_directories
+            .iter()
+            .map(|directory| [directory, WHITEOUT_OPAQUE].concat());
+        let mut whiteouts: Vec<_> = whiteout_files.chain(opaque_directories).collect();
+        whiteouts.sort();
+        for whiteout in whiteouts {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(0);
+            let path = Path::new(OsStr::from_bytes(&whiteout));
+            self.archive.append_data(&mut header, path, std::io::empty())?;
+            self.items.insert(whiteout);
+        }
+        Ok(())
+    }
+
     pub fn finish(mut self) -> anyhow::Result<usize> {
+        self.append_whiteouts()?;
         self.archive.finish()?;
         Ok(self.items.len())
     }
 
     #[cfg(test)]
-    fn into_inner(self) -> anyhow::Result<(T, usize)> {
+    fn into_inner(mut self) -> anyhow::Result<(T, usize)> {
+        self.append_whiteouts()?;
         Ok((self.archive.into_inner()?, self.items.len()))
     }
 }
@@ -149,6 +216,36 @@ mod tests {
         assert_eq!(combiner.items.len(), 1);
     }
 
+    #[test]
+    fn test_whiteouts_over_kept_layers() {
+        let mut tar_1 = setup_tar();
+        add_file(&mut tar_1, "etc/readded.conf", b"new");
+        add_file(&mut tar_1, "opt/app.txt", b"app");
+        let input_layer_1 = make_input_layer(tar_1);
+
+        let mut tar_2 = setup_tar();
+        add_dir(&mut tar_2, "etc/");
+        add_file(&mut tar_2, "etc/.wh.foreign.conf", b"");
+        add_file(&mut tar_2, "etc/.wh.readded.conf", b"");
+        add_dir(&mut tar_2, "opt/");
+        add_file(&mut tar_2, "opt/.wh..wh..opq", b"");
+        let input_layer_2 = make_input_layer(tar_2);
+
+        let mut output = vec![];
+        let mut combiner = LayerCombiner::new(&mut output).with_whiteouts(true);
+        combiner.merge_layer(input_layer_1).unwrap();
+        combiner.merge_layer(input_layer_2).unwrap();
+        let (data, total) = combiner.into_inner().unwrap();
+        let entries = read_tar_entries_content(data);
+        assert_eq!(total, entries.len());
+        // The file from the kept layer stays deleted, but the one that was added again is not hidden
+        assert_eq!(entries[Path::new("etc/.wh.foreign.conf")], b"");
+        assert_eq!(entries[Path::new("opt/.wh..wh..opq")], b"");
+        assert_eq!(entries[Path::new("opt/app.txt")], b"app");
+        assert_eq!(entries[Path::new("etc/readded.conf")], b"new");
+        assert!(!entries.contains_key(Path::new("etc/.wh.readded.conf")));
+    }
+
     #[test]
     fn test_multiple_layers() {
         let layer_1 = build_layer()
@@ -189,4 +286,29 @@ mod tests {
         assert_eq!(entries[Path::new("one.txt")], b"new content 1");
         assert_eq!(entries[Path::new("five.txt")], b"new content 2");
     }
+
+    #[test]
+    fn test_lazy_pull_metadata() {
+        let files: &[(&str, &[u8])] = &[
+            ("stargz.index.json", b"{}"),
+            (".prefetch.landmark", b"\xf5"),
+            ("app/stargz.index.json", b"kept"),
+        ];
+
+        let mut output = vec![];
+        let mut combiner = LayerCombiner::new(&mut output);
+        let mut layer = build_layer().with_files(files).build();
+        combiner.merge_lazy_pull_entries(layer.entries().unwrap()).unwrap();
+        let (data, total) = combiner.into_inner().unwrap();
+        assert_eq!(total, 1);
+        let entries = read_tar_entries_content(data);
+        assert_eq!(entries[Path::new("app/stargz.index.json")], b"kept");
+
+        // Plain layers may have files of the same names
+        let mut output = vec![];
+        let mut combiner = LayerCombiner::new(&mut output);
+        combiner.merge_layer(build_layer().with_files(files).build()).unwrap();
+        let (_, total) = combiner.into_inner().unwrap();
+        assert_eq!(total, 3);
+    }
 }
@@ -1,32 +1,296 @@
 use oci_client::Reference;
-use std::fmt::Display;
+use oci_spec::image::{Digest, ImageConfiguration};
+use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 use std::str::FromStr;
 
+/// Picks one image out of an OCI layout holding several
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageSelector {
+    /// The `org.opencontainers.image.ref.name` annotation of the image
+    RefName(String),
+    Digest(Digest),
+}
+
+impl Display for ImageSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageSelector::RefName(name) => write!(f, ":{name}"),
+            ImageSelector::Digest(digest) => write!(f, "@{digest}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Location {
-    Oci(PathBuf),
+    Oci(PathBuf, Option<ImageSelector>),
+    OciArchive(PathBuf, Option<ImageSelector>),
     Docker(Reference),
+    DockerArchive(PathBuf, Option<Reference>),
+    DockerDaemon(String),
+    /// An image in a podman/buildah store, optionally at a specific root
+    ContainersStorage(Option<PathBuf>, String),
+    Directory(PathBuf),
+    Rootfs(PathBuf),
 }
 
 impl Display for Location {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Location::Oci(path) => write!(f, "oci://{}", path.display()),
+            Location::Oci(path, None) => write!(f, "oci://{}", path.display()),
+            Location::Oci(path, Some(selector)) => write!(f, "oci://{}{selector}", path.display()),
+            Location::OciArchive(path, None) => write!(f, "oci-archive://{}", path.display()),
+            Location::OciArchive(path, Some(selector)) => write!(f, "oci-archive://{}{selector}", path.display()),
             Location::Docker(reference) => write!(f, "docker://{}", reference),
+            Location::DockerArchive(path, None) => write!(f, "docker-archive://{}", path.display()),
+            Location::DockerArchive(path, Some(reference)) => {
+                write!(f, "docker-archive://{}:{}", path.display(), reference)
+            }
+            Location::DockerDaemon(image) => write!(f, "docker-daemon://{image}"),
+            Location::ContainersStorage(None, image) => write!(f, "containers-storage://{image}"),
+            Location::ContainersStorage(Some(root), image) => {
+                write!(f, "containers-storage://[{}]{image}", root.display())
+            }
+            Location::Directory(path) => write!(f, "dir://{}", path.display()),
+            Location::Rootfs(path) => write!(f, "rootfs://{}", path.display()),
         }
     }
 }
 
+/// Splits `path:ref-name` or `path@sha256:...` into the path of an OCI layout and the image to pick from it. Ref
+/// names are split off at the first colon, like `skopeo` does for `oci:` references.
+fn split_image_selector(value: &str) -> anyhow::Result<(&str, Option<ImageSelector>)> {
+    if let Some((path, digest)) = value.rsplit_once('@') {
+        if digest.contains(':') {
+            return Ok((path, Some(ImageSelector::Digest(Digest::from_str(digest)?))));
+        }
+    }
+    let (path, name) = split_archive_reference(value);
+    Ok((path, name.map(|name| ImageSelector::RefName(name.to_string()))))
+}
+
+fn is_windows_drive(value: &str) -> bool {
+    value.len() == 1 && value.as_bytes()[0].is_ascii_alphabetic()
+}
+
+/// Splits `path/to/archive.tar:image:tag` into the path and the optional reference. Like `skopeo`, the first colon
+/// is the separator, apart from one following a Windows drive letter.
+fn split_archive_reference(value: &str) -> (&str, Option<&str>) {
+    let start = if value.len() >= 2 && is_windows_drive(&value[..1]) && value.as_bytes()[1] == b':' {
+        2
+    } else {
+        0
+    };
+    match value[start..].find(':') {
+        Some(index) => {
+            let (path, reference) = value.split_at(start + index);
+            (path, Some(&reference[1..]).filter(|reference| !reference.is_empty()))
+        }
+        None => (value, None),
+    }
+}
+
+/// Splits `[/path/to/root]image:tag` into the store root and the image. Like `containers-storage:` references in
+/// `skopeo`, the root may have a `driver@` prefix and a `+runroot` suffix, which are ignored.
+fn split_storage_root(value: &str) -> anyhow::Result<(Option<PathBuf>, &str)> {
+    let Some(value) = value.strip_prefix('[') else {
+        return Ok((None, value));
+    };
+    let Some((store, image)) = value.split_once(']') else {
+        anyhow::bail!("Missing ] in store specification [{value}");
+    };
+    let root = store.split_once('@').map_or(store, |(_, root)| root);
+    let root = root.split_once('+').map_or(root, |(root, _)| root);
+    Ok((Some(root.into()), image))
+}
+
 impl FromStr for Location {
     type Err = anyhow::Error;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
         match value.split_once("://") {
             None => Ok(Self::Docker(value.parse()?)),
-            Some(("oci", path)) => Ok(Self::Oci(path.into())),
+            Some(("oci", value)) => {
+                let (path, selector) = split_image_selector(value)?;
+                Ok(Self::Oci(path.into(), selector))
+            }
+            Some(("oci-archive", value)) => {
+                let (path, selector) = split_image_selector(value)?;
+                Ok(Self::OciArchive(path.into(), selector))
+            }
             Some(("docker", reference)) => Ok(Self::Docker(reference.parse()?)),
+            Some(("docker-archive", value)) => {
+                let (path, reference) = split_archive_reference(value);
+                let reference = reference.map(Reference::from_str).transpose()?;
+                Ok(Self::DockerArchive(path.into(), reference))
+            }
+            Some(("docker-daemon", image)) => Ok(Self::DockerDaemon(image.to_string())),
+            Some(("containers-storage", value)) => {
+                let (root, image) = split_storage_root(value)?;
+                Ok(Self::ContainersStorage(root, image.to_string()))
+            }
+            Some(("dir", path)) => Ok(Self::Directory(path.into())),
+            Some(("rootfs", path)) => Ok(Self::Rootfs(path.into())),
             Some((prefix, _)) => Err(anyhow::anyhow!("Invalid image type {prefix}")),
         }
     }
 }
+
+/// A platform given on the command line for a source
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcePlatform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl SourcePlatform {
+    /// Whether an image configuration is for this platform. Any variant matches when none was given.
+    pub fn matches(&self, config: &ImageConfiguration) -> bool {
+        config.os().to_string().eq_ignore_ascii_case(&self.os)
+            && config
+                .architecture()
+                .to_string()
+                .eq_ignore_ascii_case(&self.architecture)
+            && self.variant.as_ref().is_none_or(|variant| {
+                config
+                    .variant()
+                    .as_ref()
+                    .is_some_and(|config_variant| config_variant.eq_ignore_ascii_case(variant))
+            })
+    }
+}
+
+impl Display for SourcePlatform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a platform given as `os/arch` or `os/arch/variant`
+pub fn parse_platform(value: &str) -> anyhow::Result<SourcePlatform> {
+    let parts = value.split('/').collect::<Vec<_>>();
+    match parts[..] {
+        [os, architecture] | [os, architecture, _] if parts.iter().all(|part| !part.is_empty()) => Ok(SourcePlatform {
+            os: os.to_string(),
+            architecture: architecture.to_string(),
+            variant: parts.get(2).map(|variant| variant.to_string()),
+        }),
+        _ => anyhow::bail!("Invalid platform {value}, expected OS/ARCH or OS/ARCH/VARIANT, e.g. linux/arm64"),
+    }
+}
+
+/// A source of images for the output index, given as `[PLATFORM=]SOURCE`, e.g. `linux/arm64=docker://app:arm64`
+#[derive(Debug, Clone)]
+pub struct PlatformSource {
+    pub location: Location,
+    /// Selects the image of this platform from the source, or replaces the platform in the configuration of a
+    /// source holding a single image
+    pub platform: Option<SourcePlatform>,
+}
+
+impl FromStr for PlatformSource {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // Platforms have no colons, unlike the type prefixes and tags of locations
+        match value.split_once('=') {
+            Some((platform, location)) if !platform.contains(':') => Ok(Self {
+                location: location.parse()?,
+                platform: Some(parse_platform(platform)?),
+            }),
+            _ => Ok(Self {
+                location: value.parse()?,
+                platform: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_archive() {
+        let Location::DockerArchive(path, reference) = "docker-archive://images/app.tar".parse().unwrap() else {
+            panic!("Expected a docker archive");
+        };
+        assert_eq!(path, PathBuf::from("images/app.tar"));
+        assert!(reference.is_none());
+
+        let Location::DockerArchive(path, reference) = "docker-archive://app.tar:app:1.0".parse().unwrap() else {
+            panic!("Expected a docker archive");
+        };
+        assert_eq!(path, PathBuf::from("app.tar"));
+        assert_eq!(reference.unwrap().whole(), "docker.io/library/app:1.0");
+
+        assert_eq!(split_archive_reference(r"C:\app.tar:app"), (r"C:\app.tar", Some("app")));
+        assert_eq!(split_archive_reference("app.tar:"), ("app.tar", None));
+    }
+
+    #[test]
+    fn test_split_image_selector() {
+        assert_eq!(split_image_selector("layout/").unwrap(), ("layout/", None));
+        assert_eq!(
+            split_image_selector("./layout:app:1.0").unwrap(),
+            ("./layout", Some(ImageSelector::RefName("app:1.0".to_string())))
+        );
+        let digest = format!("sha256:{}", "a".repeat(64));
+        assert_eq!(
+            split_image_selector(&format!("layout@{digest}")).unwrap(),
+            (
+                "layout",
+                Some(ImageSelector::Digest(Digest::from_str(&digest).unwrap()))
+            )
+        );
+        assert!(split_image_selector("layout@sha256:invalid").is_err());
+    }
+
+    #[test]
+    fn test_split_storage_root() {
+        assert_eq!(split_storage_root("app:latest").unwrap(), (None, "app:latest"));
+        assert_eq!(
+            split_storage_root("[overlay@/var/lib/containers/storage+/run/containers]app").unwrap(),
+            (Some(PathBuf::from("/var/lib/containers/storage")), "app")
+        );
+        assert!(split_storage_root("[/storage app").is_err());
+    }
+
+    #[test]
+    fn test_platform_source() {
+        let source = PlatformSource::from_str("linux/arm64/v8=docker://app:arm64").unwrap();
+        let platform = source.platform.unwrap();
+        assert_eq!(platform.variant.as_deref(), Some("v8"));
+        assert_eq!(platform.to_string(), "linux/arm64/v8");
+        assert_eq!(source.location.to_string(), "docker://docker.io/library/app:arm64");
+
+        let source = PlatformSource::from_str("oci://layout:app=1").unwrap();
+        assert!(source.platform.is_none());
+        assert!(matches!(source.location, Location::Oci(_, Some(ImageSelector::RefName(name))) if name == "app=1"));
+
+        assert!(PlatformSource::from_str("linux=docker://app").is_err());
+        assert!(PlatformSource::from_str("linux//v8=docker://app").is_err());
+    }
+
+    #[test]
+    fn test_source_platform_matches() {
+        let config = |architecture: &str, variant: Option<&str>| {
+            let mut config = ImageConfiguration::default();
+            config.set_architecture(architecture.into());
+            config.set_variant(variant.map(str::to_string));
+            config
+        };
+        let arm64 = parse_platform("linux/arm64").unwrap();
+        assert!(arm64.matches(&config("arm64", Some("v8"))));
+        assert!(!arm64.matches(&config("amd64", None)));
+        let arm_v7 = parse_platform("linux/arm/v7").unwrap();
+        assert!(arm_v7.matches(&config("arm", Some("v7"))));
+        assert!(!arm_v7.matches(&config("arm", Some("v6"))));
+        assert!(!arm_v7.matches(&config("arm", None)));
+    }
+}
@@ -1,9 +1,24 @@
 use crate::index::{ImageItem, ImageItems};
-use crate::input::remote_image::RemoteImage;
+use crate::input::added_files::{parse_mode, AddedDirectory, AddedFiles, Owner};
+use crate::input::blob_cache::{default_cache_root, BlobCache};
+use crate::input::containers_storage::{default_storage_root, ContainersStorageImage};
+use crate::input::docker_archive::DockerArchiveImage;
+use crate::input::docker_daemon::{docker_socket_path, save_image};
+use crate::input::foreign_layers::ForeignLayerPolicy;
+use crate::input::lazy_pull::LazyPullFormat;
+use crate::input::prefetch::{DownloadLimiter, DEFAULT_DOWNLOAD_CONCURRENCY};
+use crate::input::remote_image::{RemoteImage, RemoteOptions, DEFAULT_MANIFEST_CONCURRENCY};
+use crate::input::rootfs::{RootfsConfig, RootfsImage, RootfsSource};
+use crate::io_utils::spawn_reader;
 use crate::layer_combiner::LayerCombiner;
+use crate::registry::auth::Credentials;
+use crate::registry::blob_reader::DEFAULT_BLOB_RETRIES;
+use crate::registry::config::{RegistryConfig, DEFAULT_CERTS_DIRS};
+use crate::registry::credentials::RegistryRole;
+use crate::registry::mirrors::MirrorRules;
 use anyhow::{bail, Context};
 use byte_unit::Byte;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use globset::Glob;
 use input::InputImage;
 use itertools::Itertools;
@@ -11,12 +26,15 @@ use memmap2::Mmap;
 use oci_spec::image::Sha256Digest;
 use output_image::image::OutputImageWriter;
 use output_image::layers::OutputLayers;
+use output_image::registry::{RegistryPusher, DEFAULT_UPLOAD_CONCURRENCY};
 use rand::prelude::*;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::File;
-use std::path::Path;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tracing::{info, info_span, instrument, Level};
 use tracing_indicatif::IndicatifLayer;
 use tracing_subscriber::layer::SubscriberExt;
@@ -25,19 +43,23 @@ use tracing_subscriber::util::SubscriberInitExt;
 mod compression;
 mod index;
 mod input;
+mod inspect;
 mod io_utils;
 mod layer_combiner;
 pub mod location;
 mod output_image;
 mod platform_matcher;
 mod progress;
+mod registry;
 #[cfg(test)]
 mod test_utils;
 
 use crate::input::local_image::LocalOciImage;
+use crate::input::overlay::{BoxedImage, OverlayImage};
+use crate::inspect::ImageSummary;
 use crate::platform_matcher::PlatformMatcher;
 use crate::progress::{display_bytes, progress_parallel_collect};
-use location::Location;
+use location::{parse_platform, Location, PlatformSource, SourcePlatform};
 use output_image::stats::WrittenImageStats;
 use shadow_rs::shadow;
 use tracing_subscriber::filter::Directive;
@@ -46,15 +68,26 @@ use tracing_subscriber::EnvFilter;
 shadow!(build);
 
 #[derive(Parser, Debug)]
-#[clap(version = build::CLAP_LONG_VERSION)]
+#[clap(
+    version = build::CLAP_LONG_VERSION,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Source image. e.g. `python:3.11`, `tensorflow/tensorflow:latest` or `oci://local/image/path`
-    source: Location,
-    /// Location to save image, e.g oci://directory/path/
-    output_dir: Location,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Source image. e.g. `python:3.11`, `tensorflow/tensorflow:latest`, `oci://local/image/path`,
+    /// `oci-archive://image.tar`, `docker-archive://image.tar:tag`, `docker-daemon://image:tag`,
+    /// `containers-storage://image:tag`, `dir://rootfs/directory` or `rootfs://rootfs.tar.gz`
+    #[arg(required = true)]
+    source: Option<Location>,
+    /// Location to save image, e.g `oci://directory/path/` or `docker://registry.example.com/image:tag`
+    #[arg(required = true)]
+    output_dir: Option<Location>,
     /// Target size for layers
-    #[arg(long, short)]
-    target_size: Byte,
+    #[arg(long, short, required = true)]
+    target_size: Option<Byte>,
 
     #[arg(long)]
     concurrency: Option<usize>,
@@ -65,8 +98,164 @@ struct Args {
     #[arg(long, default_value = "14")]
     compression_level: i32,
 
-    #[arg(long, default_value = "linux/*")]
+    #[arg(long, default_value = "linux/*", global = true)]
     platform: Glob,
+
+    /// Repositories on the output registry to mount existing blobs from, instead of uploading them
+    #[arg(long)]
+    mount_from: Vec<String>,
+
+    /// Socket of the Docker daemon for `docker-daemon://` sources [default: `DOCKER_HOST` or /var/run/docker.sock]
+    #[arg(long, global = true)]
+    docker_socket: Option<PathBuf>,
+
+    /// Operating system of images built from `dir://` or `rootfs://` sources [default: the current OS]
+    #[arg(long, global = true)]
+    os: Option<String>,
+
+    /// Architecture of images built from `dir://` or `rootfs://` sources [default: the current architecture]
+    #[arg(long, global = true)]
+    arch: Option<String>,
+
+    /// Environment variable for images built from `dir://` or `rootfs://` sources, e.g. `PATH=/usr/bin:/bin`
+    #[arg(long, global = true)]
+    env: Vec<String>,
+
+    /// Entrypoint for images built from `dir://` or `rootfs://` sources, as a JSON array or a command
+    #[arg(long, global = true)]
+    entrypoint: Option<String>,
+
+    /// Directory to cache blobs pulled from registries in [default: `~/.cache/docker-repack`]
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
+
+    /// Maximum size of the blob cache. The least recently used blobs are removed beyond this size
+    #[arg(long, default_value = "20GB", global = true)]
+    cache_size: Byte,
+
+    /// Do not read or write the blob cache
+    #[arg(long, conflicts_with = "offline", global = true)]
+    no_cache: bool,
+
+    /// Read registry images from the blob cache only, failing if anything is missing from it
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// How many times an interrupted layer download is resumed before giving up
+    #[arg(long, default_value_t = DEFAULT_BLOB_RETRIES)]
+    download_retries: usize,
+
+    /// How many layers are downloaded from registries at once, ahead of being repacked. This is separate from
+    /// `--concurrency`, which sets the number of threads used for repacking
+    #[arg(long, default_value_t = DEFAULT_DOWNLOAD_CONCURRENCY)]
+    download_concurrency: usize,
+
+    /// How many blobs are pushed to the output registry at once
+    #[arg(long, default_value_t = DEFAULT_UPLOAD_CONCURRENCY)]
+    upload_concurrency: usize,
+
+    /// How many platform manifests of a multi-platform image are fetched from registries at once
+    #[arg(long, default_value_t = DEFAULT_MANIFEST_CONCURRENCY, global = true)]
+    manifest_concurrency: usize,
+
+    /// Username for registries, with the password read from stdin with `--password-stdin`
+    #[arg(long, requires = "password_stdin", global = true)]
+    username: Option<String>,
+
+    /// Read the registry password from stdin. Without `--username`, stdin holds an identity token instead
+    #[arg(long, global = true)]
+    password_stdin: bool,
+
+    /// Use the `--password-stdin` credentials for only the `source` or `destination` registry [default: both]
+    #[arg(long, requires = "password_stdin", global = true)]
+    credentials_for: Option<RegistryRole>,
+
+    /// Directories holding CA and client certificates for registries, with a subdirectory per registry as in
+    /// `/etc/docker/certs.d/registry.example.com:5000/ca.crt`
+    #[arg(long, default_values = DEFAULT_CERTS_DIRS, global = true)]
+    certs_dir: Vec<PathBuf>,
+
+    /// Registry whose TLS certificate is not verified, e.g. `registry.example.com:5000`
+    #[arg(long, global = true)]
+    insecure_registry: Vec<String>,
+
+    /// Registry to connect to over plain HTTP. Registries on localhost always are
+    #[arg(long, global = true)]
+    plain_http: Vec<String>,
+
+    /// Proxy for registries, instead of `HTTPS_PROXY`
+    #[arg(long, global = true)]
+    https_proxy: Option<String>,
+
+    /// Comma-separated hosts that are connected to without a proxy, instead of `NO_PROXY`
+    #[arg(long, global = true)]
+    no_proxy: Option<String>,
+
+    /// Mirror rules for source registries, in the format of containers' `registries.conf`
+    #[arg(long, global = true)]
+    registries_conf: Option<PathBuf>,
+
+    /// Pull images under a registry or repository prefix through a mirror first, e.g.
+    /// `docker.io=mirror.example.com:5000`. Mirrors are tried in order, then the upstream registry
+    #[arg(long, global = true)]
+    mirror: Vec<String>,
+
+    /// What to do with foreign and non-distributable layers, which registries do not serve themselves: `fetch` them
+    /// from their URLs and repack them, `keep` them in the output manifest as they are, or `drop` them
+    #[arg(long, default_value_t = ForeignLayerPolicy::default(), global = true)]
+    foreign_layers: ForeignLayerPolicy,
+
+    /// Adds the contents of a host directory to the image, e.g. `./certs:/etc/ssl/certs`. The files are merged on top
+    /// of the image's filesystem and repacked with the rest of it
+    #[arg(long, value_name = "HOST_DIR:IMAGE_PATH")]
+    add: Vec<AddedDirectory>,
+
+    /// Owner of the files added with `--add`, as a numeric `UID:GID`
+    #[arg(long, default_value = "0:0")]
+    add_owner: Owner,
+
+    /// Permissions of the regular files added with `--add`, in octal. Directories keep those on the host
+    /// [default: those on the host]
+    #[arg(long, value_parser = parse_mode)]
+    add_mode: Option<u32>,
+
+    /// Modification time of the files added with `--add`, in seconds since the epoch [default: those on the host]
+    #[arg(long)]
+    add_mtime: Option<u64>,
+
+    /// Another source whose filesystem is stacked on top of the source's, for the images of the same platform. Later
+    /// overlays win, and their whiteouts hide files below them
+    #[arg(long, value_name = "SOURCE")]
+    overlay: Vec<Location>,
+
+    /// Which source the image configuration is taken from when overlaying: 0 for the source, 1 for the first
+    /// `--overlay`, and so on. The environment variables of every source are merged into it
+    #[arg(long, default_value_t = 0, requires = "overlay")]
+    config_from: usize,
+
+    /// Platform of the source's image as `os/arch[/variant]`. It is selected from a multi-platform source, and replaces
+    /// the platform in the configuration of a source holding a single image
+    #[arg(long, value_parser = parse_platform)]
+    source_platform: Option<SourcePlatform>,
+
+    /// Another source whose images are added to the output index alongside the source's, e.g.
+    /// `linux/arm64=docker://app:arm64`. Giving a platform selects it from a multi-platform source, or replaces the
+    /// one in the configuration of a source holding a single image
+    #[arg(long, value_name = "[PLATFORM=]SOURCE")]
+    index_source: Vec<PlatformSource>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the platforms of a source image with their manifest digests, layers and sizes. Only manifests and configs
+    /// are fetched, never layers
+    Inspect {
+        /// Source image, in any of the forms accepted for repacking
+        source: Location,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 pub fn main() -> anyhow::Result<()> {
@@ -85,16 +274,84 @@ pub fn main() -> anyhow::Result<()> {
         .with(env_builder)
         .init();
     let args = Args::parse();
+    let credentials = if args.password_stdin {
+        Some(read_credentials(args.username.clone(), std::io::stdin().lock())?)
+    } else {
+        None
+    };
+    let registry_config = RegistryConfig {
+        certs_dirs: args.certs_dir.clone(),
+        insecure: args.insecure_registry.clone(),
+        plain_http: args.plain_http.clone(),
+        https_proxy: args.https_proxy.clone(),
+        no_proxy: args.no_proxy.clone(),
+    };
+    let credentials_for = |role: RegistryRole| {
+        credentials
+            .as_ref()
+            .filter(|_| args.credentials_for.is_none_or(|only| only == role))
+    };
+
+    let platform_matcher = PlatformMatcher::from_glob(args.platform.clone())?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    // Shared by every source, so `--download-concurrency` holds however many sources are read
+    let downloads = Arc::new(DownloadLimiter::new(args.download_concurrency));
 
-    let output_dir = match args.output_dir {
-        Location::Oci(path) => path,
-        Location::Docker(_) => {
-            bail!("Docker registry output is not currently supported")
+    if let Some(Command::Inspect { source, json }) = &args.command {
+        if let Location::DockerDaemon(_) = source {
+            bail!("Inspecting {source} is not supported, as the Docker daemon can only export whole images");
         }
+        // Every platform is read, so that the report can show which ones `--platform` selects
+        let reader = SourceReader {
+            args: &args,
+            platform_matcher: &PlatformMatcher::match_all(),
+            runtime: &runtime,
+            temp_dir: &std::env::temp_dir(),
+            credentials: credentials_for(RegistryRole::Source),
+            registry_config: &registry_config,
+            downloads: &downloads,
+        };
+        let inspector = Inspector {
+            platform_matcher: &platform_matcher,
+            json: *json,
+        };
+        return reader.read(source.clone(), inspector);
+    }
+
+    let (source, output_dir, target_size) = match (args.source.clone(), args.output_dir.clone(), args.target_size) {
+        (Some(source), Some(output_dir), Some(target_size)) => (source, output_dir, target_size),
+        _ => bail!("A source, an output location and --target-size are required"),
+    };
+    // Removed when this returns, whether or not the push succeeds, unless the temporary files are kept
+    let mut staging_dir = None;
+    let (output_dir, push_reference) = match output_dir {
+        Location::Oci(path, None) => (path, None),
+        Location::Docker(reference) => {
+            // Images are written to a local OCI layout first, which is then pushed to the registry
+            let staging = tempfile::Builder::new()
+                .prefix("docker-repack-")
+                .tempdir()
+                .context("Creating a staging directory")?;
+            let path = if args.keep_temp_files {
+                staging.into_path()
+            } else {
+                staging_dir.insert(staging).path().to_path_buf()
+            };
+            info!("Staging image for {} in {}", reference, path.display());
+            (path, Some(reference))
+        }
+        location => bail!("{location} is not supported as an output"),
     };
 
+    let added_files = AddedFiles {
+        directories: args.add.clone(),
+        owner: args.add_owner,
+        mode: args.add_mode,
+        mtime: args.add_mtime,
+    };
+    added_files.check()?;
+
     let temp_dir = output_dir.join("temp");
-    let target_size = args.target_size;
 
     let output_image =
         OutputImageWriter::new(output_dir.to_path_buf(), temp_dir.clone()).context("Construct OutputImageWriter")?;
@@ -104,20 +361,49 @@ pub fn main() -> anyhow::Result<()> {
         .num_threads(args.concurrency.unwrap_or_default())
         .build_global()?;
     info!("Using {} threads", rayon::current_num_threads());
-    let platform_matcher = PlatformMatcher::from_glob(args.platform)?;
 
-    let results = match args.source {
-        Location::Oci(path) => {
-            info!("Reading images from OCI directory: {}", path.display());
-            let images = LocalOciImage::from_oci_directory(path, &platform_matcher)?;
-            handle_input_images(images, &temp_dir, &output_image, target_size, args.compression_level)?
+    let reader = SourceReader {
+        args: &args,
+        platform_matcher: &platform_matcher,
+        runtime: &runtime,
+        temp_dir: &temp_dir,
+        credentials: credentials_for(RegistryRole::Source),
+        registry_config: &registry_config,
+        downloads: &downloads,
+    };
+    let repacker = Repacker {
+        temp_dir: &temp_dir,
+        output_image: &output_image,
+        target_size,
+        compression_level: args.compression_level,
+        added_files: &added_files,
+    };
+    let results = if args.overlay.is_empty() && args.index_source.is_empty() && args.source_platform.is_none() {
+        reader.read(source, repacker)?
+    } else {
+        let sources = std::iter::once(PlatformSource {
+            location: source,
+            platform: args.source_platform.clone(),
+        })
+        .chain(args.index_source.iter().cloned());
+        let mut base = vec![];
+        for source in sources {
+            base.extend(reader.read_platform_source(source)?);
         }
-        Location::Docker(reference) => {
-            info!("Reading images registry: {}", reference);
-            let runtime = tokio::runtime::Runtime::new()?;
-            let images = RemoteImage::create_remote_images(runtime.handle(), reference, &platform_matcher)?;
-            handle_input_images(images, &temp_dir, &output_image, target_size, args.compression_level)?
+        if let Some(platform) = base
+            .iter()
+            .map(|image| image.platform().to_string())
+            .duplicates()
+            .next()
+        {
+            bail!("More than one source has an image for platform {platform}");
         }
+        let overlays = args
+            .overlay
+            .iter()
+            .map(|overlay| Ok((overlay.to_string(), reader.read(overlay.clone(), Collector)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        repacker.handle(OverlayImage::stack(base, overlays, args.config_from)?)?
     };
 
     if !args.keep_temp_files {
@@ -127,11 +413,18 @@ pub fn main() -> anyhow::Result<()> {
     info!("Wrote {} images to {}:", results.len(), output_dir.display());
     for (_, _, written_image) in &results {
         let total_size = written_image.layers.iter().map(|l| l.compressed_file_size).sum::<u64>();
-        info!(
-            "Written image {} - {:#.1}:",
-            written_image.platform,
-            display_bytes(total_size)
-        );
+        match written_image.lazy_pull_format {
+            Some(lazy_pull_format) => info!(
+                "Written image {} - {:#.1}, converted from {lazy_pull_format}:",
+                written_image.platform,
+                display_bytes(total_size)
+            ),
+            None => info!(
+                "Written image {} - {:#.1}:",
+                written_image.platform,
+                display_bytes(total_size)
+            ),
+        }
         for layer in &written_image.layers {
             info!(" - {}", layer);
         }
@@ -143,17 +436,255 @@ pub fn main() -> anyhow::Result<()> {
         .sorted_by_key(|(_, _, stats)| stats.platform.to_string())
         .collect::<Vec<_>>();
 
-    output_image.write_image_index(&manifests)?;
+    let (_, index_hash) = output_image.write_image_index(&manifests)?;
+
+    if let Some(reference) = push_reference {
+        let pusher = RegistryPusher::new(
+            reference.clone(),
+            &output_dir,
+            args.mount_from.clone(),
+            credentials_for(RegistryRole::Destination),
+            &registry_config,
+            args.upload_concurrency,
+        )?;
+        let digest = runtime
+            .block_on(pusher.push_image_index(&index_hash.into()))
+            .with_context(|| format!("Pushing image to {reference}"))?;
+        info!("Pushed image to {}@{}", reference, digest);
+        println!("{digest}");
+    }
+    // The staged image is only removed once it has been pushed
+    drop(staging_dir);
     info!("Completed");
     Ok(())
 }
 
+/// Receives the images read from a source, whatever type of source they came from
+trait ImageHandler {
+    type Output;
+
+    fn handle<T: InputImage + 'static>(self, images: Vec<T>) -> anyhow::Result<Self::Output>;
+}
+
+/// Repacks the images into the output image
+struct Repacker<'a> {
+    temp_dir: &'a Path,
+    output_image: &'a OutputImageWriter,
+    target_size: Byte,
+    compression_level: i32,
+    added_files: &'a AddedFiles,
+}
+
+impl ImageHandler for Repacker<'_> {
+    type Output = Vec<(u64, Sha256Digest, WrittenImageStats)>;
+
+    fn handle<T: InputImage + 'static>(self, images: Vec<T>) -> anyhow::Result<Self::Output> {
+        handle_input_images(
+            images,
+            self.temp_dir,
+            self.output_image,
+            self.target_size,
+            self.compression_level,
+            self.added_files,
+        )
+    }
+}
+
+/// Keeps the images of a source, to be combined with those of other sources
+struct Collector;
+
+impl ImageHandler for Collector {
+    type Output = Vec<BoxedImage>;
+
+    fn handle<T: InputImage + 'static>(self, images: Vec<T>) -> anyhow::Result<Self::Output> {
+        Ok(images.into_iter().map(|image| Box::new(image) as BoxedImage).collect())
+    }
+}
+
+/// Prints a summary of the images to stdout, for the `inspect` subcommand
+struct Inspector<'a> {
+    platform_matcher: &'a PlatformMatcher,
+    json: bool,
+}
+
+impl ImageHandler for Inspector<'_> {
+    type Output = ();
+
+    fn handle<T: InputImage + 'static>(self, images: Vec<T>) -> anyhow::Result<()> {
+        let summaries = images
+            .iter()
+            .map(|image| ImageSummary::new(image, self.platform_matcher))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut stdout = std::io::stdout().lock();
+        if self.json {
+            inspect::write_json(&summaries, &mut stdout)
+        } else {
+            Ok(inspect::write_table(&summaries, &mut stdout)?)
+        }
+    }
+}
+
+/// Reads the images of any source location with the options given on the command line
+struct SourceReader<'a> {
+    args: &'a Args,
+    platform_matcher: &'a PlatformMatcher,
+    runtime: &'a tokio::runtime::Runtime,
+    temp_dir: &'a Path,
+    credentials: Option<&'a Credentials>,
+    registry_config: &'a RegistryConfig,
+    /// Limits the layer downloads of all registry sources together
+    downloads: &'a Arc<DownloadLimiter>,
+}
+
+impl SourceReader<'_> {
+    /// Reads the images of a source for the output index. When the source has a platform, the image of that platform
+    /// is selected from a multi-platform source, and a single image is given that platform.
+    fn read_platform_source(&self, source: PlatformSource) -> anyhow::Result<Vec<BoxedImage>> {
+        let Some(platform) = source.platform else {
+            return self.read(source.location, Collector);
+        };
+        // The platform is the one given, so it is not matched against `--platform`
+        let reader = SourceReader {
+            platform_matcher: &PlatformMatcher::match_all(),
+            ..*self
+        };
+        let mut images = reader.read(source.location.clone(), Collector)?;
+        if images.len() == 1 {
+            let image = OverlayImage::new(images, 0)?.with_platform(&platform)?;
+            return Ok(vec![Box::new(image)]);
+        }
+        images.retain(|image| platform.matches(image.config()));
+        if images.len() != 1 {
+            bail!(
+                "{} holds {} images for platform {platform}, expected one",
+                source.location,
+                images.len()
+            );
+        }
+        Ok(images)
+    }
+
+    fn read<H: ImageHandler>(&self, source: Location, handler: H) -> anyhow::Result<H::Output> {
+        let args = self.args;
+        let platform_matcher = self.platform_matcher;
+        let rootfs_config = RootfsConfig {
+            os: args.os.clone(),
+            architecture: args.arch.clone(),
+            env: args.env.clone(),
+            entrypoint: args.entrypoint.clone(),
+        };
+        match source {
+            Location::Oci(path, selector) => {
+                info!("Reading images from OCI directory: {}", path.display());
+                let images =
+                    LocalOciImage::from_oci_directory(path, selector.as_ref(), platform_matcher, args.foreign_layers)?;
+                handler.handle(images)
+            }
+            Location::OciArchive(path, selector) => {
+                info!("Reading images from OCI archive: {}", path.display());
+                let images =
+                    LocalOciImage::from_oci_archive(path, selector.as_ref(), platform_matcher, args.foreign_layers)?;
+                handler.handle(images)
+            }
+            Location::DockerDaemon(image) => {
+                let socket = docker_socket_path(args.docker_socket.as_deref());
+                info!("Exporting {} from the Docker daemon at {}", image, socket.display());
+                // The export is in `docker save` format, which lists its layers in a manifest.json written after
+                // them, so it is spooled to disk to be indexed rather than read as it streams
+                let archive = tempfile::Builder::new()
+                    .prefix("docker-daemon-")
+                    .suffix(".tar")
+                    .tempfile_in(self.temp_dir)
+                    .with_context(|| format!("Creating a file in {}", self.temp_dir.display()))?;
+                let size = self.runtime.block_on(save_image(&socket, &image, archive.path()))?;
+                info!(
+                    "Exported {:#.1} from the Docker daemon to {}",
+                    display_bytes(size),
+                    archive.path().display()
+                );
+                // The archive is memory-mapped once it is indexed, so the file is removed when this returns
+                let images = DockerArchiveImage::from_archive(archive.path(), None, platform_matcher)?;
+                handler.handle(images)
+            }
+            Location::ContainersStorage(root, image) => {
+                let root = root.unwrap_or_else(default_storage_root);
+                info!("Reading images from containers storage: {}", root.display());
+                let images = ContainersStorageImage::from_storage(&root, &image, platform_matcher)?;
+                handler.handle(images)
+            }
+            Location::Directory(path) => {
+                info!("Building image from directory: {}", path.display());
+                let image = RootfsImage::new(RootfsSource::Directory(path), &rootfs_config)?;
+                handler.handle(vec![image])
+            }
+            Location::Rootfs(path) => {
+                info!("Building image from rootfs tarball: {}", path.display());
+                let image = RootfsImage::new(RootfsSource::Tarball(path), &rootfs_config)?;
+                handler.handle(vec![image])
+            }
+            Location::Docker(reference) => {
+                info!("Reading images registry: {}", reference);
+                let cache = match args.cache_dir.clone().or_else(default_cache_root) {
+                    _ if args.no_cache => None,
+                    Some(root) => {
+                        info!("Caching blobs in {}", root.display());
+                        Some(Arc::new(BlobCache::new(root, args.cache_size.as_u64(), args.offline)))
+                    }
+                    None if args.offline => bail!("No cache directory found for --offline, set one with --cache-dir"),
+                    None => None,
+                };
+                let mut mirrors = match &args.registries_conf {
+                    Some(path) => MirrorRules::load(path)?,
+                    None => MirrorRules::default(),
+                };
+                for mirror in &args.mirror {
+                    mirrors.add_mirror(mirror)?;
+                }
+                let options = RemoteOptions {
+                    cache,
+                    max_retries: args.download_retries,
+                    downloads: self.downloads.clone(),
+                    spool_dir: self.temp_dir.join("downloads"),
+                    manifest_concurrency: args.manifest_concurrency,
+                    credentials: self.credentials.cloned(),
+                    registry_config: self.registry_config.clone(),
+                    mirrors,
+                    foreign_layers: args.foreign_layers,
+                };
+                let images =
+                    RemoteImage::create_remote_images(self.runtime.handle(), reference, platform_matcher, options)?;
+                handler.handle(images)
+            }
+            Location::DockerArchive(path, tag) => {
+                info!("Reading images from docker archive: {}", path.display());
+                let images = DockerArchiveImage::from_archive(&path, tag.as_ref(), platform_matcher)?;
+                handler.handle(images)
+            }
+        }
+    }
+}
+
+/// Reads the password or identity token given with `--password-stdin`
+fn read_credentials(username: Option<String>, mut stdin: impl BufRead) -> anyhow::Result<Credentials> {
+    let mut secret = String::new();
+    stdin.read_line(&mut secret).context("Reading password from stdin")?;
+    let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+    if secret.is_empty() {
+        bail!("No password was given on stdin");
+    }
+    Ok(match username {
+        Some(username) => Credentials::Basic(username, secret),
+        None => Credentials::IdentityToken(secret),
+    })
+}
+
 fn handle_input_images<T: InputImage>(
     images: Vec<T>,
     temp_dir: &Path,
     output_image: &OutputImageWriter,
     target_size: Byte,
     compression_level: i32,
+    added_files: &AddedFiles,
 ) -> anyhow::Result<Vec<(u64, Sha256Digest, WrittenImageStats)>> {
     info!("Found {} images", images.len());
     for image in &images {
@@ -166,18 +697,25 @@ fn handle_input_images<T: InputImage>(
             let image_digest = input_image.image_digest();
             let platform_key = input_image.platform().file_key()?;
             let combined_path = temp_dir.join(format!("combined-{platform_key}-{image_digest}.tar"));
-            let image_items = load_and_merge_image(&input_image, &combined_path)?;
-            Ok((input_image, image_items))
+            let (image_items, lazy_pull_format) = load_and_merge_image(&input_image, &combined_path, added_files)?;
+            Ok((input_image, image_items, lazy_pull_format))
         }),
     )?;
+    let lazy_pull_formats: HashMap<_, _> = images
+        .iter()
+        .filter_map(|(input_image, _, lazy_pull_format)| Some((input_image, (*lazy_pull_format)?)))
+        .collect();
+    for (input_image, lazy_pull_format) in &lazy_pull_formats {
+        info!("{input_image} is in {lazy_pull_format} format, its lazy-pull metadata was dropped");
+    }
     info!(
         "Loaded and merged {} images - {} items in total",
         images.len(),
-        images.iter().map(|(_, v)| v.total_items).sum::<usize>()
+        images.iter().map(|(_, v, _)| v.total_items).sum::<usize>()
     );
     let images_with_content = images
         .iter()
-        .map(|(input_image, image_items)| {
+        .map(|(input_image, image_items, _)| {
             let image_content = image_items.get_image_content()?;
             Ok((input_image, image_content))
         })
@@ -275,37 +813,68 @@ fn handle_input_images<T: InputImage>(
     written_layers_map
         .into_iter()
         .map(|(image, layers)| {
-            output_image
-                .write_oci_image(image.config().clone(), layers, image.platform())
-                .context("Write Image")
+            let (size, hash, mut stats) = output_image
+                .write_oci_image(image.config().clone(), layers, image.foreign_layers(), image.platform())
+                .context("Write Image")?;
+            stats.lazy_pull_format = lazy_pull_formats.get(*image).copied();
+            Ok((size, hash, stats))
         })
         .collect::<anyhow::Result<Vec<_>>>()
 }
 
 #[instrument(skip_all, fields(image = %input_image))]
-fn load_and_merge_image(input_image: &impl InputImage, combined_path: &Path) -> anyhow::Result<ImageItems<Mmap>> {
+fn load_and_merge_image(
+    input_image: &impl InputImage,
+    combined_path: &Path,
+    added_files: &AddedFiles,
+) -> anyhow::Result<(ImageItems<Mmap>, Option<LazyPullFormat>)> {
     let combined_output_file = File::options()
         .create(true)
         .truncate(true)
         .write(true)
         .open(combined_path)
         .with_context(|| format!("Opening file {combined_path:?}"))?;
-    let mut combiner = LayerCombiner::new(combined_output_file);
+    // Kept foreign layers stay below the repacked ones, so files deleted from them are hidden with whiteouts
+    let mut combiner =
+        LayerCombiner::new(combined_output_file).with_whiteouts(!input_image.foreign_layers().is_empty());
+    // Added files are the topmost layer, so they replace files of the same name in the image
+    if !added_files.is_empty() {
+        let added_files = added_files.clone();
+        let reader = spawn_reader("added files", move |writer| added_files.write_tar(writer))?;
+        let mut archive = tar::Archive::new(reader);
+        combiner.merge_entries(archive.entries()?)?;
+    }
     let layer_iterator = input_image.layers_from_manifest()?;
     for input_layer in progress::progress_iter("Merging Layers", layer_iterator) {
         let mut input_layer = input_layer?;
+        let lazy_pull = input_image.layer_lazy_pull_format(&input_layer.name).is_some();
         let entries = progress::spinner_iter("Merging Entries", input_layer.entries()?);
-        combiner.merge_entries(entries)?;
+        if lazy_pull {
+            combiner.merge_lazy_pull_entries(entries)?;
+        } else {
+            combiner.merge_entries(entries)?;
+        }
+        input_layer.finish()?;
+    }
+    // Merged below the image's layers, so that only the destination directories missing from the image are added
+    if !added_files.is_empty() {
+        let mut destinations = vec![];
+        added_files.write_destinations_tar(&mut destinations)?;
+        combiner.merge_entries(tar::Archive::new(destinations.as_slice()).entries()?)?;
     }
 
     let total_items = combiner.finish()?;
-    ImageItems::from_file(combined_path, total_items)
+    Ok((
+        ImageItems::from_file(combined_path, total_items)?,
+        input_image.lazy_pull_format(),
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::layer_combiner::LayerCombiner;
+    use crate::output_image::layers::LayerType;
 
     use crate::test_utils::build_layer;
 
@@ -352,4 +921,40 @@ mod tests {
         let layers = OutputLayers::pack_items(&image_items, 4096, 1024 * 1024 * 250).unwrap();
         assert_eq!(layers.len(), 1);
     }
+
+    #[test]
+    fn test_kept_layer_whiteouts_are_lowest() {
+        let layer = build_layer()
+            .with_files(&[(".wh.foreign.txt", b""), ("small.txt", b"small")])
+            .build();
+
+        let mut data = vec![];
+        let mut combiner = LayerCombiner::new(&mut data).with_whiteouts(true);
+        combiner.merge_layer(layer).unwrap();
+        let total_items = combiner.finish().unwrap();
+
+        let items = ImageItems::from_data(data, total_items);
+        let content = items.get_image_content().unwrap();
+        let image_items = ImageItem::items_from_data(content, 1).unwrap();
+        let layers = OutputLayers::pack_items(&image_items, 4096, 1024 * 1024 * 250).unwrap();
+        // Written layers are ordered by their type, so the whiteout only hides files of the kept layers below
+        let whiteout_layer = layers
+            .all_layers()
+            .iter()
+            .find(|layer| layer.paths().contains(&Path::new(".wh.foreign.txt")))
+            .unwrap();
+        assert_eq!(whiteout_layer.type_, LayerType::Small);
+    }
+
+    #[test]
+    fn test_read_credentials() {
+        let credentials = read_credentials(Some("user".to_string()), "secret\r\nrest".as_bytes()).unwrap();
+        assert_eq!(
+            credentials,
+            Credentials::Basic("user".to_string(), "secret".to_string())
+        );
+        let credentials = read_credentials(None, "token\n".as_bytes()).unwrap();
+        assert_eq!(credentials, Credentials::IdentityToken("token".to_string()));
+        assert!(read_credentials(None, "".as_bytes()).is_err());
+    }
 }
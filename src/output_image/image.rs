@@ -1,4 +1,5 @@
 use crate::compression::Compression;
+use crate::input::foreign_layers::ForeignLayer;
 use crate::input::Platform;
 use crate::io_utils::WriteCounter;
 use crate::output_image::layers::OutputLayer;
@@ -53,22 +54,33 @@ impl OutputImageWriter {
         &self,
         config: ImageConfiguration,
         mut written_layers: Vec<WrittenLayer>,
+        foreign_layers: &[ForeignLayer],
         platform: Platform,
     ) -> anyhow::Result<(u64, Sha256Digest, WrittenImageStats)> {
+        // The small layer is lowest, so whiteouts for kept foreign layers, which are small, never hide repacked files
         written_layers.sort_by_key(|l| (l.layer.type_, l.compressed_file_size));
-        let (config_size, config_hash) = self.write_config(&config, &written_layers).context("Write config")?;
-        self.build_manifest(config_size, config_hash, &written_layers, platform)
+        let (config_size, config_hash) = self
+            .write_config(&config, &written_layers, foreign_layers)
+            .context("Write config")?;
+        self.build_manifest(config_size, config_hash, &written_layers, foreign_layers, platform)
             .context("Build manifest")
     }
 
-    pub fn write_image_index(self, manifests: &[(u64, Sha256Digest, WrittenImageStats)]) -> anyhow::Result<()> {
+    pub fn write_image_index(
+        self,
+        manifests: &[(u64, Sha256Digest, WrittenImageStats)],
+    ) -> anyhow::Result<(u64, Sha256Digest)> {
         let description = manifests.iter().map(|(_, _, stats)| stats.description()).join(" / ");
 
         // All of our manifests should be added to a single index, which is stored as a blob.
         let index = manifests
             .iter()
-            .map(|(size, hash, _)| Descriptor::new(MediaType::ImageManifest, *size, hash.clone()))
-            .collect_vec();
+            .map(|(size, hash, stats)| {
+                let mut descriptor = Descriptor::new(MediaType::ImageManifest, *size, hash.clone());
+                descriptor.set_platform(Some(stats.platform.descriptor_platform()?));
+                Ok(descriptor)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
         let image_index = ImageIndexBuilder::default()
             .schema_version(2u32)
             .media_type(MediaType::ImageIndex)
@@ -83,14 +95,14 @@ impl OutputImageWriter {
             .schema_version(2u32)
             .media_type(MediaType::ImageIndex)
             .annotations([("org.opencontainers.image.description".to_string(), description.clone())])
-            .manifests(&[Descriptor::new(MediaType::ImageIndex, index_size, index_hash)])
+            .manifests(&[Descriptor::new(MediaType::ImageIndex, index_size, index_hash.clone())])
             .build()
             .context("ImageIndexBuilder Build")?;
 
         oci_index.to_file_pretty(self.output_dir.join("index.json"))?;
 
         std::fs::write(self.output_dir.join("oci-layout"), "{\"imageLayoutVersion\":\"1.0.0\"}")?;
-        Ok(())
+        Ok((index_size, index_hash))
     }
 
     fn build_manifest(
@@ -98,18 +110,21 @@ impl OutputImageWriter {
         config_size: u64,
         config_hash: Sha256Digest,
         written_layers: &[WrittenLayer],
+        foreign_layers: &[ForeignLayer],
         platform: Platform,
     ) -> anyhow::Result<(u64, Sha256Digest, WrittenImageStats)> {
         let config_descriptor = Descriptor::new(MediaType::ImageConfig, config_size, config_hash);
-        let layer_descriptors = written_layers
+        // Foreign layers are base layers, so they stay below the repacked layers
+        let layer_descriptors = foreign_layers
             .iter()
-            .map(|l| {
+            .map(|l| l.descriptor.clone())
+            .chain(written_layers.iter().map(|l| {
                 Descriptor::new(
                     MediaType::ImageLayerZstd,
                     l.compressed_file_size,
                     l.compressed_content_hash.clone(),
                 )
-            })
+            }))
             .collect_vec();
 
         let stats = WrittenImageStats::new(written_layers, platform);
@@ -130,15 +145,25 @@ impl OutputImageWriter {
         &self,
         config: &ImageConfiguration,
         layers: &[WrittenLayer],
+        foreign_layers: &[ForeignLayer],
     ) -> anyhow::Result<(u64, Sha256Digest)> {
         let created_at = chrono::Utc::now().to_rfc3339();
-        let diff_ids = layers
+        let diff_ids = foreign_layers
             .iter()
-            .map(|l| format!("sha256:{}", l.raw_content_hash))
+            .map(|l| l.diff_id.clone())
+            .chain(layers.iter().map(|l| format!("sha256:{}", l.raw_content_hash)))
             .collect_vec();
-        let history: Result<Vec<_>, _> = layers
-            .iter()
-            .map(|l| {
+        let foreign_history = foreign_layers.iter().map(|l| {
+            HistoryBuilder::default()
+                .author("docker-repack")
+                .created_by(format!("Foreign layer {}", l.descriptor.digest()))
+                .created(config.created().as_ref().unwrap_or(&created_at))
+                .empty_layer(false)
+                .build()
+                .with_context(|| format!("HistoryBuilder Build for foreign layer {}", l.descriptor.digest()))
+        });
+        let history: Result<Vec<_>, _> = foreign_history
+            .chain(layers.iter().map(|l| {
                 HistoryBuilder::default()
                     .author("docker-repack")
                     .created_by(l.layer.to_string())
@@ -146,7 +171,7 @@ impl OutputImageWriter {
                     .empty_layer(false)
                     .build()
                     .with_context(|| format!("HistoryBuilder Build for layer {}", l.layer))
-            })
+            }))
             .collect();
 
         let mut config = config.clone();
@@ -222,7 +247,7 @@ impl OutputImageWriter {
     }
 }
 
-fn hash_reader(mut content: impl Read) -> anyhow::Result<(u64, Sha256Digest)> {
+pub fn hash_reader(mut content: impl Read) -> anyhow::Result<(u64, Sha256Digest)> {
     let mut hasher = sha2::Sha256::new();
     let compressed_file_size = std::io::copy(&mut content, &mut hasher).context("Copying bytes")?;
     let digest: [u8; 32] = hasher.finalize().into();
@@ -1,3 +1,4 @@
 pub mod image;
 pub mod layers;
+pub mod registry;
 pub mod stats;
@@ -0,0 +1,251 @@
+use crate::input::foreign_layers::has_urls;
+use crate::progress::display_bytes;
+use crate::registry::auth::Credentials;
+use crate::registry::config::RegistryConfig;
+use crate::registry::credentials::{find_credentials, RegistryRole};
+use crate::registry::{pull_scope, push_scope, MountResult, RegistryClient};
+use anyhow::{bail, Context};
+use futures_util::{StreamExt, TryStreamExt};
+use itertools::Itertools;
+use oci_client::Reference;
+use oci_spec::image::{Digest, ImageIndex, ImageManifest, MediaType};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, instrument};
+
+pub const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+
+enum BlobPushResult {
+    Present(u64),
+    Mounted(u64),
+    Uploaded(u64),
+}
+
+pub struct RegistryPusher {
+    client: RegistryClient,
+    reference: Reference,
+    mount_from: Vec<String>,
+    blobs_dir: PathBuf,
+    /// How many blobs are pushed at once
+    upload_concurrency: usize,
+}
+
+impl Display for RegistryPusher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RegistryPusher {}", self.reference)
+    }
+}
+
+impl RegistryPusher {
+    pub fn new(
+        reference: Reference,
+        output_dir: &Path,
+        mount_from: Vec<String>,
+        credentials: Option<&Credentials>,
+        config: &RegistryConfig,
+        upload_concurrency: usize,
+    ) -> anyhow::Result<Self> {
+        if reference.digest().is_some() {
+            bail!("Cannot push to a digest reference ({reference}), use a tag instead");
+        }
+        let auth = find_credentials(&reference, RegistryRole::Destination, credentials);
+        let scopes = std::iter::once(push_scope(reference.repository()))
+            .chain(mount_from.iter().map(|repository| pull_scope(repository)))
+            .collect();
+        let client = RegistryClient::new(&reference, auth, scopes, config)?;
+        let blobs_dir = output_dir.join("blobs").join("sha256");
+        Ok(Self {
+            client,
+            reference,
+            mount_from,
+            blobs_dir,
+            upload_concurrency: upload_concurrency.max(1),
+        })
+    }
+
+    /// Pushes every blob and manifest referenced by the image index with the given digest, then tags the
+    /// index with our reference. Returns the digest of the pushed index.
+    #[instrument(skip_all, fields(reference = %self.reference))]
+    pub async fn push_image_index(&self, index_digest: &Digest) -> anyhow::Result<Digest> {
+        let index_path = self.blob_path(index_digest);
+        let index = ImageIndex::from_file(&index_path)
+            .with_context(|| format!("Error reading image index from {index_path:?}"))?;
+        let manifests = index
+            .manifests()
+            .iter()
+            .map(|descriptor| {
+                let path = self.blob_path(descriptor.digest());
+                let manifest = ImageManifest::from_file(&path)
+                    .with_context(|| format!("Error reading image manifest from {path:?}"))?;
+                Ok((descriptor.digest().clone(), manifest))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let blobs = manifests
+            .iter()
+            .flat_map(|(_, manifest)| std::iter::once(manifest.config()).chain(manifest.layers()))
+            // Foreign layers were kept as they are, and are fetched from their URLs rather than pushed
+            .filter(|descriptor| !has_urls(descriptor))
+            .unique_by(|descriptor| descriptor.digest().to_string())
+            .map(|descriptor| descriptor.digest().clone())
+            .collect_vec();
+        self.push_blobs(blobs).await?;
+
+        for (digest, _) in &manifests {
+            self.push_manifest(&digest.to_string(), digest, MediaType::ImageManifest)
+                .await?;
+        }
+        let tag = self.reference.tag().unwrap_or("latest");
+        self.push_manifest(tag, index_digest, MediaType::ImageIndex).await?;
+        Ok(index_digest.clone())
+    }
+
+    async fn push_blobs(&self, blobs: Vec<Digest>) -> anyhow::Result<()> {
+        info!("Pushing {} blobs to {}", blobs.len(), self.reference);
+        let results: Vec<_> = futures_util::stream::iter(blobs)
+            .map(|digest| async move {
+                let path = self.blob_path(&digest);
+                push_blob(
+                    &self.client,
+                    self.reference.repository(),
+                    &self.mount_from,
+                    &digest,
+                    &path,
+                )
+                .await
+            })
+            .buffer_unordered(self.upload_concurrency)
+            .try_collect()
+            .await?;
+
+        let (mut uploaded, mut skipped, mut mounted) = (0, 0, 0);
+        for result in results {
+            match result {
+                BlobPushResult::Present(size) => skipped += size,
+                BlobPushResult::Mounted(size) => mounted += size,
+                BlobPushResult::Uploaded(size) => uploaded += size,
+            }
+        }
+        info!(
+            "Uploaded {:#.1}, skipped {:#.1} already present and {:#.1} mounted from other repositories",
+            display_bytes(uploaded),
+            display_bytes(skipped),
+            display_bytes(mounted)
+        );
+        Ok(())
+    }
+
+    async fn push_manifest(&self, tag: &str, digest: &Digest, media_type: MediaType) -> anyhow::Result<()> {
+        let path = self.blob_path(digest);
+        let body = std::fs::read(&path).with_context(|| format!("Reading manifest {path:?}"))?;
+        debug!("Pushing {media_type} {digest} to {}:{tag}", self.reference.repository());
+        self.client
+            .put_manifest(self.reference.repository(), tag, media_type.as_ref(), body)
+            .await
+    }
+
+    fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.blobs_dir.join(digest.digest())
+    }
+}
+
+async fn push_blob(
+    client: &RegistryClient,
+    repository: &str,
+    mount_from: &[String],
+    digest: &Digest,
+    path: &Path,
+) -> anyhow::Result<BlobPushResult> {
+    let file = File::open(path).with_context(|| format!("Opening blob {path:?}"))?;
+    let data = unsafe { memmap2::MmapOptions::new().map(&file) }?;
+    let size = data.len() as u64;
+
+    if client.blob_size(repository, digest).await?.is_some() {
+        debug!("Blob {digest} already exists");
+        return Ok(BlobPushResult::Present(size));
+    }
+
+    // Each mount that fails starts an upload session. Only the last one is used for the upload, so the others are
+    // cancelled before the next mount is tried.
+    let mut location = None;
+    for from in mount_from {
+        if let Some(previous) = location.take() {
+            client.cancel_upload(&previous).await;
+        }
+        match client.mount_blob(repository, digest, from).await? {
+            MountResult::Mounted => {
+                debug!("Mounted blob {digest} from {from}");
+                return Ok(BlobPushResult::Mounted(size));
+            }
+            MountResult::Upload(upload_location) => location = Some(upload_location),
+        }
+    }
+    let location = match location {
+        Some(location) => location,
+        None => client.start_upload(repository).await?,
+    };
+
+    debug!("Uploading blob {digest} ({})", display_bytes(size));
+    client
+        .upload_blob(location, digest, &data)
+        .await
+        .with_context(|| format!("Pushing blob {digest} to {repository}"))?;
+    Ok(BlobPushResult::Uploaded(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_image::image::hash_reader;
+    use crate::test_utils::{serve_http, TestResponse};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_push_cancels_unused_upload_sessions() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let server_requests = requests.clone();
+        let address = serve_http(move |request| {
+            server_requests
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", request.method, request.path));
+            match request.method.as_str() {
+                "HEAD" => TestResponse::new(404),
+                "POST" if request.path.contains("from=other%2Frepo") => TestResponse::new(201),
+                "POST" => TestResponse::new(202).header("Location", "/upload/1"),
+                "DELETE" => TestResponse::new(204),
+                method => panic!("Unexpected method {method}"),
+            }
+        })
+        .await;
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let blobs_dir = output_dir.path().join("blobs").join("sha256");
+        std::fs::create_dir_all(&blobs_dir).unwrap();
+        let (_, digest) = hash_reader(b"blob".as_slice()).unwrap();
+        std::fs::write(blobs_dir.join(digest.digest()), b"blob").unwrap();
+
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        let mount_from = vec!["missing/repo".to_string(), "other/repo".to_string()];
+        let pusher = RegistryPusher::new(
+            reference,
+            output_dir.path(),
+            mount_from,
+            Some(&Credentials::Anonymous),
+            &RegistryConfig::default(),
+            DEFAULT_UPLOAD_CONCURRENCY,
+        )
+        .unwrap();
+        pusher.push_blobs(vec![digest.into()]).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        let methods = requests
+            .iter()
+            .map(|request| request.split_once(' ').unwrap().0)
+            .collect_vec();
+        assert_eq!(methods, ["HEAD", "POST", "DELETE", "POST"]);
+        assert_eq!(requests[2], "DELETE /upload/1");
+    }
+}
@@ -1,3 +1,4 @@
+use crate::input::lazy_pull::LazyPullFormat;
 use crate::input::Platform;
 use crate::output_image::image::WrittenLayer;
 use crate::output_image::layers::LayerType;
@@ -38,6 +39,8 @@ impl Display for WrittenLayerStats {
 pub struct WrittenImageStats {
     pub layers: Vec<WrittenLayerStats>,
     pub platform: Platform,
+    /// The lazy-pull format of the source image, whose metadata was left out of the written layers
+    pub lazy_pull_format: Option<LazyPullFormat>,
 }
 
 impl WrittenImageStats {
@@ -45,6 +48,7 @@ impl WrittenImageStats {
         Self {
             platform,
             layers: layers.iter().map(WrittenLayerStats::from_written_layer).collect(),
+            lazy_pull_format: None,
         }
     }
 
@@ -1,6 +1,5 @@
 use globset::{Glob, GlobBuilder, GlobMatcher};
-use oci_client::manifest::Platform as OciClientPlatform;
-use oci_spec::image::Platform;
+use oci_spec::image::{ImageConfiguration, Platform};
 use std::fmt::{Display, Formatter};
 use tracing::{debug, instrument};
 
@@ -39,7 +38,7 @@ impl PlatformMatcher {
         Ok(Self { glob, exclude })
     }
 
-    #[cfg(test)]
+    /// Matches every platform apart from the `unknown/*` entries used for attestations
     pub fn match_all() -> Self {
         let glob = Glob::new("*").unwrap();
         Self::from_glob(glob).unwrap()
@@ -83,19 +82,9 @@ impl PlatformMatcher {
         }
     }
 
-    pub fn matches_oci_client_platform(&self, platform: Option<&OciClientPlatform>) -> bool {
-        match platform {
-            Some(platform) => {
-                let os = &platform.os;
-                let arch = &platform.architecture;
-                let variant = platform.variant.as_deref().unwrap_or("unknown");
-                self.matches_str(os, arch, variant)
-            }
-            None => {
-                // If no platform is specified, we assume it matches
-                true
-            }
-        }
+    pub fn matches_image_config(&self, config: &ImageConfiguration) -> bool {
+        let variant = config.variant().as_deref().unwrap_or("unknown");
+        self.matches_str(&config.os().to_string(), &config.architecture().to_string(), variant)
     }
 }
 
@@ -121,34 +110,6 @@ mod tests {
         assert!(matcher.matches_str("Linux", "Amd64", "unknown"));
     }
 
-    #[test]
-    fn test_oci_client_matcher() {
-        let platform = OciClientPlatform {
-            os: "Linux".to_string(),
-            architecture: "arm64".to_string(),
-            os_version: None,
-            os_features: None,
-            variant: Some("v8".to_string()),
-            features: None,
-        };
-
-        let platform_unknown = OciClientPlatform {
-            os: "Unknown".to_string(),
-            architecture: "Unknown".to_string(),
-            os_version: None,
-            os_features: None,
-            variant: Some("Unknown".to_string()),
-            features: None,
-        };
-
-        for pattern in ["linux/*", "linux/arm64", "linux/arm64/v8", "*/arm64/v8"] {
-            let matcher = PlatformMatcher::from_glob(Glob::new(pattern).unwrap()).unwrap();
-            assert!(matcher.matches_oci_client_platform(Some(&platform)));
-            assert!(!matcher.matches_oci_client_platform(Some(&platform_unknown)));
-            assert!(matcher.matches_oci_client_platform(None));
-        }
-    }
-
     #[test]
     fn test_oci_spec_platform() {
         let platform = PlatformBuilder::default()
@@ -0,0 +1,251 @@
+use crate::registry::USER_AGENT;
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use reqwest::RequestBuilder;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use tracing::debug;
+
+/// Credentials for a registry, as configured by the user
+#[derive(Clone, Eq, PartialEq)]
+pub enum Credentials {
+    Anonymous,
+    Basic(String, String),
+    /// A refresh token, exchanged for access tokens with the OAuth2 `refresh_token` grant
+    IdentityToken(String),
+}
+
+impl Credentials {
+    /// Parses credentials given as `username:password`, or as an identity token without a username
+    pub fn parse(value: &str) -> Self {
+        match value.split_once(':') {
+            Some((username, password)) => Credentials::Basic(username.to_string(), password.to_string()),
+            None => Credentials::IdentityToken(value.to_string()),
+        }
+    }
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Anonymous => write!(f, "Anonymous"),
+            Credentials::Basic(username, _) => write!(f, "Basic({username}, <redacted>)"),
+            Credentials::IdentityToken(_) => write!(f, "IdentityToken(<redacted>)"),
+        }
+    }
+}
+
+/// Credentials attached to every request made to a registry, obtained by answering a `WWW-Authenticate` challenge.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Authorization {
+    Basic(String, String),
+    Bearer(String),
+}
+
+impl Authorization {
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Authorization::Basic(username, password) => request.basic_auth(username, Some(password)),
+            Authorization::Bearer(token) => request.bearer_auth(token),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Challenge {
+    Basic,
+    Bearer { realm: String, service: Option<String> },
+}
+
+fn parse_challenge(value: &str) -> Option<Challenge> {
+    let value = value.trim();
+    let (scheme, params) = value.split_once(' ').unwrap_or((value, ""));
+    if scheme.eq_ignore_ascii_case("basic") {
+        return Some(Challenge::Basic);
+    }
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut params = parse_params(params);
+    let realm = params.remove("realm")?;
+    let service = params.remove("service");
+    Some(Challenge::Bearer { realm, service })
+}
+
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = input.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim_start();
+        // Quoted values can contain commas, e.g. `scope="repository:foo:pull,push"`
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        params.insert(key, value.to_string());
+        rest = remaining.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+/// Answers a `WWW-Authenticate` challenge, requesting a bearer token covering all of the given scopes if needed.
+pub async fn authorize(
+    http: &reqwest::Client,
+    challenge: &str,
+    auth: &Credentials,
+    scopes: &[String],
+) -> anyhow::Result<Authorization> {
+    match parse_challenge(challenge) {
+        Some(Challenge::Basic) => match auth {
+            Credentials::Basic(username, password) => Ok(Authorization::Basic(username.clone(), password.clone())),
+            Credentials::IdentityToken(_) => {
+                bail!("Registry requires basic authentication, but only an identity token was found")
+            }
+            Credentials::Anonymous => bail!("Registry requires basic authentication, but no credentials were found"),
+        },
+        Some(Challenge::Bearer { realm, service }) => {
+            let request = match auth {
+                Credentials::IdentityToken(refresh_token) => {
+                    // The OAuth2 token exchange, as used by `docker login` with identity tokens
+                    let scope = scopes.join(" ");
+                    let mut form = vec![
+                        ("grant_type", "refresh_token"),
+                        ("refresh_token", refresh_token.as_str()),
+                        ("client_id", USER_AGENT),
+                        ("scope", scope.as_str()),
+                    ];
+                    if let Some(service) = &service {
+                        form.push(("service", service));
+                    }
+                    debug!("Exchanging identity token at {realm} for {scopes:?}");
+                    http.post(&realm).form(&form)
+                }
+                auth => {
+                    let mut query = scopes.iter().map(|scope| ("scope", scope.as_str())).collect_vec();
+                    if let Some(service) = &service {
+                        query.push(("service", service));
+                    }
+                    debug!("Requesting token from {realm} for {query:?}");
+                    let request = http.get(&realm).query(&query);
+                    match auth {
+                        Credentials::Basic(username, password) => request.basic_auth(username, Some(password)),
+                        _ => request,
+                    }
+                }
+            };
+            let response = request
+                .send()
+                .await
+                .with_context(|| format!("Requesting token from {realm}"))?;
+            let status = response.status();
+            if !status.is_success() {
+                let body = response.text().await.unwrap_or_default();
+                bail!("Token request to {realm} failed with {status}: {body}");
+            }
+            let body: serde_json::Value = response.json().await.context("Parsing token response")?;
+            let token = body
+                .get("token")
+                .or_else(|| body.get("access_token"))
+                .and_then(|token| token.as_str())
+                .with_context(|| format!("Token response from {realm} did not contain a token"))?;
+            Ok(Authorization::Bearer(token.to_string()))
+        }
+        None => bail!("Unsupported authentication challenge: {challenge}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{serve_http, TestRequest, TestResponse};
+
+    #[test]
+    fn test_parse_challenge() {
+        assert_eq!(parse_challenge("Basic realm=\"Registry\""), Some(Challenge::Basic));
+        assert_eq!(
+            parse_challenge(
+                "Bearer realm=\"https://auth.docker.io/token\",service=\"registry.docker.io\",scope=\"repository:library/alpine:pull,push\""
+            ),
+            Some(Challenge::Bearer {
+                realm: "https://auth.docker.io/token".to_string(),
+                service: Some("registry.docker.io".to_string())
+            })
+        );
+        assert_eq!(
+            parse_challenge("bearer realm=https://example.com/token"),
+            Some(Challenge::Bearer {
+                realm: "https://example.com/token".to_string(),
+                service: None
+            })
+        );
+        assert_eq!(parse_challenge("Negotiate"), None);
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        assert_eq!(
+            Credentials::parse("user:pass:word"),
+            Credentials::Basic("user".to_string(), "pass:word".to_string())
+        );
+        assert_eq!(
+            Credentials::parse("eyJhbGciOi"),
+            Credentials::IdentityToken("eyJhbGciOi".to_string())
+        );
+        assert_eq!(
+            format!("{:?}", Credentials::parse("user:secret")),
+            "Basic(user, <redacted>)"
+        );
+    }
+
+    fn form_value(request: &TestRequest, key: &str) -> Option<String> {
+        let body = String::from_utf8_lossy(&request.body);
+        reqwest::Url::parse(&format!("http://localhost/?{body}"))
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_identity_token_exchange() {
+        let address = serve_http(|request| {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/oauth2/token");
+            assert_eq!(form_value(&request, "grant_type").as_deref(), Some("refresh_token"));
+            assert_eq!(form_value(&request, "service").as_deref(), Some("test"));
+            assert_eq!(
+                form_value(&request, "scope").as_deref(),
+                Some("repository:a:pull repository:b:pull,push")
+            );
+            match form_value(&request, "refresh_token").as_deref() {
+                Some("refresh") => TestResponse::new(200).body(r#"{"access_token": "access"}"#),
+                _ => TestResponse::new(401).body("invalid refresh token"),
+            }
+        })
+        .await;
+        let challenge = format!("Bearer realm=\"http://{address}/oauth2/token\",service=\"test\"");
+        let scopes = ["repository:a:pull".to_string(), "repository:b:pull,push".to_string()];
+        let http = reqwest::Client::new();
+
+        let authorization = authorize(
+            &http,
+            &challenge,
+            &Credentials::IdentityToken("refresh".to_string()),
+            &scopes,
+        )
+        .await
+        .unwrap();
+        assert_eq!(authorization, Authorization::Bearer("access".to_string()));
+
+        let error = authorize(
+            &http,
+            &challenge,
+            &Credentials::IdentityToken("expired".to_string()),
+            &scopes,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("invalid refresh token"), "{error}");
+    }
+}
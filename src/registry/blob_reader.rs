@@ -0,0 +1,256 @@
+use crate::registry::rate_limit::backoff;
+use crate::registry::{is_transient_status, RegistryClient};
+use futures_util::TryStreamExt;
+use oci_spec::image::Digest;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use std::io::{ErrorKind, Read};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, BufReader};
+use tokio::runtime::Handle;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{debug, warn};
+
+pub const DEFAULT_BLOB_RETRIES: usize = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const READ_BUFFER_SIZE: usize = 5 * 1024 * 1024; // 5 mb
+
+type BlobStream = SyncIoBridge<BufReader<Box<dyn AsyncRead + Send + Unpin>>>;
+
+/// Reads a blob from a registry in synchronous code. If the connection fails partway through, the download is
+/// resumed from the last byte received with a `Range` request, after a jittered delay that doubles with every
+/// consecutive failure. Readers of the blob only see an error once `max_retries` consecutive attempts have failed.
+pub struct BlobReader {
+    client: Arc<RegistryClient>,
+    repository: String,
+    digest: Digest,
+    size: Option<u64>,
+    handle: Handle,
+    max_retries: usize,
+    stream: Option<BlobStream>,
+    offset: u64,
+    failures: usize,
+}
+
+impl BlobReader {
+    pub fn new(
+        client: Arc<RegistryClient>,
+        repository: &str,
+        digest: Digest,
+        size: Option<u64>,
+        handle: Handle,
+        max_retries: usize,
+    ) -> Self {
+        Self {
+            client,
+            repository: repository.to_string(),
+            digest,
+            size,
+            handle,
+            max_retries,
+            stream: None,
+            offset: 0,
+            failures: 0,
+        }
+    }
+
+    fn connect(&mut self) -> std::io::Result<BlobStream> {
+        let url = self
+            .client
+            .blob_url(&self.repository, &self.digest)
+            .map_err(std::io::Error::other)?;
+        let offset = self.offset;
+        debug!("Fetching blob {} from byte {}", self.digest, offset);
+        let response = self
+            .handle
+            .block_on(self.client.send(|http| {
+                let request = http.get(url.clone());
+                if offset > 0 {
+                    request.header(RANGE, format!("bytes={offset}-"))
+                } else {
+                    request
+                }
+            }))
+            .map_err(std::io::Error::other)?;
+        let status = response.status();
+        // Registries that do not support ranges send the whole blob again, so skip to where we were
+        let skip = match status {
+            StatusCode::PARTIAL_CONTENT => 0,
+            StatusCode::OK => offset,
+            status => {
+                let kind = if is_transient_status(status) {
+                    ErrorKind::Other
+                } else {
+                    ErrorKind::InvalidInput
+                };
+                return Err(std::io::Error::new(
+                    kind,
+                    format!("Fetching blob {} failed with {status}", self.digest),
+                ));
+            }
+        };
+        let stream = response.bytes_stream().map_err(std::io::Error::other);
+        let reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(StreamReader::new(stream));
+        let mut stream =
+            SyncIoBridge::new_with_handle(BufReader::with_capacity(READ_BUFFER_SIZE, reader), self.handle.clone());
+        if skip > 0 {
+            let skipped = std::io::copy(&mut (&mut stream).take(skip), &mut std::io::sink())?;
+            if skipped != skip {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Drops the connection and waits before the next attempt, or returns the error if there have been too many
+    fn retry(&mut self, error: std::io::Error) -> std::io::Result<()> {
+        self.stream = None;
+        if error.kind() == ErrorKind::InvalidInput {
+            return Err(error);
+        }
+        self.failures += 1;
+        if self.failures > self.max_retries {
+            return Err(std::io::Error::new(
+                error.kind(),
+                format!(
+                    "Downloading blob {} failed after {} attempts: {error}",
+                    self.digest, self.failures
+                ),
+            ));
+        }
+        let delay = backoff(RETRY_DELAY, self.failures);
+        warn!(
+            "Downloading blob {} failed at byte {}, resuming in {delay:?}: {error}",
+            self.digest, self.offset
+        );
+        std::thread::sleep(delay);
+        Ok(())
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => match self.connect() {
+                    Ok(stream) => self.stream.insert(stream),
+                    Err(e) => {
+                        self.retry(e)?;
+                        continue;
+                    }
+                },
+            };
+            match stream.read(buf) {
+                Ok(0) if !buf.is_empty() && self.size.is_some_and(|size| self.offset < size) => {
+                    let error = std::io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("Connection closed after {} bytes", self.offset),
+                    );
+                    self.retry(error)?;
+                }
+                Ok(read) => {
+                    if read > 0 {
+                        self.offset += read as u64;
+                        self.failures = 0;
+                    }
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => self.retry(e)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::auth::Credentials;
+    use crate::registry::config::RegistryConfig;
+    use crate::registry::pull_scope;
+    use crate::test_utils::{serve_http, TestResponse};
+    use oci_client::Reference;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use tokio::runtime::Runtime;
+
+    const CONTENT: &[u8] = b"the content of a large layer";
+    const DIGEST: &str = "sha256:0d90d93a5cab3fd2879040420c7b7e4958aee8997fef78e9a5dd80cb01f3bd9c";
+
+    fn reader_for(
+        runtime: &Runtime,
+        handler: impl Fn(usize, Option<&str>) -> TestResponse + Send + Sync + 'static,
+    ) -> BlobReader {
+        let requests = Mutex::new(0);
+        let address = runtime.block_on(serve_http(move |request| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            handler(*requests, request.header("range"))
+        }));
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        let client = RegistryClient::new(
+            &reference,
+            Credentials::Anonymous,
+            vec![pull_scope("test/image")],
+            &RegistryConfig::default(),
+        )
+        .unwrap();
+        let digest = Digest::from_str(DIGEST).unwrap();
+        BlobReader::new(
+            Arc::new(client),
+            "test/image",
+            digest,
+            Some(CONTENT.len() as u64),
+            runtime.handle().clone(),
+            2,
+        )
+    }
+
+    /// The first response is cut off after 10 bytes
+    fn interrupted_response(request: usize) -> Option<TestResponse> {
+        (request == 1).then(|| {
+            TestResponse::new(200)
+                .header("Content-Length", CONTENT.len())
+                .body(&CONTENT[..10])
+        })
+    }
+
+    #[test]
+    fn test_resume_with_range() {
+        let runtime = Runtime::new().unwrap();
+        let mut reader = reader_for(&runtime, |request, range| {
+            interrupted_response(request).unwrap_or_else(|| {
+                assert_eq!(range, Some("bytes=10-"));
+                TestResponse::new(206).body(&CONTENT[10..])
+            })
+        });
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(content, CONTENT);
+    }
+
+    #[test]
+    fn test_resume_without_range_support() {
+        let runtime = Runtime::new().unwrap();
+        let mut reader = reader_for(&runtime, |request, _| {
+            interrupted_response(request).unwrap_or_else(|| TestResponse::new(200).body(CONTENT))
+        });
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(content, CONTENT);
+    }
+
+    #[test]
+    fn test_retry_limit() {
+        let runtime = Runtime::new().unwrap();
+        let mut reader = reader_for(&runtime, |_, _| TestResponse::new(503));
+        let error = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert!(error.to_string().contains("failed after 3 attempts"), "{error}");
+
+        let mut reader = reader_for(&runtime, |_, _| TestResponse::new(404));
+        let error = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert!(error.to_string().contains("404"), "{error}");
+    }
+}
@@ -0,0 +1,118 @@
+use crate::registry::auth::Credentials;
+use docker_credential::{CredentialRetrievalError, DockerCredential};
+use oci_client::Reference;
+use strum::{Display, EnumString};
+use tracing::{debug, instrument, warn};
+
+/// Credentials for both the source and destination registries, as `username:password` or an identity token
+pub const REGISTRY_AUTH_ENV: &str = "DOCKER_REPACK_REGISTRY_AUTH";
+/// Credentials for the source registry only, taking precedence over `DOCKER_REPACK_REGISTRY_AUTH`
+pub const SOURCE_AUTH_ENV: &str = "DOCKER_REPACK_SOURCE_AUTH";
+/// Credentials for the destination registry only, taking precedence over `DOCKER_REPACK_REGISTRY_AUTH`
+pub const DESTINATION_AUTH_ENV: &str = "DOCKER_REPACK_DESTINATION_AUTH";
+
+/// Whether a registry is being pulled from or pushed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum RegistryRole {
+    Source,
+    Destination,
+}
+
+impl RegistryRole {
+    fn env(self) -> &'static str {
+        match self {
+            RegistryRole::Source => SOURCE_AUTH_ENV,
+            RegistryRole::Destination => DESTINATION_AUTH_ENV,
+        }
+    }
+}
+
+/// Finds the credentials for a registry. Explicitly given credentials win, followed by the environment variables for
+/// the role and then for both roles, and finally the Docker and Podman credential stores.
+#[instrument(skip_all, fields(reference = %reference, role = %role))]
+pub fn find_credentials(reference: &Reference, role: RegistryRole, explicit: Option<&Credentials>) -> Credentials {
+    if let Some(credentials) = explicit {
+        debug!("Using credentials given on the command line");
+        return credentials.clone();
+    }
+    if let Some(credentials) = credentials_from_env(role, |name| std::env::var(name).ok()) {
+        return credentials;
+    }
+    credentials_from_stores(reference)
+}
+
+fn credentials_from_env(role: RegistryRole, lookup: impl Fn(&str) -> Option<String>) -> Option<Credentials> {
+    [role.env(), REGISTRY_AUTH_ENV].into_iter().find_map(|name| {
+        let value = lookup(name).filter(|value| !value.is_empty())?;
+        debug!("Using credentials from {name}");
+        Some(Credentials::parse(&value))
+    })
+}
+
+fn credentials_from_stores(reference: &Reference) -> Credentials {
+    let server = reference
+        .resolve_registry()
+        .strip_suffix('/')
+        .unwrap_or_else(|| reference.resolve_registry());
+
+    let auth_results = [
+        ("docker", docker_credential::get_credential(server)),
+        ("podman", docker_credential::get_podman_credential(server)),
+    ];
+
+    for (name, cred_result) in auth_results.into_iter() {
+        match cred_result {
+            Err(e) => match e {
+                CredentialRetrievalError::HelperFailure { stdout, stderr } => {
+                    let base_message =
+                        "Credential helper returned non-zero response code, falling back to anonymous auth";
+                    if !stderr.is_empty() || !stdout.is_empty() {
+                        let extra = [stdout.trim(), stderr.trim()].join(" - ");
+                        warn!("{name}: {base_message}: stdout/stderr = {extra}");
+                    } else {
+                        warn!("{name}: {base_message}");
+                    };
+                }
+                e => {
+                    debug!("{name}: {e}");
+                }
+            },
+            Ok(DockerCredential::UsernamePassword(username, password)) => {
+                debug!("{name}: Found docker credentials");
+                return Credentials::Basic(username, password);
+            }
+            Ok(DockerCredential::IdentityToken(token)) => {
+                debug!("{name}: Found identity token");
+                return Credentials::IdentityToken(token);
+            }
+        };
+    }
+    debug!("No credentials found, using anonymous auth");
+    Credentials::Anonymous
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_credentials_from_env() {
+        let env = HashMap::from([
+            (REGISTRY_AUTH_ENV, "shared:password"),
+            (DESTINATION_AUTH_ENV, "identity-token"),
+            (SOURCE_AUTH_ENV, ""),
+        ]);
+        let lookup = |name: &str| env.get(name).map(|value| value.to_string());
+        assert_eq!(
+            credentials_from_env(RegistryRole::Source, lookup),
+            Some(Credentials::Basic("shared".to_string(), "password".to_string()))
+        );
+        assert_eq!(
+            credentials_from_env(RegistryRole::Destination, lookup),
+            Some(Credentials::IdentityToken("identity-token".to_string()))
+        );
+        assert_eq!(credentials_from_env(RegistryRole::Source, |_| None), None);
+    }
+}
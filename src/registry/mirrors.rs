@@ -0,0 +1,308 @@
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use oci_client::Reference;
+use std::path::Path;
+use std::str::FromStr;
+use strum::EnumString;
+use toml_edit::{DocumentMut, Item, Table};
+
+/// Which references a mirror is used for, as in the `pull-from-mirror` option of `registries.conf`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum PullFromMirror {
+    #[default]
+    All,
+    DigestOnly,
+    TagOnly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    pub location: String,
+    pub insecure: bool,
+    pub pull_from: PullFromMirror,
+}
+
+/// A `[[registry]]` table of `registries.conf`: references under `prefix` are pulled from the mirrors in order, then
+/// from `location`, which defaults to the prefix itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryRule {
+    pub prefix: String,
+    pub location: Option<String>,
+    pub insecure: bool,
+    pub blocked: bool,
+    pub mirrors: Vec<Mirror>,
+}
+
+/// A reference to try pulling an image from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub reference: Reference,
+    /// The registry was marked insecure, so its TLS certificate is not verified
+    pub insecure: bool,
+}
+
+/// Rules rewriting references to mirrors, in the format of containers' `registries.conf`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorRules {
+    rules: Vec<RegistryRule>,
+}
+
+fn get_str(table: &Table, key: &str) -> anyhow::Result<Option<String>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match item.as_str() {
+            Some(value) => Ok(Some(value.trim_end_matches('/').to_string())),
+            None => bail!("`{key}` must be a string"),
+        },
+    }
+}
+
+fn get_bool(table: &Table, key: &str) -> anyhow::Result<bool> {
+    match table.get(key) {
+        None => Ok(false),
+        Some(item) => item.as_bool().with_context(|| format!("`{key}` must be a boolean")),
+    }
+}
+
+fn tables<'a>(table: &'a Table, key: &str) -> anyhow::Result<Vec<&'a Table>> {
+    match table.get(key) {
+        None => Ok(vec![]),
+        Some(Item::ArrayOfTables(tables)) => Ok(tables.iter().collect()),
+        Some(_) => bail!("`{key}` must be an array of tables, e.g. [[{key}]]"),
+    }
+}
+
+/// The repository name including its registry, e.g. `docker.io/library/alpine`
+fn full_name(reference: &Reference) -> String {
+    format!("{}/{}", reference.registry(), reference.repository())
+}
+
+/// Whether `name` is `prefix`, or is in a namespace below it
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl MirrorRules {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let document: DocumentMut = content.parse().context("Parsing registries.conf")?;
+        let mut rules = Self::default();
+        for registry in tables(document.as_table(), "registry")? {
+            let location = get_str(registry, "location")?;
+            let prefix = match get_str(registry, "prefix")? {
+                Some(prefix) => prefix,
+                None => location
+                    .clone()
+                    .context("[[registry]] needs a `prefix` or a `location`")?,
+            };
+            let digest_only = get_bool(registry, "mirror-by-digest-only")?;
+            let mirrors = tables(registry, "mirror")?
+                .into_iter()
+                .map(|mirror| {
+                    let location = get_str(mirror, "location")?.context("[[registry.mirror]] needs a `location`")?;
+                    let pull_from = match get_str(mirror, "pull-from-mirror")? {
+                        Some(value) => PullFromMirror::from_str(&value)
+                            .with_context(|| format!("Unknown pull-from-mirror value {value}"))?,
+                        None if digest_only => PullFromMirror::DigestOnly,
+                        None => PullFromMirror::All,
+                    };
+                    Ok(Mirror {
+                        location,
+                        insecure: get_bool(mirror, "insecure")?,
+                        pull_from,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Reading mirrors of {prefix}"))?;
+            rules.rules.push(RegistryRule {
+                location: location.filter(|location| *location != prefix),
+                prefix,
+                insecure: get_bool(registry, "insecure")?,
+                blocked: get_bool(registry, "blocked")?,
+                mirrors,
+            });
+        }
+        Ok(rules)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Reading mirror rules from {}", path.display()))
+    }
+
+    /// Adds a mirror given as `prefix=mirror`, after any mirrors already configured for the prefix
+    pub fn add_mirror(&mut self, value: &str) -> anyhow::Result<()> {
+        let Some((prefix, location)) = value.split_once('=') else {
+            bail!("Invalid mirror {value}, expected PREFIX=MIRROR, e.g. docker.io=mirror.example.com:5000");
+        };
+        let prefix = prefix.trim_end_matches('/').to_string();
+        let mirror = Mirror {
+            location: location.trim_end_matches('/').to_string(),
+            insecure: false,
+            pull_from: PullFromMirror::All,
+        };
+        match self.rules.iter_mut().find(|rule| rule.prefix == prefix) {
+            Some(rule) => rule.mirrors.push(mirror),
+            None => self.rules.push(RegistryRule {
+                prefix,
+                location: None,
+                insecure: false,
+                blocked: false,
+                mirrors: vec![mirror],
+            }),
+        }
+        Ok(())
+    }
+
+    /// Returns the references to try pulling `reference` from, in order: each mirror, and then the upstream registry
+    pub fn candidates(&self, reference: &Reference) -> anyhow::Result<Vec<Candidate>> {
+        let name = full_name(reference);
+        // The most specific prefix wins, as in registries.conf
+        let Some(rule) = self
+            .rules
+            .iter()
+            .filter(|rule| has_prefix(&name, &rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+        else {
+            return Ok(vec![Candidate {
+                reference: reference.clone(),
+                insecure: false,
+            }]);
+        };
+        if rule.blocked {
+            bail!("Pulling from {} is blocked by the mirror rules", rule.prefix);
+        }
+        let rewrite = |location: &str| -> anyhow::Result<Reference> {
+            let mut rewritten = format!("{location}{}", &name[rule.prefix.len()..]);
+            if let Some(tag) = reference.tag() {
+                rewritten.push_str(&format!(":{tag}"));
+            }
+            if let Some(digest) = reference.digest() {
+                rewritten.push_str(&format!("@{digest}"));
+            }
+            Reference::from_str(&rewritten).with_context(|| format!("Invalid mirror reference {rewritten}"))
+        };
+        let mut candidates = rule
+            .mirrors
+            .iter()
+            .filter(|mirror| match mirror.pull_from {
+                PullFromMirror::All => true,
+                PullFromMirror::DigestOnly => reference.digest().is_some(),
+                PullFromMirror::TagOnly => reference.digest().is_none(),
+            })
+            .map(|mirror| {
+                Ok(Candidate {
+                    reference: rewrite(&mirror.location)?,
+                    insecure: mirror.insecure,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        candidates.push(Candidate {
+            reference: rewrite(rule.location.as_deref().unwrap_or(&rule.prefix))?,
+            insecure: rule.insecure,
+        });
+        Ok(candidates
+            .into_iter()
+            .unique_by(|candidate| candidate.reference.whole())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTRIES_CONF: &str = r#"
+unqualified-search-registries = ["docker.io"]
+
+[[registry]]
+prefix = "docker.io"
+location = "docker.io"
+
+[[registry.mirror]]
+location = "mirror.local:5000/dockerhub"
+insecure = true
+
+[[registry.mirror]]
+location = "pinned.local"
+pull-from-mirror = "digest-only"
+
+[[registry]]
+prefix = "docker.io/internal"
+location = "registry.internal/images"
+
+[[registry]]
+location = "blocked.example.com"
+blocked = true
+"#;
+
+    fn candidates(rules: &MirrorRules, reference: &str) -> Vec<(String, bool)> {
+        rules
+            .candidates(&Reference::from_str(reference).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|candidate| (candidate.reference.whole(), candidate.insecure))
+            .collect()
+    }
+
+    #[test]
+    fn test_registries_conf() {
+        let rules = MirrorRules::parse(REGISTRIES_CONF).unwrap();
+        assert_eq!(
+            candidates(&rules, "alpine:3.20"),
+            [
+                ("mirror.local:5000/dockerhub/library/alpine:3.20".to_string(), true),
+                ("docker.io/library/alpine:3.20".to_string(), false),
+            ]
+        );
+        let digest = "sha256:0d90d93a5cab3fd2879040420c7b7e4958aee8997fef78e9a5dd80cb01f3bd9c";
+        assert_eq!(
+            candidates(&rules, &format!("alpine@{digest}"))
+                .into_iter()
+                .map(|(reference, _)| reference)
+                .collect_vec(),
+            [
+                format!("mirror.local:5000/dockerhub/library/alpine@{digest}"),
+                format!("pinned.local/library/alpine@{digest}"),
+                format!("docker.io/library/alpine@{digest}"),
+            ]
+        );
+        assert_eq!(
+            candidates(&rules, "internal/app:1"),
+            [("registry.internal/images/app:1".to_string(), false)]
+        );
+        assert_eq!(
+            candidates(&rules, "ghcr.io/org/app:1"),
+            [("ghcr.io/org/app:1".to_string(), false)]
+        );
+        // Prefixes only match whole path components
+        assert_eq!(
+            candidates(&rules, "docker.io/internalfoo/app:1"),
+            [
+                ("mirror.local:5000/dockerhub/internalfoo/app:1".to_string(), true),
+                ("docker.io/internalfoo/app:1".to_string(), false),
+            ]
+        );
+        let error = rules
+            .candidates(&Reference::from_str("blocked.example.com/app:1").unwrap())
+            .unwrap_err();
+        assert!(error.to_string().contains("blocked"), "{error}");
+    }
+
+    #[test]
+    fn test_add_mirror() {
+        let mut rules = MirrorRules::default();
+        rules.add_mirror("docker.io=first.local").unwrap();
+        rules.add_mirror("docker.io/=second.local/hub/").unwrap();
+        assert!(rules.add_mirror("docker.io").is_err());
+        assert_eq!(
+            candidates(&rules, "alpine"),
+            [
+                ("first.local/library/alpine:latest".to_string(), false),
+                ("second.local/hub/library/alpine:latest".to_string(), false),
+                ("docker.io/library/alpine:latest".to_string(), false),
+            ]
+        );
+    }
+}
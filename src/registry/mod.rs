@@ -0,0 +1,626 @@
+use crate::registry::auth::{authorize, Authorization, Credentials};
+use crate::registry::config::RegistryConfig;
+use crate::registry::rate_limit::{backoff, retry_after, RateLimit, MAX_RATE_LIMIT_RETRIES, MAX_RETRY_AFTER};
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use oci_client::Reference;
+use oci_spec::image::Digest;
+use reqwest::header::{ACCEPT, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE, WWW_AUTHENTICATE};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+pub mod auth;
+pub mod blob_reader;
+pub mod config;
+pub mod credentials;
+pub mod mirrors;
+pub mod rate_limit;
+
+pub const USER_AGENT: &str = concat!("docker-repack/", env!("CARGO_PKG_VERSION"));
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024; // 16 mb
+const UPLOAD_CHUNK_RETRIES: usize = 5;
+const UPLOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(1);
+/// Warn when fewer requests than this remain in the registry's rate limit
+const LOW_RATE_LIMIT: u64 = 10;
+
+pub fn pull_scope(repository: &str) -> String {
+    format!("repository:{repository}:pull")
+}
+
+pub fn push_scope(repository: &str) -> String {
+    format!("repository:{repository}:pull,push")
+}
+
+/// Registries on the loopback interface are spoken to over plain HTTP, matching the Docker daemon's defaults.
+pub fn is_local_registry(registry: &str) -> bool {
+    let host = match registry.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']').map(|(host, _)| host).unwrap_or(bracketed),
+        None => registry.rsplit_once(':').map(|(host, _)| host).unwrap_or(registry),
+    };
+    host.eq_ignore_ascii_case("localhost") || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Server errors and rate limiting may go away if the request is tried again
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+pub enum MountResult {
+    Mounted,
+    Upload(Url),
+}
+
+/// A minimal OCI distribution API client for a single registry. Authorization is negotiated lazily: requests are
+/// sent with the current credentials, and a `401` response is answered by requesting a token for `scopes`.
+///
+/// This is used instead of `oci_client::Client`, which cannot resume a chunked upload, returns no upload session
+/// from a failed mount, and has no way to send identity tokens, client certificates or a per-registry proxy. Only its
+/// `Reference` and manifest types are used.
+pub struct RegistryClient {
+    http: reqwest::Client,
+    base_url: Url,
+    auth: Credentials,
+    scopes: Vec<String>,
+    authorization: RwLock<Option<Authorization>>,
+    rate_limit: Mutex<Option<RateLimit>>,
+}
+
+impl Display for RegistryClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RegistryClient {}", self.base_url)
+    }
+}
+
+impl RegistryClient {
+    pub fn new(
+        reference: &Reference,
+        auth: Credentials,
+        scopes: Vec<String>,
+        config: &RegistryConfig,
+    ) -> anyhow::Result<Self> {
+        let registry = reference.resolve_registry();
+        let scheme = if config.use_plain_http(registry) {
+            "http"
+        } else {
+            "https"
+        };
+        let base_url = Url::parse(&format!("{scheme}://{registry}/"))
+            .with_context(|| format!("Invalid registry address {registry}"))?;
+        let http = config.http_client(registry)?;
+        Ok(Self {
+            http,
+            base_url,
+            auth,
+            scopes,
+            authorization: RwLock::new(None),
+            rate_limit: Mutex::new(None),
+        })
+    }
+
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        self.base_url
+            .join(path)
+            .with_context(|| format!("Invalid registry path {path}"))
+    }
+
+    pub fn blob_url(&self, repository: &str, digest: &Digest) -> anyhow::Result<Url> {
+        self.url(&format!("v2/{repository}/blobs/{digest}"))
+    }
+
+    pub fn manifest_url(&self, repository: &str, reference: &str) -> anyhow::Result<Url> {
+        self.url(&format!("v2/{repository}/manifests/{reference}"))
+    }
+
+    fn upload_url(&self, repository: &str) -> anyhow::Result<Url> {
+        self.url(&format!("v2/{repository}/blobs/uploads/"))
+    }
+
+    fn location(&self, response: &Response) -> anyhow::Result<Url> {
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .context("Registry response did not include a Location header")?
+            .to_str()?;
+        // Locations may be relative to the registry, or absolute
+        self.url(location)
+    }
+
+    /// Sends a request built by `request`, re-authorizing and re-sending it once if the registry responds with
+    /// `401 Unauthorized`. Rate limited requests are re-sent after the delay the registry asks for in `Retry-After`,
+    /// or with a jittered backoff if it does not say.
+    pub async fn send(&self, request: impl Fn(&reqwest::Client) -> RequestBuilder) -> anyhow::Result<Response> {
+        let mut attempts = 0;
+        loop {
+            let response = self.send_authorized(&request).await?;
+            self.record_rate_limit(&response);
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || attempts == MAX_RATE_LIMIT_RETRIES {
+                return Ok(response);
+            }
+            attempts += 1;
+            let delay = match retry_after(response.headers()) {
+                Some(delay) if delay > MAX_RETRY_AFTER => {
+                    warn!(
+                        "{} asked us to wait {delay:?} before trying again, giving up",
+                        self.base_url
+                    );
+                    return Ok(response);
+                }
+                Some(delay) => delay,
+                None => backoff(RATE_LIMIT_DELAY, attempts),
+            };
+            warn!("Rate limited by {}, trying again in {delay:#.1?}", self.base_url);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn send_authorized(&self, request: &impl Fn(&reqwest::Client) -> RequestBuilder) -> anyhow::Result<Response> {
+        let authorization = self.authorization.read().await.clone();
+        let response = with_authorization(request(&self.http), authorization.as_ref())
+            .send()
+            .await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let Some(challenge) = response.headers().get(WWW_AUTHENTICATE) else {
+            return Ok(response);
+        };
+        let challenge = challenge.to_str()?.to_string();
+        debug!("Authorizing with {}: {}", self.base_url, challenge);
+        let authorization = authorize(&self.http, &challenge, &self.auth, &self.scopes)
+            .await
+            .with_context(|| format!("Authorizing with {}", self.base_url))?;
+        *self.authorization.write().await = Some(authorization.clone());
+        Ok(with_authorization(request(&self.http), Some(&authorization))
+            .send()
+            .await?)
+    }
+
+    /// Keeps track of the pull quota reported by the registry, warning when it is about to run out
+    fn record_rate_limit(&self, response: &Response) {
+        let Some(rate_limit) = RateLimit::from_headers(response.headers()) else {
+            return;
+        };
+        let previous = self.rate_limit.lock().unwrap().replace(rate_limit);
+        trace!("{} rate limit: {rate_limit:?}", self.base_url);
+        let changed = previous.is_none_or(|previous| previous.remaining != rate_limit.remaining);
+        if changed && rate_limit.remaining < LOW_RATE_LIMIT {
+            warn!(
+                "Only {} requests to {} remain before being rate limited{}",
+                rate_limit.remaining,
+                self.base_url,
+                rate_limit
+                    .window
+                    .map(|window| format!(" (the limit resets within {window:?})"))
+                    .unwrap_or_default()
+            );
+        }
+    }
+
+    /// The last pull quota reported by the registry, if it reports one
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        *self.rate_limit.lock().unwrap()
+    }
+
+    /// Fetches a manifest by tag or digest, accepting any of `media_types`. Returns the manifest and the media type
+    /// the registry reported for it, if any.
+    pub async fn get_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        media_types: &[&str],
+    ) -> anyhow::Result<(Bytes, Option<String>)> {
+        let url = self.manifest_url(repository, reference)?;
+        let accept = media_types.join(", ");
+        let response = self.send(|http| http.get(url.clone()).header(ACCEPT, &accept)).await?;
+        match response.status() {
+            StatusCode::OK => {
+                let media_type = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.split(';').next())
+                    .map(|value| value.trim().to_string());
+                Ok((response.bytes().await?, media_type))
+            }
+            status => Err(error_for_response(response, status, &format!("Fetching manifest {reference}")).await),
+        }
+    }
+
+    /// Starts downloading a blob. The body of the returned response is the blob content.
+    pub async fn get_blob(&self, repository: &str, digest: &Digest) -> anyhow::Result<Response> {
+        let url = self.blob_url(repository, digest)?;
+        let response = self.send(|http| http.get(url.clone())).await?;
+        match response.status() {
+            StatusCode::OK => Ok(response),
+            status => Err(error_for_response(response, status, &format!("Fetching blob {digest}")).await),
+        }
+    }
+
+    /// Returns the size of the blob if the registry already has it.
+    pub async fn blob_size(&self, repository: &str, digest: &Digest) -> anyhow::Result<Option<u64>> {
+        let url = self.blob_url(repository, digest)?;
+        let response = self.send(|http| http.head(url.clone())).await?;
+        match response.status() {
+            // The body of a HEAD response is always empty, so read the size from the header directly
+            StatusCode::OK => Ok(response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok()?.parse().ok())
+                .or(Some(0))),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => bail!("Checking for blob {digest} failed with {status}"),
+        }
+    }
+
+    /// Attempts to mount a blob from another repository on the same registry. Registries that cannot mount the
+    /// blob start a regular upload session instead.
+    pub async fn mount_blob(&self, repository: &str, digest: &Digest, from: &str) -> anyhow::Result<MountResult> {
+        let mut url = self.upload_url(repository)?;
+        url.query_pairs_mut()
+            .append_pair("mount", &digest.to_string())
+            .append_pair("from", from);
+        let response = self
+            .send(|http| http.post(url.clone()).header(CONTENT_LENGTH, 0))
+            .await?;
+        match response.status() {
+            StatusCode::CREATED => Ok(MountResult::Mounted),
+            StatusCode::ACCEPTED => Ok(MountResult::Upload(self.location(&response)?)),
+            status => Err(error_for_response(response, status, &format!("Mounting blob {digest} from {from}")).await),
+        }
+    }
+
+    pub async fn start_upload(&self, repository: &str) -> anyhow::Result<Url> {
+        let url = self.upload_url(repository)?;
+        let response = self
+            .send(|http| http.post(url.clone()).header(CONTENT_LENGTH, 0))
+            .await?;
+        match response.status() {
+            StatusCode::ACCEPTED => self.location(&response),
+            status => Err(error_for_response(response, status, "Starting blob upload").await),
+        }
+    }
+
+    /// Uploads a blob in chunks to an upload session. If a chunk fails, the session status is queried and the
+    /// upload resumes from the last byte the registry received. The session is cancelled if the upload fails.
+    pub async fn upload_blob(&self, mut location: Url, digest: &Digest, data: &[u8]) -> anyhow::Result<()> {
+        let result = self.upload_chunks(&mut location, digest, data).await;
+        if result.is_err() {
+            self.cancel_upload(&location).await;
+        }
+        result
+    }
+
+    async fn upload_chunks(&self, location: &mut Url, digest: &Digest, data: &[u8]) -> anyhow::Result<()> {
+        let mut offset = 0;
+        let mut failures = 0;
+        while offset < data.len() {
+            let end = (offset + UPLOAD_CHUNK_SIZE).min(data.len());
+            let chunk = Bytes::copy_from_slice(&data[offset..end]);
+            let result = self
+                .send(|http| {
+                    http.patch(location.clone())
+                        .header(CONTENT_TYPE, "application/octet-stream")
+                        .header(CONTENT_RANGE, format!("{}-{}", offset, end - 1))
+                        .body(chunk.clone())
+                })
+                .await;
+            let error = match result {
+                Ok(response) if response.status() == StatusCode::ACCEPTED => {
+                    *location = self.location(&response)?;
+                    offset = end;
+                    failures = 0;
+                    continue;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    error_for_response(response, status, "Uploading chunk").await
+                }
+                Err(e) => e,
+            };
+            failures += 1;
+            if failures > UPLOAD_CHUNK_RETRIES {
+                return Err(error.context(format!("Uploading blob {digest} failed after {failures} attempts")));
+            }
+            warn!("Uploading blob {digest} failed at byte {offset}, resuming: {error:#}");
+            tokio::time::sleep(backoff(UPLOAD_RETRY_DELAY, failures)).await;
+            (*location, offset) = self
+                .upload_status(location)
+                .await
+                .with_context(|| format!("Resuming upload of blob {digest}"))?;
+        }
+
+        let mut url = location.clone();
+        url.query_pairs_mut().append_pair("digest", &digest.to_string());
+        let response = self
+            .send(|http| http.put(url.clone()).header(CONTENT_LENGTH, 0))
+            .await?;
+        match response.status() {
+            StatusCode::CREATED => Ok(()),
+            status => Err(error_for_response(response, status, &format!("Completing upload of blob {digest}")).await),
+        }
+    }
+
+    /// Cancels an upload session, rather than leaving the registry to keep it until it expires. Failing to cancel it
+    /// is only logged, as the session expires anyway.
+    pub async fn cancel_upload(&self, location: &Url) {
+        match self.send(|http| http.delete(location.clone())).await {
+            Ok(response) if response.status().is_success() || response.status() == StatusCode::NOT_FOUND => {
+                debug!("Cancelled upload session {location}");
+            }
+            Ok(response) => warn!("Cancelling upload session {location} failed with {}", response.status()),
+            Err(e) => warn!("Cancelling upload session {location} failed: {e:#}"),
+        }
+    }
+
+    /// Returns the current location of an upload session and the offset of the next byte the registry expects.
+    async fn upload_status(&self, location: &Url) -> anyhow::Result<(Url, usize)> {
+        let response = self.send(|http| http.get(location.clone())).await?;
+        match response.status() {
+            StatusCode::NO_CONTENT => {
+                let offset = match response.headers().get(RANGE) {
+                    Some(range) => {
+                        let range = range.to_str()?;
+                        let (_, end) = range
+                            .split_once('-')
+                            .with_context(|| format!("Invalid upload range {range}"))?;
+                        end.parse::<usize>()? + 1
+                    }
+                    None => 0,
+                };
+                let location = self.location(&response).unwrap_or_else(|_| location.clone());
+                Ok((location, offset))
+            }
+            status => Err(error_for_response(response, status, "Fetching upload status").await),
+        }
+    }
+
+    pub async fn put_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        media_type: &str,
+        body: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let url = self.manifest_url(repository, reference)?;
+        let body = Bytes::from(body);
+        let response = self
+            .send(|http| {
+                http.put(url.clone())
+                    .header(CONTENT_TYPE, media_type)
+                    .body(body.clone())
+            })
+            .await?;
+        match response.status() {
+            StatusCode::OK | StatusCode::CREATED => Ok(()),
+            status => Err(error_for_response(response, status, &format!("Pushing manifest {reference}")).await),
+        }
+    }
+}
+
+fn with_authorization(request: RequestBuilder, authorization: Option<&Authorization>) -> RequestBuilder {
+    match authorization {
+        Some(authorization) => authorization.apply(request),
+        None => request,
+    }
+}
+
+async fn error_for_response(response: Response, status: StatusCode, action: &str) -> anyhow::Error {
+    let url = response.url().clone();
+    let body = response.text().await.unwrap_or_default();
+    anyhow::anyhow!("{action} failed: {status} from {url}: {body}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{serve_http, TestResponse};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    const DIGEST: &str = "sha256:0d90d93a5cab3fd2879040420c7b7e4958aee8997fef78e9a5dd80cb01f3bd9c";
+
+    fn client_for(address: std::net::SocketAddr) -> RegistryClient {
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        RegistryClient::new(
+            &reference,
+            Credentials::Anonymous,
+            vec![push_scope("test/image")],
+            &RegistryConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_is_local_registry() {
+        assert!(is_local_registry("localhost:5000"));
+        assert!(is_local_registry("127.0.0.1:5000"));
+        assert!(is_local_registry("[::1]:5000"));
+        assert!(!is_local_registry("index.docker.io"));
+        assert!(!is_local_registry("ghcr.io:443"));
+    }
+
+    #[tokio::test]
+    async fn test_bearer_authorization() {
+        let address = serve_http(|request| match request.path.as_str() {
+            path if path.starts_with("/token") => {
+                assert!(path.contains("scope=repository%3Atest%2Fimage%3Apull%2Cpush"));
+                TestResponse::new(200).body(r#"{"token": "abc"}"#)
+            }
+            _ if request.header("authorization") == Some("Bearer abc") => {
+                TestResponse::new(200).header("Content-Length", 5)
+            }
+            _ => TestResponse::new(401).header(
+                "WWW-Authenticate",
+                format!(
+                    "Bearer realm=\"http://{}/token\",service=\"test\"",
+                    request.header("host").unwrap()
+                ),
+            ),
+        })
+        .await;
+        let client = client_for(address);
+        let size = client
+            .blob_size("test/image", &Digest::from_str(DIGEST).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(size, Some(5));
+        assert_eq!(
+            *client.authorization.read().await,
+            Some(Authorization::Bearer("abc".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_resumes_after_failed_chunk() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let failed_once = Arc::new(Mutex::new(false));
+        let server_received = received.clone();
+        let address = serve_http(move |request| {
+            let mut received = server_received.lock().unwrap();
+            match request.method.as_str() {
+                "PATCH" => {
+                    let mut failed_once = failed_once.lock().unwrap();
+                    if !*failed_once {
+                        // Accept half of the chunk, then fail
+                        *failed_once = true;
+                        received.extend_from_slice(&request.body[..request.body.len() / 2]);
+                        return TestResponse::new(500);
+                    }
+                    let (start, _) = request.header("content-range").unwrap().split_once('-').unwrap();
+                    assert_eq!(start.parse::<usize>().unwrap(), received.len());
+                    received.extend_from_slice(&request.body);
+                    TestResponse::new(202).header("Location", "/upload/1")
+                }
+                "GET" => TestResponse::new(204)
+                    .header("Location", "/upload/1")
+                    .header("Range", format!("0-{}", received.len() - 1)),
+                "PUT" => {
+                    assert!(request.path.contains("digest=sha256%3A"));
+                    TestResponse::new(201)
+                }
+                method => panic!("Unexpected method {method}"),
+            }
+        })
+        .await;
+        let client = client_for(address);
+        let location = client.url("/upload/1").unwrap();
+        let data = b"hello world, this is a blob".to_vec();
+        client
+            .upload_blob(location, &Digest::from_str(DIGEST).unwrap(), &data)
+            .await
+            .unwrap();
+        assert_eq!(*received.lock().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_is_cancelled() {
+        let cancelled = Arc::new(Mutex::new(vec![]));
+        let server_cancelled = cancelled.clone();
+        let address = serve_http(move |request| match request.method.as_str() {
+            "PATCH" => TestResponse::new(202).header("Location", "/upload/2"),
+            "PUT" => TestResponse::new(400).body("digest invalid"),
+            "DELETE" => {
+                server_cancelled.lock().unwrap().push(request.path);
+                TestResponse::new(204)
+            }
+            method => panic!("Unexpected method {method}"),
+        })
+        .await;
+        let client = client_for(address);
+        let location = client.url("/upload/1").unwrap();
+        let error = client
+            .upload_blob(location, &Digest::from_str(DIGEST).unwrap(), b"blob")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Completing upload"));
+        assert_eq!(*cancelled.lock().unwrap(), ["/upload/2"]);
+    }
+
+    #[tokio::test]
+    async fn test_mount_blob() {
+        let address = serve_http(|request| {
+            assert_eq!(request.method, "POST");
+            if request.path.contains("from=other%2Frepo") {
+                TestResponse::new(201)
+            } else {
+                TestResponse::new(202).header("Location", "/upload/2")
+            }
+        })
+        .await;
+        let client = client_for(address);
+        let digest = Digest::from_str(DIGEST).unwrap();
+        assert!(matches!(
+            client.mount_blob("test/image", &digest, "other/repo").await.unwrap(),
+            MountResult::Mounted
+        ));
+        match client.mount_blob("test/image", &digest, "missing/repo").await.unwrap() {
+            MountResult::Upload(location) => assert_eq!(location.path(), "/upload/2"),
+            MountResult::Mounted => panic!("Blob should not be mounted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_basic_credentials_for_token() {
+        let address = serve_http(|request| match request.path.as_str() {
+            path if path.starts_with("/token") => match request.header("authorization") {
+                // user:pass
+                Some("Basic dXNlcjpwYXNz") => TestResponse::new(200).body(r#"{"token": "private"}"#),
+                _ => TestResponse::new(401).body("bad credentials"),
+            },
+            _ if request.header("authorization") == Some("Bearer private") => {
+                TestResponse::new(200).header("Content-Length", 5)
+            }
+            _ => TestResponse::new(401).header(
+                "WWW-Authenticate",
+                format!("Bearer realm=\"http://{}/token\"", request.header("host").unwrap()),
+            ),
+        })
+        .await;
+        let reference = Reference::from_str(&format!("{address}/test/image:latest")).unwrap();
+        let digest = Digest::from_str(DIGEST).unwrap();
+        let credentials = Credentials::Basic("user".to_string(), "pass".to_string());
+        let client = RegistryClient::new(
+            &reference,
+            credentials,
+            vec![pull_scope("test/image")],
+            &RegistryConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(client.blob_size("test/image", &digest).await.unwrap(), Some(5));
+
+        let client = client_for(address);
+        let error = client.blob_size("test/image", &digest).await.unwrap_err();
+        assert!(format!("{error:#}").contains("bad credentials"), "{error:#}");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let requests = Mutex::new(0);
+        let address = serve_http(move |_| {
+            let mut requests = requests.lock().unwrap();
+            *requests += 1;
+            let response = match *requests {
+                1 => TestResponse::new(429).header("Retry-After", 0),
+                2 => TestResponse::new(429),
+                _ => TestResponse::new(200).header("Content-Length", 5),
+            };
+            response
+                .header("RateLimit-Limit", "100;w=21600")
+                .header("RateLimit-Remaining", format!("{};w=21600", 10 - *requests))
+        })
+        .await;
+        let client = client_for(address);
+        let size = client
+            .blob_size("test/image", &Digest::from_str(DIGEST).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(size, Some(5));
+        assert_eq!(client.rate_limit().map(|rate_limit| rate_limit.remaining), Some(7));
+    }
+}
@@ -0,0 +1,111 @@
+use rand::prelude::*;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::Duration;
+
+/// How many times a rate limited request is retried before giving up
+pub const MAX_RATE_LIMIT_RETRIES: usize = 5;
+/// Registries asking us to wait longer than this are treated as errors, rather than stalling for hours
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Returns a delay of `base * 2^(attempt - 1)`, randomised by ±50% so that concurrent requests that failed together
+/// do not all retry at the same moment.
+pub fn backoff(base: Duration, attempt: usize) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1) as u32));
+    let jitter = SmallRng::from_entropy().gen_range(0.5..1.5);
+    delay.mul_f64(jitter).min(MAX_BACKOFF)
+}
+
+/// Parses the `Retry-After` header, which is either a number of seconds or an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.to_utc() - chrono::Utc::now()).to_std().unwrap_or_default())
+}
+
+/// The pull quota Docker Hub reports in its `RateLimit-Limit` and `RateLimit-Remaining` headers, e.g. `100;w=21600`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub remaining: u64,
+    pub limit: Option<u64>,
+    /// The length of the quota window
+    pub window: Option<Duration>,
+}
+
+impl RateLimit {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let (remaining, window) = parse_quota(headers.get("ratelimit-remaining")?.to_str().ok()?)?;
+        let limit = headers
+            .get("ratelimit-limit")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_quota)
+            .map(|(limit, _)| limit);
+        Some(Self {
+            remaining,
+            limit,
+            window,
+        })
+    }
+}
+
+fn parse_quota(value: &str) -> Option<(u64, Option<Duration>)> {
+    let mut parts = value.split(';');
+    let quota = parts.next()?.trim().parse().ok()?;
+    let window = parts
+        .filter_map(|part| part.trim().strip_prefix("w="))
+        .find_map(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs);
+    Some((quota, window))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+        let later = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&later).unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(
+            delay > Duration::from_secs(25) && delay <= Duration::from_secs(30),
+            "{delay:?}"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(RateLimit::from_headers(&headers), None);
+        headers.insert("ratelimit-limit", HeaderValue::from_static("100;w=21600"));
+        headers.insert("ratelimit-remaining", HeaderValue::from_static("76;w=21600"));
+        assert_eq!(
+            RateLimit::from_headers(&headers),
+            Some(RateLimit {
+                remaining: 76,
+                limit: Some(100),
+                window: Some(Duration::from_secs(21600)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 1..4 {
+            let expected = Duration::from_millis(100 * 2u64.pow(attempt as u32 - 1));
+            let delay = backoff(Duration::from_millis(100), attempt);
+            assert!(delay >= expected / 2 && delay < expected * 3 / 2, "{delay:?}");
+        }
+        assert_eq!(backoff(Duration::from_secs(10), 20), MAX_BACKOFF);
+    }
+}
@@ -147,3 +147,131 @@ pub fn compare_paths(paths: Vec<impl AsRef<Path>>, expected: Vec<&str>) {
     let expected: HashSet<_> = expected.iter().map(|v| v.as_ref()).collect();
     assert_eq!(paths, expected);
 }
+
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|v| v.as_str())
+    }
+}
+
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+/// Serves HTTP/1.1 requests on a random local port, closing the connection after every response.
+pub async fn serve_http(handler: impl Fn(TestRequest) -> TestResponse + Send + Sync + 'static) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let handler = std::sync::Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+            tokio::spawn(async move { handle_http_connection(stream, handler.as_ref()).await });
+        }
+    });
+    address
+}
+
+/// Serves HTTP/1.1 requests on a Unix socket at `path`, closing the connection after every response.
+pub async fn serve_http_unix(path: &Path, handler: impl Fn(TestRequest) -> TestResponse + Send + Sync + 'static) {
+    let listener = tokio::net::UnixListener::bind(path).unwrap();
+    let handler = std::sync::Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+            tokio::spawn(async move { handle_http_connection(stream, handler.as_ref()).await });
+        }
+    });
+}
+
+pub async fn handle_http_connection(
+    stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    handler: &(impl Fn(TestRequest) -> TestResponse + ?Sized),
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::io::BufReader::new(stream);
+    let mut request_line = String::new();
+    if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap().to_string();
+    let path = parts.next().unwrap().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    let content_length = headers
+        .get("content-length")
+        .map(|v| v.parse::<usize>().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.unwrap();
+
+    let is_head = method == "HEAD";
+    let response = handler(TestRequest {
+        method,
+        path,
+        headers,
+        body,
+    });
+    let mut output = format!("HTTP/1.1 {} Test\r\nConnection: close\r\n", response.status);
+    if !response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+    {
+        output.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    for (name, value) in &response.headers {
+        output.push_str(&format!("{name}: {value}\r\n"));
+    }
+    output.push_str("\r\n");
+    let stream = stream.get_mut();
+    stream.write_all(output.as_bytes()).await.unwrap();
+    if !is_head {
+        stream.write_all(&response.body).await.unwrap();
+    }
+    stream.shutdown().await.unwrap();
+}